@@ -0,0 +1,77 @@
+//! Latency of a request towards the homeserver, with a new HTTP client for every request as the
+//! proxy used to do, and with a single pooled client shared between requests.
+//!
+//! The homeserver is a local HTTP server answering every request with a small JSON body, so the
+//! difference between the two is the cost of setting up a client and a connection.
+
+use std::{convert::Infallible, net::SocketAddr, time::Duration};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Response, Server,
+};
+use tokio::runtime::Runtime;
+
+const BODY: &str = r#"{"versions":["v1.1","v1.2","v1.3","v1.4","v1.5","v1.6"]}"#;
+
+/// Start the homeserver in the background and return its address.
+fn homeserver(runtime: &Runtime) -> SocketAddr {
+    let _guard = runtime.enter();
+
+    let server =
+        Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Body::from(BODY)))
+            }))
+        }));
+    let address = server.local_addr();
+
+    runtime.spawn(server);
+    address
+}
+
+/// The client the proxy shares between requests, configured with the default settings.
+fn shared_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(60))
+        .connect_timeout(Duration::from_secs(10))
+        .pool_max_idle_per_host(32)
+        .pool_idle_timeout(Duration::from_secs(90))
+        .tcp_keepalive(Duration::from_secs(60))
+        .build()
+        .unwrap()
+}
+
+async fn fetch(client: &reqwest::Client, url: &str) {
+    let body = client.get(url).send().await.unwrap().bytes().await.unwrap();
+    assert_eq!(body.len(), BODY.len());
+}
+
+fn upstream_request(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let url = format!("http://{}/_matrix/client/versions", homeserver(&runtime));
+
+    // Every request with a new client leaves a connection in TIME_WAIT behind, keep the number
+    // of iterations low enough to not run out of ports.
+    let mut group = c.benchmark_group("upstream_request");
+    group
+        .sample_size(20)
+        .warm_up_time(Duration::from_secs(1))
+        .measurement_time(Duration::from_secs(3));
+
+    group.bench_function("client_per_request", |b| {
+        b.to_async(&runtime)
+            .iter(|| async { fetch(&reqwest::Client::new(), &url).await });
+    });
+
+    let client = shared_client();
+    group.bench_function("shared_client", |b| {
+        b.to_async(&runtime).iter(|| fetch(&client, &url));
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, upstream_request);
+criterion_main!(benches);
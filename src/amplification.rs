@@ -0,0 +1,111 @@
+//! Protection against the proxy being used to amplify traffic towards a forged source address.
+//!
+//! A small request over UDP can carry the address of a victim as its source, and make the proxy
+//! send a much larger response to it. Until a source proved that it receives what the proxy sends
+//! to it, responses are limited to a multiple of the size of the request (RFC 9175, section 2.4).
+//! Peers connected over TCP, TLS or WebSockets and peers that completed a DTLS handshake count as
+//! verified, as do endpoints that answered an Echo challenge.
+
+use std::time::Duration;
+
+use coap_lite::Packet;
+
+use crate::{block, config::AmplificationConfig};
+
+/// Size, in bytes, of the Block2 option the block handler adds to the first block of a response.
+const BLOCK2_OPTION_SIZE: usize = 4;
+
+/// Time clients should wait before retrying a request whose response was too large.
+///
+/// Unverified sources only become verified by switching to a transport that verifies them, so
+/// retrying right away doesn't help.
+pub const RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Limits the size of responses to sources that didn't prove their address.
+#[derive(Debug)]
+pub struct AmplificationGuard {
+    factor: usize,
+}
+
+impl AmplificationGuard {
+    pub fn new(config: &AmplificationConfig) -> Self {
+        Self {
+            factor: usize::try_from(config.factor).unwrap_or(usize::MAX),
+        }
+    }
+
+    /// Check whether the response to an unverified source is too large for a request of the
+    /// size.
+    pub fn exceeds(&self, request_size: usize, response: &Packet) -> bool {
+        response_size(response) > request_size.saturating_mul(self.factor)
+    }
+}
+
+/// Get the size of the first message of the response, larger payloads are sent block-wise and
+/// the client needs to ask for every further block.
+fn response_size(message: &Packet) -> usize {
+    let Ok(bytes) = message.to_bytes_unlimited() else {
+        return usize::MAX;
+    };
+
+    if message.payload.len() > block::BLOCK_WISE_THRESHOLD {
+        bytes.len() - message.payload.len() + block::BLOCK_WISE_THRESHOLD + BLOCK2_OPTION_SIZE
+    } else {
+        bytes.len()
+    }
+}
+
+/// Get the size of the request as the client sent it.
+pub fn request_size(message: &Packet) -> usize {
+    message.to_bytes_unlimited().map_or(0, |bytes| bytes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use coap_lite::{CoapOption, MessageClass, ResponseType};
+
+    use super::*;
+
+    fn response(payload_size: usize) -> Packet {
+        let mut message = Packet::new();
+        message.header.code = MessageClass::Response(ResponseType::Content);
+        message.set_token(vec![1, 2]);
+        message.payload = vec![b'x'; payload_size];
+        message
+    }
+
+    fn guard(factor: u32) -> AmplificationGuard {
+        AmplificationGuard::new(&AmplificationConfig {
+            enabled: true,
+            factor,
+        })
+    }
+
+    #[test]
+    fn limits_responses_to_a_multiple_of_the_request() {
+        let mut request = Packet::new();
+        request.set_token(vec![1, 2]);
+        request.add_option(CoapOption::UriPath, b"versions".to_vec());
+        let request_size = request_size(&request);
+
+        // The header, the token and the payload marker.
+        let overhead = 7;
+        let limit = request_size * 3;
+
+        assert!(!guard(3).exceeds(request_size, &response(limit - overhead)));
+        assert!(guard(3).exceeds(request_size, &response(limit - overhead + 1)));
+        assert!(!guard(4).exceeds(request_size, &response(limit - overhead + 1)));
+    }
+
+    #[test]
+    fn counts_only_the_first_block_of_large_responses() {
+        let first_block = response_size(&response(block::BLOCK_WISE_THRESHOLD + 1));
+
+        assert_eq!(
+            response_size(&response(100 * block::BLOCK_WISE_THRESHOLD)),
+            first_block
+        );
+        assert!(first_block < block::BLOCK_WISE_THRESHOLD + 20);
+        assert!(!guard(1).exceeds(first_block, &response(10 * block::BLOCK_WISE_THRESHOLD)));
+    }
+}
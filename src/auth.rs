@@ -0,0 +1,37 @@
+//! Extraction of the Matrix access token from CoAP requests.
+
+use anyhow::{bail, Context, Result};
+use coap_lite::{CoapOption, Packet};
+
+/// Get the access token carried in the dedicated access token option.
+///
+/// The option is opaque and carries the bytes of the access token, which saves the overhead of
+/// the `access_token=` query parameter on every request.
+pub fn option_access_token(message: &Packet, option_number: u16) -> Result<Option<String>> {
+    let Some(values) = message.get_option(CoapOption::Unknown(option_number)) else {
+        return Ok(None);
+    };
+
+    let mut values = values.iter();
+
+    let Some(value) = values.next() else {
+        return Ok(None);
+    };
+
+    if values.next().is_some() {
+        bail!("The access token option must not be repeated");
+    }
+
+    if value.is_empty() {
+        bail!("The access token option must not be empty");
+    }
+
+    let access_token =
+        String::from_utf8(value.clone()).context("The access token option isn't valid UTF-8")?;
+
+    if !access_token.bytes().all(|b| b.is_ascii_graphic()) {
+        bail!("The access token option contains characters that aren't allowed in a token");
+    }
+
+    Ok(Some(access_token))
+}
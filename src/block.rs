@@ -0,0 +1,424 @@
+//! Support for the size options of block-wise transfers (RFC 7959).
+//!
+//! The slicing of large responses into Block2 blocks and the reassembly of Block1 uploads is done
+//! by the block handler of the CoAP server before and after our request handler runs, so the
+//! handler always sees whole bodies. What's left for us is to tell the client how large the
+//! bodies are, or may be, using the Size1 and Size2 options, and to stop uploads that grow beyond
+//! the largest accepted body before the block handler buffers them.
+//!
+//! Responses the block handler never sees, separate responses and notifications, are sliced by
+//! the proxy itself and their further blocks are served from a cache of its own.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use coap::server::{Listener, Responder, TransportRequestSender};
+use coap_lite::{
+    block_handler::BlockValue, option_value::OptionValueU32, BlockHandler, BlockHandlerConfig,
+    CoapOption, CoapRequest, CoapResponse, Packet, ResponseType,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::warn;
+
+/// Responses with payloads larger than this are transferred block-wise by the block handler.
+///
+/// This is the largest block size that fits into the default maximum message size of the block
+/// handler.
+pub const BLOCK_WISE_THRESHOLD: usize = 1024;
+
+/// The code of 2.31 Continue responses.
+const CODE_CONTINUE: u8 = 0x5F;
+
+/// Get the size of the request body the client announced in the Size1 option.
+pub fn announced_request_size(message: &Packet) -> Option<u32> {
+    message
+        .get_first_option_as::<OptionValueU32>(CoapOption::Size1)
+        .and_then(|size| size.ok())
+        .map(|size| size.0)
+}
+
+/// Tell the client the largest request body we are willing to accept.
+pub fn set_size1(response: &mut Packet, max_request_body: u32) {
+    response.clear_option(CoapOption::Size1);
+    response.add_option_as(CoapOption::Size1, OptionValueU32(max_request_body));
+}
+
+/// Check if the client asked for the size of the response body.
+pub fn wants_size2(message: &Packet) -> bool {
+    message.get_option(CoapOption::Size2).is_some()
+}
+
+/// Tell the client the size of the response body, if it asked for it or the body is going to be
+/// transferred block-wise.
+pub fn set_size2(response: &mut Packet, asked_for_size: bool) {
+    if asked_for_size || response.payload.len() > BLOCK_WISE_THRESHOLD {
+        let size = u32::try_from(response.payload.len()).unwrap_or(u32::MAX);
+
+        response.clear_option(CoapOption::Size2);
+        response.add_option_as(CoapOption::Size2, OptionValueU32(size));
+    }
+}
+
+/// Get the size of the body a Block1 upload grows to with this block, or the size the client
+/// announced if that's larger.
+fn upload_size(message: &Packet) -> Option<u64> {
+    let block = message
+        .get_first_option_as::<BlockValue>(CoapOption::Block1)
+        .and_then(|block| block.ok())?;
+
+    let received = u64::from(block.num) * block.size() as u64 + message.payload.len() as u64;
+
+    Some(received.max(announced_request_size(message).map_or(0, u64::from)))
+}
+
+/// Build the 4.13 response to a block of an upload which exceeds the largest accepted body.
+fn reject_upload(datagram: &[u8], max_request_body: u32) -> Option<Vec<u8>> {
+    let message = Packet::from_bytes(datagram).ok()?;
+
+    if upload_size(&message)? <= u64::from(max_request_body) {
+        return None;
+    }
+
+    warn!("Received a block of a too large request body");
+
+    // The response starts out as a copy of the request, including the payload.
+    let mut response = CoapResponse::new(&message)?;
+    response.message.payload.clear();
+    response.set_status(ResponseType::RequestEntityTooLarge);
+    set_size1(&mut response.message, max_request_body);
+
+    response.message.to_bytes().ok()
+}
+
+/// Sends the responses of the server, dropping the payload of 2.31 Continue responses.
+///
+/// The block handler answers the blocks of an upload with a response that still carries the
+/// block it received, since responses start out as a copy of the request.
+struct ContinueResponder {
+    responder: Arc<dyn Responder>,
+}
+
+#[async_trait]
+impl Responder for ContinueResponder {
+    async fn respond(&self, response: Vec<u8>) {
+        self.responder
+            .respond(strip_continue_payload(response))
+            .await;
+    }
+
+    fn address(&self) -> SocketAddr {
+        self.responder.address()
+    }
+}
+
+fn strip_continue_payload(datagram: Vec<u8>) -> Vec<u8> {
+    if datagram.get(1) != Some(&CODE_CONTINUE) {
+        return datagram;
+    }
+
+    let Ok(mut message) = Packet::from_bytes(&datagram) else {
+        return datagram;
+    };
+
+    message.payload.clear();
+    message.to_bytes().unwrap_or(datagram)
+}
+
+/// Wraps a listener, rejecting the blocks of uploads larger than the largest accepted request
+/// body before the block handler of the server buffers them.
+///
+/// The block handler reassembles Block1 uploads without any limit, the request handler only
+/// sees the body once it's complete.
+pub struct UploadLimit {
+    listener: Box<dyn Listener>,
+    max_request_body: u32,
+}
+
+impl UploadLimit {
+    pub fn new(listener: Box<dyn Listener>, max_request_body: u32) -> Self {
+        Self {
+            listener,
+            max_request_body,
+        }
+    }
+}
+
+#[async_trait]
+impl Listener for UploadLimit {
+    async fn listen(
+        self: Box<Self>,
+        sender: TransportRequestSender,
+    ) -> io::Result<JoinHandle<io::Result<()>>> {
+        let (inner_sender, mut receiver) =
+            mpsc::unbounded_channel::<(Vec<u8>, Arc<dyn Responder>)>();
+        let listener = self.listener.listen(inner_sender).await?;
+        let max_request_body = self.max_request_body;
+
+        Ok(tokio::spawn(async move {
+            while let Some((datagram, responder)) = receiver.recv().await {
+                if let Some(response) = reject_upload(&datagram, max_request_body) {
+                    responder.respond(response).await;
+                    continue;
+                }
+
+                let responder = Arc::new(ContinueResponder { responder });
+
+                if sender.send((datagram, responder)).is_err() {
+                    break;
+                }
+            }
+
+            listener.await?
+        }))
+    }
+}
+
+/// The responses sent outside of the block handler of the server whose further blocks clients
+/// can still ask for.
+pub struct ResponseBlocks {
+    handler: Mutex<BlockHandler<SocketAddr>>,
+}
+
+impl std::fmt::Debug for ResponseBlocks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponseBlocks").finish_non_exhaustive()
+    }
+}
+
+impl Default for ResponseBlocks {
+    fn default() -> Self {
+        Self {
+            handler: Mutex::new(BlockHandler::new(BlockHandlerConfig::default())),
+        }
+    }
+}
+
+impl ResponseBlocks {
+    /// Answer a request for a further block of a response from the cache.
+    ///
+    /// Returns `true` if the response was set, the request then doesn't need to be handled.
+    pub fn serve(&self, request: &mut CoapRequest<SocketAddr>) -> bool {
+        // Block1 transfers are reassembled by the block handler of the CoAP server.
+        if request.message.get_option(CoapOption::Block2).is_none()
+            || request.message.get_option(CoapOption::Block1).is_some()
+        {
+            return false;
+        }
+
+        self.handler
+            .lock()
+            .unwrap()
+            .intercept_request(request)
+            .unwrap_or(false)
+    }
+
+    /// Replace the response of the request by its first block if it's too large for a single
+    /// message, keeping the whole response for the requests of the further blocks.
+    pub fn slice(&self, request: &mut CoapRequest<SocketAddr>) {
+        if let Err(e) = self.handler.lock().unwrap().intercept_response(request) {
+            let _ = request.apply_from_error(e);
+        }
+    }
+
+    /// Slice a notification, which nobody asked for a block of.
+    ///
+    /// The registration is the request the further blocks are going to be asked for with, any
+    /// block a client asked for earlier is forgotten so the notification starts with the first.
+    pub fn slice_notification(
+        &self,
+        registration: &Packet,
+        source: SocketAddr,
+        notification: Packet,
+    ) -> Packet {
+        let mut registration = registration.clone();
+        registration.clear_option(CoapOption::Block2);
+
+        let mut request = CoapRequest::from_packet(registration, source);
+        let mut handler = self.handler.lock().unwrap();
+        let _ = handler.intercept_request(&mut request);
+
+        if let Some(response) = &mut request.response {
+            response.message = notification.clone();
+        }
+
+        if let Err(e) = handler.intercept_response(&mut request) {
+            warn!("Could not slice a notification {e:?}");
+        }
+
+        request
+            .response
+            .map_or(notification, |response| response.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use coap_lite::{MessageClass, MessageType, RequestType};
+
+    use super::*;
+
+    fn block(num: u16, payload_size: usize, size1: Option<u32>) -> Vec<u8> {
+        let mut message = Packet::new();
+        message.header.set_type(MessageType::Confirmable);
+        message.header.code = MessageClass::Request(RequestType::Put);
+        message.header.message_id = 7;
+        message.set_token(vec![1, 2]);
+        message.add_option_as(
+            CoapOption::Block1,
+            BlockValue::new(usize::from(num), true, 1024).unwrap(),
+        );
+
+        if let Some(size1) = size1 {
+            message.add_option_as(CoapOption::Size1, OptionValueU32(size1));
+        }
+
+        message.payload = vec![0; payload_size];
+        message.to_bytes().unwrap()
+    }
+
+    #[test]
+    fn accepts_uploads_within_the_limit() {
+        assert_eq!(reject_upload(&block(0, 1024, None), 2048), None);
+        assert_eq!(reject_upload(&block(1, 1024, None), 2048), None);
+        assert_eq!(reject_upload(&block(0, 1024, Some(2048)), 2048), None);
+    }
+
+    #[test]
+    fn accepts_requests_without_block1() {
+        let mut message = Packet::new();
+        message.header.code = MessageClass::Request(RequestType::Post);
+        message.payload = vec![0; 1024];
+
+        assert_eq!(reject_upload(&message.to_bytes().unwrap(), 16), None);
+    }
+
+    #[test]
+    fn rejects_blocks_beyond_the_limit() {
+        for datagram in [
+            block(2, 1, None),
+            block(1000, 1024, None),
+            block(0, 1024, Some(4096)),
+        ] {
+            let response = reject_upload(&datagram, 2048).expect("The block should be rejected");
+            let response = Packet::from_bytes(&response).unwrap();
+
+            assert_eq!(response.header.get_type(), MessageType::Acknowledgement);
+            assert_eq!(response.header.message_id, 7);
+            assert_eq!(response.get_token(), [1, 2]);
+            assert_eq!(
+                response.header.code,
+                MessageClass::Response(ResponseType::RequestEntityTooLarge)
+            );
+            assert_eq!(announced_request_size(&response), Some(2048));
+            assert!(response.payload.is_empty());
+        }
+    }
+
+    #[test]
+    fn strips_the_payload_of_continue_responses() {
+        let mut message = Packet::new();
+        message.header.code = MessageClass::Response(ResponseType::Continue);
+        message.payload = vec![0; 16];
+
+        let stripped = Packet::from_bytes(&strip_continue_payload(message.to_bytes().unwrap()));
+        assert!(stripped.unwrap().payload.is_empty());
+
+        message.header.code = MessageClass::Response(ResponseType::Content);
+        let datagram = message.to_bytes().unwrap();
+        assert_eq!(strip_continue_payload(datagram.clone()), datagram);
+    }
+
+    fn sync_request(block2: Option<u16>) -> Packet {
+        let mut message = Packet::new();
+        message.header.code = MessageClass::Request(RequestType::Get);
+        message.set_token(vec![3]);
+
+        for segment in ["_matrix", "client", "v3", "sync"] {
+            message.add_option(CoapOption::UriPath, segment.as_bytes().to_vec());
+        }
+
+        if let Some(num) = block2 {
+            message.add_option_as(
+                CoapOption::Block2,
+                BlockValue::new(usize::from(num), false, 1024).unwrap(),
+            );
+        }
+
+        message
+    }
+
+    fn notification(body: Vec<u8>) -> Packet {
+        let mut message = Packet::new();
+        message.header.code = MessageClass::Response(ResponseType::Content);
+        message.set_token(vec![3]);
+        message.payload = body;
+        message
+    }
+
+    fn block2(message: &Packet) -> BlockValue {
+        message
+            .get_first_option_as::<BlockValue>(CoapOption::Block2)
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn serves_the_further_blocks_of_notifications() {
+        let blocks = ResponseBlocks::default();
+        let source = SocketAddr::from(([127, 0, 0, 1], 5683));
+        let body: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+
+        let first =
+            blocks.slice_notification(&sync_request(None), source, notification(body.clone()));
+        assert_eq!(first.get_token(), [3]);
+        assert_eq!(first.payload, body[..1024]);
+        assert!(block2(&first).more);
+
+        let mut received = first.payload;
+
+        for num in 1..3 {
+            let mut request = CoapRequest::from_packet(sync_request(Some(num)), source);
+            assert!(blocks.serve(&mut request));
+
+            let response = request.response.unwrap().message;
+            assert_eq!(block2(&response).num, num);
+            assert_eq!(block2(&response).more, num < 2);
+            received.extend(response.payload);
+        }
+
+        assert_eq!(received, body);
+    }
+
+    #[test]
+    fn starts_every_notification_with_the_first_block() {
+        let blocks = ResponseBlocks::default();
+        let source = SocketAddr::from(([127, 0, 0, 1], 5683));
+
+        blocks.slice_notification(&sync_request(None), source, notification(vec![1; 3000]));
+        let mut request = CoapRequest::from_packet(sync_request(Some(1)), source);
+        assert!(blocks.serve(&mut request));
+
+        let next =
+            blocks.slice_notification(&sync_request(None), source, notification(vec![2; 3000]));
+        assert_eq!(block2(&next).num, 0);
+        assert_eq!(next.payload, vec![2; 1024]);
+    }
+
+    #[test]
+    fn sends_small_notifications_as_they_are() {
+        let blocks = ResponseBlocks::default();
+        let source = SocketAddr::from(([127, 0, 0, 1], 5683));
+
+        let message =
+            blocks.slice_notification(&sync_request(None), source, notification(vec![1; 100]));
+        assert!(message.get_option(CoapOption::Block2).is_none());
+        assert_eq!(message.payload, vec![1; 100]);
+
+        let mut request = CoapRequest::from_packet(sync_request(Some(1)), source);
+        assert!(!blocks.serve(&mut request));
+    }
+}
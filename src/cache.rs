@@ -0,0 +1,510 @@
+//! Caching of homeserver responses.
+//!
+//! Responses to GET requests are cached if the homeserver allows it using `Cache-Control`, keyed
+//! by the URL and the access token of the request. Stale responses carrying an `ETag` are
+//! revalidated with the homeserver instead of being fetched again. The cache is limited in the
+//! number of responses and their total size, the least recently used responses are evicted first.
+//!
+//! Independent of the cache, CoAP clients are told how long they may reuse a response using the
+//! Max-Age option.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AGE, CACHE_CONTROL, ETAG, VARY},
+    StatusCode,
+};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::config::CacheConfig;
+
+/// A response of the homeserver, as far as the proxy cares about it.
+#[derive(Clone, Debug)]
+pub struct UpstreamResponse {
+    pub status: StatusCode,
+    pub content_type: Option<String>,
+    pub etag: Option<HeaderValue>,
+    pub body: Bytes,
+}
+
+impl UpstreamResponse {
+    /// Size of the response when it's stored in the cache.
+    fn size(&self) -> usize {
+        self.body.len() + self.content_type.as_ref().map_or(0, String::len)
+    }
+}
+
+/// Identifies the responses a cached response can be reused for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    url: Url,
+    /// Hash of the access token, responses are never shared between access tokens.
+    identity: Option<[u8; 32]>,
+}
+
+impl Key {
+    pub fn new(url: &Url, access_token: Option<&str>) -> Self {
+        Self {
+            url: url.clone(),
+            identity: access_token.map(|access_token| Sha256::digest(access_token).into()),
+        }
+    }
+}
+
+/// The result of looking up a request in the cache.
+pub enum Lookup {
+    /// The cached response can be used as it is, it stays fresh for the given time.
+    Fresh(UpstreamResponse, Duration),
+    /// The cached response is stale, but can be revalidated using its `ETag`.
+    Stale(UpstreamResponse, HeaderValue),
+}
+
+#[derive(Debug)]
+struct Entry {
+    response: UpstreamResponse,
+    expires: Instant,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheTable {
+    entries: HashMap<Key, Entry>,
+    /// The keys of the entries, ordered by when they were last used.
+    recently_used: BTreeMap<u64, Key>,
+    next_use: u64,
+    size: usize,
+}
+
+impl CacheTable {
+    fn touch(&mut self, key: &Key) {
+        let next_use = self.next_use;
+
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recently_used.remove(&entry.last_used);
+            self.recently_used.insert(next_use, key.clone());
+            entry.last_used = next_use;
+            self.next_use += 1;
+        }
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.recently_used.remove(&entry.last_used);
+        self.size -= entry.response.size();
+
+        Some(entry)
+    }
+}
+
+/// The responses of the homeserver that can be reused.
+#[derive(Debug)]
+pub struct Cache {
+    table: Mutex<CacheTable>,
+    max_entries: usize,
+    max_size: usize,
+    default_max_age: Duration,
+}
+
+impl Cache {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            table: Mutex::new(CacheTable::default()),
+            max_entries: config.max_entries,
+            max_size: config.max_size,
+            default_max_age: config.default_max_age(),
+        }
+    }
+
+    /// Look up the cached response for a request.
+    pub fn lookup(&self, key: &Key) -> Option<Lookup> {
+        let mut table = self.table.lock().unwrap();
+        let entry = table.entries.get(key)?;
+        let now = Instant::now();
+
+        if entry.expires > now {
+            let lookup = Lookup::Fresh(entry.response.clone(), entry.expires - now);
+            table.touch(key);
+
+            return Some(lookup);
+        }
+
+        if let Some(etag) = &entry.response.etag {
+            return Some(Lookup::Stale(entry.response.clone(), etag.clone()));
+        }
+
+        table.remove(key);
+        None
+    }
+
+    /// Keep using a stale response after the homeserver confirmed that it's still valid.
+    ///
+    /// The headers of the confirmation determine how long the response stays fresh. Returns the
+    /// time the response stays fresh, `None` if it can't be cached anymore.
+    pub fn revalidate(&self, key: &Key, headers: &HeaderMap) -> Option<Duration> {
+        let mut table = self.table.lock().unwrap();
+
+        let Some(freshness) = self.response_freshness(headers, key.identity.is_some()) else {
+            table.remove(key);
+            return None;
+        };
+
+        if let Some(entry) = table.entries.get_mut(key) {
+            entry.expires = Instant::now() + freshness;
+
+            if let Some(etag) = headers.get(ETAG) {
+                entry.response.etag = Some(etag.clone());
+            }
+
+            table.touch(key);
+        }
+
+        Some(freshness)
+    }
+
+    /// Store a response of the homeserver if it's cacheable.
+    ///
+    /// Returns the time the response stays fresh, `None` if it can't be cached. Confirmations that
+    /// the representation of the client is still valid aren't stored, but can stay fresh.
+    pub fn store(
+        &self,
+        key: Key,
+        headers: &HeaderMap,
+        response: &UpstreamResponse,
+    ) -> Option<Duration> {
+        if !matches!(response.status, StatusCode::OK | StatusCode::NOT_MODIFIED) {
+            return None;
+        }
+
+        let freshness = self.response_freshness(headers, key.identity.is_some())?;
+        let size = response.size();
+
+        if response.status != StatusCode::OK || size > self.max_size || self.max_entries == 0 {
+            return Some(freshness);
+        }
+
+        let mut table = self.table.lock().unwrap();
+        table.remove(&key);
+
+        while table.entries.len() >= self.max_entries || table.size + size > self.max_size {
+            let Some((_, oldest)) = table.recently_used.pop_first() else {
+                break;
+            };
+
+            if let Some(entry) = table.entries.remove(&oldest) {
+                table.size -= entry.response.size();
+            }
+        }
+
+        let last_used = table.next_use;
+        table.next_use += 1;
+        table.size += size;
+        table.recently_used.insert(last_used, key.clone());
+        table.entries.insert(
+            key,
+            Entry {
+                response: response.clone(),
+                expires: Instant::now() + freshness,
+                last_used,
+            },
+        );
+
+        Some(freshness)
+    }
+
+    /// Drop the cached responses for a resource after it was changed.
+    pub fn invalidate(&self, url: &Url) {
+        let mut table = self.table.lock().unwrap();
+        let stale: Vec<Key> = table
+            .entries
+            .keys()
+            .filter(|key| key.url.origin() == url.origin() && key.url.path() == url.path())
+            .cloned()
+            .collect();
+
+        for key in stale {
+            table.remove(&key);
+        }
+    }
+
+    /// Get the time a response stays fresh, `None` if it can't be cached.
+    pub fn response_freshness(&self, headers: &HeaderMap, authenticated: bool) -> Option<Duration> {
+        // Responses without any caching information are only cached for anonymous requests, and
+        // only if this was configured.
+        if !headers.contains_key(CACHE_CONTROL) {
+            return (!authenticated && !self.default_max_age.is_zero())
+                .then_some(self.default_max_age);
+        }
+
+        freshness(headers, authenticated)
+    }
+}
+
+/// Check if the response to the revalidation of a cached response confirms that its entity tag
+/// is still current.
+///
+/// The revalidation only carries the entity tag of the cached response, a 304 Not Modified
+/// without an `ETag` header refers to it.
+pub fn confirms(status: StatusCode, headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    status == StatusCode::NOT_MODIFIED
+        && headers.get(ETAG).is_none_or(|confirmed| confirmed == etag)
+}
+
+/// Get the time a response stays fresh in a shared cache according to its `Cache-Control`
+/// header, `None` if the response can't be cached.
+pub fn freshness(headers: &HeaderMap, authenticated: bool) -> Option<Duration> {
+    let directives: Vec<(String, Option<String>)> = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"').to_owned()),
+            ),
+            None => (directive.trim().to_ascii_lowercase(), None),
+        })
+        .collect();
+
+    let has = |name: &str| directives.iter().any(|(directive, _)| directive == name);
+    let seconds = |name: &str| {
+        directives
+            .iter()
+            .find(|(directive, _)| directive == name)
+            .and_then(|(_, value)| value.as_deref()?.parse::<u64>().ok())
+    };
+
+    if has("no-store") || has("no-cache") || has("private") {
+        return None;
+    }
+
+    // The proxy doesn't know which request headers a response depends on.
+    let varies = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.split(',').any(|header| header.trim() == "*"));
+
+    if varies {
+        return None;
+    }
+
+    let shared_max_age = seconds("s-maxage");
+
+    // Responses to authenticated requests may only be stored by shared caches if the homeserver
+    // explicitly allows it.
+    if authenticated && !has("public") && shared_max_age.is_none() {
+        return None;
+    }
+
+    let max_age = shared_max_age.or_else(|| seconds("max-age"))?;
+    let age = headers
+        .get(AGE)
+        .and_then(|age| age.to_str().ok()?.parse::<u64>().ok())
+        .unwrap_or(0);
+
+    Some(Duration::from_secs(max_age.checked_sub(age)?)).filter(|freshness| !freshness.is_zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> Cache {
+        Cache::new(&CacheConfig {
+            max_entries: 2,
+            max_size: 1024,
+            ..CacheConfig::default()
+        })
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    fn response(etag: Option<&'static str>, body: &'static str) -> UpstreamResponse {
+        UpstreamResponse {
+            status: StatusCode::OK,
+            content_type: Some("application/json".to_owned()),
+            etag: etag.map(HeaderValue::from_static),
+            body: Bytes::from_static(body.as_bytes()),
+        }
+    }
+
+    fn key(path: &str) -> Key {
+        Key::new(
+            &Url::parse("https://example.org/")
+                .unwrap()
+                .join(path)
+                .unwrap(),
+            None,
+        )
+    }
+
+    fn expire(cache: &Cache, key: &Key) {
+        let mut table = cache.table.lock().unwrap();
+        table.entries.get_mut(key).unwrap().expires = Instant::now();
+    }
+
+    fn cached_etag(cache: &Cache, key: &Key) -> Option<HeaderValue> {
+        match cache.lookup(key) {
+            Some(Lookup::Fresh(response, _)) => response.etag,
+            _ => panic!("The response should be fresh"),
+        }
+    }
+
+    #[test]
+    fn revalidates_stale_responses() {
+        let cache = cache();
+        let key = key("versions");
+        let fresh = headers(&[("cache-control", "max-age=60")]);
+
+        cache.store(key.clone(), &fresh, &response(Some(r#""v1""#), "{}"));
+        expire(&cache, &key);
+
+        let Some(Lookup::Stale(_, etag)) = cache.lookup(&key) else {
+            panic!("The response should be stale");
+        };
+        assert_eq!(etag, r#""v1""#);
+
+        // A 304 without an ETag confirms the entity tag of the revalidation.
+        let not_modified = StatusCode::NOT_MODIFIED;
+        assert!(confirms(not_modified, &fresh, &etag));
+        assert_eq!(
+            cache.revalidate(&key, &fresh),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(cached_etag(&cache, &key).unwrap(), r#""v1""#);
+
+        // A 304 with an ETag needs to name the same entity tag.
+        expire(&cache, &key);
+        let confirmed = headers(&[("cache-control", "max-age=60"), ("etag", r#""v1""#)]);
+        let other = headers(&[("cache-control", "max-age=60"), ("etag", r#""v2""#)]);
+        assert!(confirms(not_modified, &confirmed, &etag));
+        assert!(!confirms(not_modified, &other, &etag));
+        assert!(!confirms(StatusCode::OK, &confirmed, &etag));
+
+        cache.revalidate(&key, &confirmed);
+        assert_eq!(cached_etag(&cache, &key).unwrap(), r#""v1""#);
+
+        // A confirmation that can't be cached drops the response.
+        expire(&cache, &key);
+        assert_eq!(
+            cache.revalidate(&key, &headers(&[("cache-control", "no-store")])),
+            None
+        );
+        assert!(cache.lookup(&key).is_none());
+    }
+
+    #[test]
+    fn drops_stale_responses_without_an_etag() {
+        let cache = cache();
+        let key = key("versions");
+
+        cache.store(
+            key.clone(),
+            &headers(&[("cache-control", "max-age=60")]),
+            &response(None, "{}"),
+        );
+        expire(&cache, &key);
+
+        assert!(cache.lookup(&key).is_none());
+        assert!(cache.table.lock().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_responses() {
+        let cache = cache();
+        let fresh = headers(&[("cache-control", "max-age=60")]);
+        let (first, second, third) = (key("first"), key("second"), key("third"));
+
+        cache.store(first.clone(), &fresh, &response(None, "1"));
+        cache.store(second.clone(), &fresh, &response(None, "2"));
+        cache.lookup(&first);
+        cache.store(third.clone(), &fresh, &response(None, "3"));
+
+        assert!(cache.lookup(&first).is_some());
+        assert!(cache.lookup(&second).is_none());
+        assert!(cache.lookup(&third).is_some());
+
+        // Responses larger than the whole cache aren't stored.
+        let large = "x".repeat(2048);
+        let response = UpstreamResponse {
+            body: Bytes::from(large),
+            ..response(None, "")
+        };
+        cache.store(key("large"), &fresh, &response);
+        assert!(cache.lookup(&key("large")).is_none());
+    }
+
+    #[test]
+    fn invalidates_every_query_of_a_resource() {
+        let cache = cache();
+        let fresh = headers(&[("cache-control", "max-age=60")]);
+        let url = Url::parse("https://example.org/room?limit=10").unwrap();
+
+        cache.store(Key::new(&url, None), &fresh, &response(None, "{}"));
+        cache.invalidate(&Url::parse("https://example.org/room").unwrap());
+
+        assert!(cache.lookup(&Key::new(&url, None)).is_none());
+    }
+
+    #[test]
+    fn keeps_access_tokens_apart() {
+        let url = Url::parse("https://example.org/room").unwrap();
+
+        assert_ne!(Key::new(&url, Some("alice")), Key::new(&url, Some("bob")));
+        assert_ne!(Key::new(&url, Some("alice")), Key::new(&url, None));
+    }
+
+    #[test]
+    fn computes_the_freshness() {
+        let cases = [
+            (&[("cache-control", "max-age=60")][..], false, Some(60)),
+            (
+                &[("cache-control", "max-age=60"), ("age", "20")],
+                false,
+                Some(40),
+            ),
+            (
+                &[("cache-control", "max-age=60"), ("age", "60")],
+                false,
+                None,
+            ),
+            (
+                &[("cache-control", "max-age=60, s-maxage=10")],
+                false,
+                Some(10),
+            ),
+            (&[("cache-control", "max-age=60")], true, None),
+            (&[("cache-control", "public, max-age=60")], true, Some(60)),
+            (&[("cache-control", "s-maxage=60")], true, Some(60)),
+            (&[("cache-control", "private, max-age=60")], false, None),
+            (&[("cache-control", "no-store")], false, None),
+            (&[("cache-control", "no-cache, max-age=60")], false, None),
+            (
+                &[("cache-control", "max-age=60"), ("vary", "*")],
+                false,
+                None,
+            ),
+            (&[], false, None),
+        ];
+
+        for (pairs, authenticated, expected) in cases {
+            assert_eq!(
+                freshness(&headers(pairs), authenticated),
+                expected.map(Duration::from_secs),
+                "{pairs:?} authenticated: {authenticated}"
+            );
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Transcoding of request and response bodies between CBOR and JSON.
+//!
+//! Matrix only knows about JSON, but JSON is quite wasteful on constrained links. Clients can
+//! instead send and receive the same documents encoded as CBOR, the proxy converts them on the
+//! fly.
+//!
+//! Only the subset of CBOR which has a JSON equivalent is accepted, this means that map keys need
+//! to be text strings and that byte strings, tags and non-finite floats are rejected instead of
+//! being silently mangled.
+
+use anyhow::{bail, Context, Result};
+use ciborium::Value as CborValue;
+use serde_json::{Map, Number, Value as JsonValue};
+
+/// Convert a CBOR encoded document into its JSON equivalent.
+pub fn cbor_to_json(cbor: &[u8]) -> Result<Vec<u8>> {
+    let value: CborValue =
+        ciborium::from_reader(cbor).context("The body isn't a valid CBOR document")?;
+    let value = cbor_value_to_json(value)?;
+
+    Ok(serde_json::to_vec(&value)?)
+}
+
+/// Convert a JSON document into its CBOR equivalent.
+pub fn json_to_cbor(json: &[u8]) -> Result<Vec<u8>> {
+    let value: JsonValue =
+        serde_json::from_slice(json).context("The body isn't a valid JSON document")?;
+
+    let mut cbor = Vec::new();
+    ciborium::into_writer(&value, &mut cbor).context("Could not encode the body as CBOR")?;
+
+    Ok(cbor)
+}
+
+fn cbor_value_to_json(value: CborValue) -> Result<JsonValue> {
+    Ok(match value {
+        CborValue::Null => JsonValue::Null,
+        CborValue::Bool(value) => JsonValue::Bool(value),
+        CborValue::Text(value) => JsonValue::String(value),
+        CborValue::Integer(value) => {
+            let value = i128::from(value);
+
+            if let Ok(value) = i64::try_from(value) {
+                JsonValue::Number(value.into())
+            } else if let Ok(value) = u64::try_from(value) {
+                JsonValue::Number(value.into())
+            } else {
+                bail!("The integer {value} can't be represented in JSON");
+            }
+        }
+        CborValue::Float(value) => match Number::from_f64(value) {
+            Some(number) => JsonValue::Number(number),
+            None => bail!("The float {value} can't be represented in JSON"),
+        },
+        CborValue::Array(values) => JsonValue::Array(
+            values
+                .into_iter()
+                .map(cbor_value_to_json)
+                .collect::<Result<_>>()?,
+        ),
+        CborValue::Map(entries) => {
+            let mut map = Map::with_capacity(entries.len());
+
+            for (key, value) in entries {
+                let CborValue::Text(key) = key else {
+                    bail!("Only text strings are allowed as map keys, found {key:?}");
+                };
+
+                if map.insert(key, cbor_value_to_json(value)?).is_some() {
+                    bail!("The CBOR map contains a duplicate key");
+                }
+            }
+
+            JsonValue::Object(map)
+        }
+        CborValue::Bytes(_) => bail!("Byte strings can't be represented in JSON"),
+        CborValue::Tag(tag, _) => bail!("The CBOR tag {tag} can't be represented in JSON"),
+        value => bail!("The CBOR value {value:?} can't be represented in JSON"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use ciborium::Value as CborValue;
+    use serde_json::Value as JsonValue;
+
+    use super::*;
+
+    fn encode(value: &CborValue) -> Vec<u8> {
+        let mut cbor = Vec::new();
+        ciborium::into_writer(value, &mut cbor).unwrap();
+        cbor
+    }
+
+    #[test]
+    fn corpus_round_trips() {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+        let mut bodies = 0;
+
+        for entry in fs::read_dir(corpus).unwrap() {
+            let path = entry.unwrap().path();
+            let json = fs::read(&path).unwrap();
+
+            let cbor = json_to_cbor(&json).unwrap();
+            let round_tripped = cbor_to_json(&cbor).unwrap();
+
+            assert_eq!(
+                serde_json::from_slice::<JsonValue>(&round_tripped).unwrap(),
+                serde_json::from_slice::<JsonValue>(&json).unwrap(),
+                "{} doesn't survive the round trip",
+                path.display()
+            );
+
+            // CBOR encoding the round tripped JSON again needs to give the same bytes.
+            assert_eq!(json_to_cbor(&round_tripped).unwrap(), cbor);
+
+            bodies += 1;
+        }
+
+        assert!(bodies > 0, "The corpus is empty");
+    }
+
+    #[test]
+    fn cbor_is_smaller() {
+        let json =
+            fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus/sync_response.json"))
+                .unwrap();
+        let compact =
+            serde_json::to_vec(&serde_json::from_slice::<JsonValue>(&json).unwrap()).unwrap();
+
+        assert!(json_to_cbor(&json).unwrap().len() < compact.len());
+    }
+
+    #[test]
+    fn rejects_invalid_documents() {
+        assert!(json_to_cbor(b"{\"unterminated\": ").is_err());
+        assert!(cbor_to_json(&[0xff]).is_err());
+    }
+
+    #[test]
+    fn rejects_values_without_json_equivalent() {
+        let values = [
+            CborValue::Bytes(vec![1, 2, 3]),
+            CborValue::Tag(1, Box::new(CborValue::Integer(0.into()))),
+            CborValue::Float(f64::NAN),
+            CborValue::Float(f64::INFINITY),
+            CborValue::Integer((-18_446_744_073_709_551_616_i128).try_into().unwrap()),
+            CborValue::Map(vec![(
+                CborValue::Integer(1.into()),
+                CborValue::Text("integer key".to_owned()),
+            )]),
+            CborValue::Map(vec![
+                (CborValue::Text("key".to_owned()), CborValue::Null),
+                (CborValue::Text("key".to_owned()), CborValue::Bool(true)),
+            ]),
+            CborValue::Array(vec![CborValue::Bytes(Vec::new())]),
+        ];
+
+        for value in values {
+            assert!(
+                cbor_to_json(&encode(&value)).is_err(),
+                "{value:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn converts_integers_at_the_edges() {
+        let cbor = encode(&CborValue::Array(vec![
+            CborValue::Integer(u64::MAX.into()),
+            CborValue::Integer(i64::MIN.into()),
+        ]));
+
+        assert_eq!(
+            cbor_to_json(&cbor).unwrap(),
+            br#"[18446744073709551615,-9223372036854775808]"#
+        );
+    }
+}
@@ -0,0 +1,286 @@
+//! Conditional requests and the mapping between CoAP ETags and HTTP entity tags.
+//!
+//! CoAP ETags are at most 8 bytes long while HTTP entity tags can be of any length, so the proxy
+//! hashes the entity tags of the homeserver and remembers which entity tag each CoAP ETag stands
+//! for. Representations without an entity tag get an ETag derived from their payload, the proxy
+//! can only validate those itself.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use coap_lite::{CoapOption, Packet};
+use reqwest::header::{HeaderMap, HeaderValue, IF_MATCH, IF_NONE_MATCH};
+use sha2::{Digest, Sha256};
+
+/// Length, in bytes, of the CoAP ETags the proxy generates.
+const ETAG_LENGTH: usize = 8;
+
+/// Maximum number of entity tags the proxy remembers, the oldest ones are forgotten first.
+const MAX_ENTITY_TAGS: usize = 4096;
+
+fn hash(value: &[u8], content_format: Option<u16>) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(content_format.unwrap_or(u16::MAX).to_be_bytes());
+    hasher.update(value);
+
+    hasher.finalize()[..ETAG_LENGTH].to_vec()
+}
+
+/// Join entity tags into the value of an `If-Match` or `If-None-Match` header.
+fn header_value<'a>(entity_tags: impl Iterator<Item = &'a HeaderValue>) -> Option<HeaderValue> {
+    let joined: Vec<&[u8]> = entity_tags.map(HeaderValue::as_bytes).collect();
+
+    HeaderValue::from_bytes(&joined.join(&b", "[..])).ok()
+}
+
+#[derive(Debug, Default)]
+struct EntityTagTable {
+    entity_tags: HashMap<Vec<u8>, HeaderValue>,
+    /// The CoAP ETags in the order they were added.
+    order: VecDeque<Vec<u8>>,
+}
+
+/// The entity tags of the homeserver the proxy handed out CoAP ETags for.
+#[derive(Debug, Default)]
+pub struct EntityTags {
+    table: Mutex<EntityTagTable>,
+}
+
+impl EntityTags {
+    /// Get the CoAP ETag of a representation, derived from its entity tag if it has one.
+    pub fn etag(
+        &self,
+        entity_tag: Option<&HeaderValue>,
+        payload: &[u8],
+        content_format: Option<u16>,
+    ) -> Vec<u8> {
+        let Some(entity_tag) = entity_tag else {
+            return hash(payload, content_format);
+        };
+
+        let etag = hash(entity_tag.as_bytes(), content_format);
+        let mut table = self.table.lock().unwrap();
+
+        if table
+            .entity_tags
+            .insert(etag.clone(), entity_tag.clone())
+            .is_none()
+        {
+            table.order.push_back(etag.clone());
+
+            if table.order.len() > MAX_ENTITY_TAGS {
+                if let Some(oldest) = table.order.pop_front() {
+                    table.entity_tags.remove(&oldest);
+                }
+            }
+        }
+
+        etag
+    }
+
+    fn entity_tag(&self, etag: &[u8]) -> Option<HeaderValue> {
+        self.table.lock().unwrap().entity_tags.get(etag).cloned()
+    }
+
+    /// Translate the conditional options of a request for the homeserver.
+    pub fn conditions(&self, message: &Packet) -> Conditions {
+        let mut conditions = Conditions::default();
+
+        // The client has these representations and only wants a new one if they're outdated.
+        if let Some(etags) = message.get_option(CoapOption::ETag) {
+            conditions.validators = etags
+                .iter()
+                .filter_map(|etag| Some((etag.clone(), self.entity_tag(etag)?)))
+                .collect();
+        }
+
+        if let Some(etags) = message.get_option(CoapOption::IfMatch) {
+            // An empty ETag only requires the resource to exist.
+            if etags.iter().any(Vec::is_empty) {
+                conditions.if_match = Some(HeaderValue::from_static("*"));
+            } else {
+                let entity_tags: Vec<HeaderValue> = etags
+                    .iter()
+                    .filter_map(|etag| self.entity_tag(etag))
+                    .collect();
+
+                if entity_tags.is_empty() {
+                    conditions.unsatisfiable = true;
+                } else {
+                    conditions.if_match = header_value(entity_tags.iter());
+                }
+            }
+        }
+
+        conditions.if_none_match = message.get_option(CoapOption::IfNoneMatch).is_some();
+
+        conditions
+    }
+}
+
+/// The conditions a client attached to a request.
+#[derive(Debug, Default)]
+pub struct Conditions {
+    /// The CoAP ETags of the representations the client has, with the entity tags they stand
+    /// for. ETags the proxy doesn't know an entity tag for are left out.
+    validators: Vec<(Vec<u8>, HeaderValue)>,
+    /// The entity tags the current representation needs to match.
+    if_match: Option<HeaderValue>,
+    /// Whether the request requires that the resource doesn't exist.
+    if_none_match: bool,
+    /// Whether the request requires the current representation to match one of ETags the proxy
+    /// never handed out, which can't be the case.
+    pub unsatisfiable: bool,
+}
+
+impl Conditions {
+    /// Whether the request is only allowed to succeed under some condition, or the client wants to
+    /// validate the representations it has. Such requests can't be answered from the cache.
+    pub fn is_conditional(&self) -> bool {
+        self.if_match.is_some()
+            || self.if_none_match
+            || self.unsatisfiable
+            || !self.validators.is_empty()
+    }
+
+    /// The conditions as headers of the upstream request.
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        if let Some(if_match) = &self.if_match {
+            headers.insert(IF_MATCH, if_match.clone());
+        }
+
+        if self.if_none_match {
+            headers.insert(IF_NONE_MATCH, HeaderValue::from_static("*"));
+        } else if let Some(entity_tags) =
+            header_value(self.validators.iter().map(|(_, entity_tag)| entity_tag))
+                .filter(|_| !self.validators.is_empty())
+        {
+            headers.insert(IF_NONE_MATCH, entity_tags);
+        }
+
+        headers
+    }
+
+    /// Get the CoAP ETag of the representation the homeserver confirmed to be current.
+    pub fn validated(&self, entity_tag: Option<&HeaderValue>) -> Option<Vec<u8>> {
+        let validated = match entity_tag {
+            Some(entity_tag) => self
+                .validators
+                .iter()
+                .find(|(_, validator)| validator == entity_tag),
+            None => self.validators.first(),
+        };
+
+        validated.map(|(etag, _)| etag.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTITY_TAG: HeaderValue = HeaderValue::from_static(r#""v1""#);
+
+    fn request(options: &[(CoapOption, &[u8])]) -> Packet {
+        let mut message = Packet::new();
+
+        for (option, value) in options {
+            message.add_option(*option, value.to_vec());
+        }
+
+        message
+    }
+
+    #[test]
+    fn maps_etags_onto_entity_tags() {
+        let entity_tags = EntityTags::default();
+        let etag = entity_tags.etag(Some(&ENTITY_TAG), b"{}", Some(50));
+
+        assert_eq!(etag.len(), ETAG_LENGTH);
+        assert_eq!(entity_tags.entity_tag(&etag), Some(ENTITY_TAG));
+        // The same entity tag in another content format is another representation.
+        assert_ne!(entity_tags.etag(Some(&ENTITY_TAG), b"{}", Some(60)), etag);
+        // Representations without an entity tag get an ETag of their payload.
+        assert_eq!(
+            entity_tags.etag(None, b"{}", Some(50)),
+            entity_tags.etag(None, b"{}", Some(50))
+        );
+        assert_ne!(
+            entity_tags.etag(None, b"{}", Some(50)),
+            entity_tags.etag(None, b"[]", Some(50))
+        );
+    }
+
+    #[test]
+    fn validates_the_etags_of_the_client() {
+        let entity_tags = EntityTags::default();
+        let etag = entity_tags.etag(Some(&ENTITY_TAG), b"{}", None);
+        let conditions = entity_tags.conditions(&request(&[
+            (CoapOption::ETag, &etag),
+            (CoapOption::ETag, b"unknown"),
+        ]));
+
+        // Requests validating a representation never use the cache.
+        assert!(conditions.is_conditional());
+        assert_eq!(conditions.headers().get(IF_NONE_MATCH), Some(&ENTITY_TAG));
+
+        // A 304 with or without an ETag confirms the representation of the client.
+        assert_eq!(conditions.validated(Some(&ENTITY_TAG)), Some(etag.clone()));
+        assert_eq!(conditions.validated(None), Some(etag));
+        assert_eq!(
+            conditions.validated(Some(&HeaderValue::from_static(r#""v2""#))),
+            None
+        );
+
+        // ETags the proxy never handed out can't be validated.
+        let unknown = entity_tags.conditions(&request(&[(CoapOption::ETag, b"unknown")]));
+        assert!(!unknown.is_conditional());
+        assert!(unknown.headers().is_empty());
+    }
+
+    #[test]
+    fn translates_preconditions() {
+        let entity_tags = EntityTags::default();
+        let etag = entity_tags.etag(Some(&ENTITY_TAG), b"{}", None);
+
+        let if_match = entity_tags.conditions(&request(&[(CoapOption::IfMatch, &etag)]));
+        assert!(if_match.is_conditional());
+        assert_eq!(if_match.headers().get(IF_MATCH), Some(&ENTITY_TAG));
+
+        let exists = entity_tags.conditions(&request(&[(CoapOption::IfMatch, b"")]));
+        assert_eq!(exists.headers().get(IF_MATCH).unwrap(), "*");
+
+        let unknown = entity_tags.conditions(&request(&[(CoapOption::IfMatch, b"unknown")]));
+        assert!(unknown.unsatisfiable);
+        assert!(unknown.is_conditional());
+
+        // If-None-Match takes precedence over the validators.
+        let absent = entity_tags.conditions(&request(&[
+            (CoapOption::IfNoneMatch, b""),
+            (CoapOption::ETag, &etag),
+        ]));
+        assert!(absent.is_conditional());
+        assert_eq!(absent.headers().get(IF_NONE_MATCH).unwrap(), "*");
+    }
+
+    #[test]
+    fn forgets_the_oldest_entity_tags() {
+        let entity_tags = EntityTags::default();
+        let first = entity_tags.etag(Some(&HeaderValue::from_static(r#""0""#)), b"", None);
+
+        for i in 1..=MAX_ENTITY_TAGS {
+            let entity_tag = HeaderValue::from_str(&format!(r#""{i}""#)).unwrap();
+            entity_tags.etag(Some(&entity_tag), b"", None);
+        }
+
+        assert_eq!(entity_tags.entity_tag(&first), None);
+        assert_eq!(
+            entity_tags.table.lock().unwrap().entity_tags.len(),
+            MAX_ENTITY_TAGS
+        );
+    }
+}
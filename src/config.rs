@@ -0,0 +1,1268 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use url::Url;
+
+use crate::hex;
+
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:5683";
+const DEFAULT_HOMESERVER: &str = "http://localhost:8015/";
+const DEFAULT_REQUEST_TIMEOUT: u64 = 60;
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+const DEFAULT_POOL_MAX_IDLE_PER_HOST: usize = 32;
+const DEFAULT_POOL_IDLE_TIMEOUT: u64 = 90;
+const DEFAULT_TCP_KEEPALIVE: u64 = 60;
+const DEFAULT_ACCESS_TOKEN_OPTION: u16 = 65001;
+const DEFAULT_MAX_REQUEST_BODY: u32 = 1024 * 1024;
+const DEFAULT_SESSION_OPTION: u16 = 65003;
+const DEFAULT_SESSION_LIFETIME: u64 = 7 * 24 * 60 * 60;
+const DEFAULT_SESSION_HANDLE_LENGTH: usize = 8;
+const DEFAULT_OBSERVE_SYNC_TIMEOUT: u64 = 30;
+const DEFAULT_MAX_OBSERVATIONS: usize = 1024;
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+const DEFAULT_CACHE_MAX_ENTRIES: usize = 1024;
+const DEFAULT_CACHE_MAX_SIZE: usize = 16 * 1024 * 1024;
+const DEFAULT_DISCOVERY_REFRESH_INTERVAL: u64 = 60 * 60;
+const DEFAULT_DISCOVERY_RETRY_INTERVAL: u64 = 60;
+const DEFAULT_METRICS_PATH: &str = "/metrics";
+const DEFAULT_ADDRESS_RATE: u32 = 20;
+const DEFAULT_ADDRESS_BURST: u32 = 50;
+const DEFAULT_ACCESS_TOKEN_RATE: u32 = 20;
+const DEFAULT_ACCESS_TOKEN_BURST: u32 = 50;
+const DEFAULT_AMPLIFICATION_FACTOR: u32 = 3;
+const DEFAULT_ECHO_CHALLENGE_LIFETIME: u64 = 30;
+const DEFAULT_ECHO_VERIFIED_LIFETIME: u64 = 60 * 60;
+const DEFAULT_SEPARATE_ACK_DELAY: u64 = 1000;
+const DEFAULT_SEPARATE_ACK_TIMEOUT: u64 = 2000;
+const DEFAULT_SEPARATE_MAX_RETRANSMIT: u32 = 4;
+
+/// Command line arguments of the proxy.
+///
+/// Every option can also be set using an environment variable, command line flags take
+/// precedence over environment variables, which in turn take precedence over the config file.
+#[derive(Debug, Parser)]
+#[command(version, about = "A CoAP proxy for the Matrix client-server API")]
+pub struct Args {
+    /// Path to a TOML configuration file.
+    #[arg(short, long, env = "COAP_PROXY_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address the CoAP listener should bind to, can be given multiple times.
+    #[arg(short, long, env = "COAP_PROXY_LISTEN", value_delimiter = ',')]
+    pub listen: Vec<SocketAddr>,
+
+    /// Address a DTLS secured CoAP listener should bind to, can be given multiple times.
+    #[arg(long, env = "COAP_PROXY_DTLS_LISTEN", value_delimiter = ',')]
+    pub dtls_listen: Vec<SocketAddr>,
+
+    /// Address a CoAP over TCP listener should bind to, can be given multiple times.
+    #[arg(long, env = "COAP_PROXY_TCP_LISTEN", value_delimiter = ',')]
+    pub tcp_listen: Vec<SocketAddr>,
+
+    /// Address a CoAP over TLS listener should bind to, can be given multiple times.
+    #[arg(long, env = "COAP_PROXY_TLS_LISTEN", value_delimiter = ',')]
+    pub tls_listen: Vec<SocketAddr>,
+
+    /// Address a CoAP over WebSockets listener should bind to, can be given multiple times.
+    #[arg(long, env = "COAP_PROXY_WEBSOCKET_LISTEN", value_delimiter = ',')]
+    pub websocket_listen: Vec<SocketAddr>,
+
+    /// Address a CoAP over secure WebSockets listener should bind to, can be given multiple
+    /// times.
+    #[arg(long, env = "COAP_PROXY_WEBSOCKET_TLS_LISTEN", value_delimiter = ',')]
+    pub websocket_tls_listen: Vec<SocketAddr>,
+
+    /// Base URL of the homeserver requests should be forwarded to.
+    #[arg(long, env = "COAP_PROXY_HOMESERVER")]
+    pub homeserver: Option<Url>,
+
+    /// Matrix server name whose homeserver should be discovered, takes precedence over the
+    /// homeserver URL.
+    #[arg(long, env = "COAP_PROXY_SERVER_NAME")]
+    pub server_name: Option<String>,
+
+    /// The format of the log output.
+    #[arg(long, env = "COAP_PROXY_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Timeout, in seconds, for a whole request towards the homeserver.
+    #[arg(long, env = "COAP_PROXY_REQUEST_TIMEOUT")]
+    pub request_timeout: Option<u64>,
+
+    /// Timeout, in seconds, for establishing a connection to the homeserver.
+    #[arg(long, env = "COAP_PROXY_CONNECT_TIMEOUT")]
+    pub connect_timeout: Option<u64>,
+
+    /// Maximum number of idle connections to the homeserver that are kept open.
+    #[arg(long, env = "COAP_PROXY_POOL_MAX_IDLE_PER_HOST")]
+    pub pool_max_idle_per_host: Option<usize>,
+
+    /// Time, in seconds, after which idle connections to the homeserver are closed.
+    #[arg(long, env = "COAP_PROXY_POOL_IDLE_TIMEOUT")]
+    pub pool_idle_timeout: Option<u64>,
+
+    /// Interval, in seconds, of TCP keep-alive probes on connections to the homeserver.
+    #[arg(long, env = "COAP_PROXY_TCP_KEEPALIVE")]
+    pub tcp_keepalive: Option<u64>,
+
+    /// Talk HTTP/2 to the homeserver without negotiating it first.
+    #[arg(long, env = "COAP_PROXY_HTTP2_PRIOR_KNOWLEDGE")]
+    pub http2_prior_knowledge: bool,
+
+    /// Number of the CoAP option carrying the access token, needs to be in the experimental
+    /// range.
+    #[arg(long, env = "COAP_PROXY_ACCESS_TOKEN_OPTION")]
+    pub access_token_option: Option<u16>,
+
+    /// Maximum size, in bytes, of a request body, including bodies sent block-wise.
+    #[arg(long, env = "COAP_PROXY_MAX_REQUEST_BODY")]
+    pub max_request_body: Option<u32>,
+
+    /// Address the HTTP listener serving the Prometheus metrics should bind to.
+    #[arg(long, env = "COAP_PROXY_METRICS_LISTEN")]
+    pub metrics_listen: Option<SocketAddr>,
+}
+
+/// The format the log output should use.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Multi-line, human readable output.
+    #[default]
+    Pretty,
+    /// Single-line, human readable output.
+    Full,
+    /// Abbreviated single-line output.
+    Compact,
+    /// Newline delimited JSON objects.
+    Json,
+}
+
+/// What happens to requests no virtual host matches.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnknownHost {
+    /// Forward them to the configured homeserver.
+    #[default]
+    Default,
+    /// Reject them with 4.04 Not Found.
+    NotFound,
+    /// Reject them with 5.02 Bad Gateway.
+    BadGateway,
+}
+
+/// The validated configuration of the proxy.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The addresses the CoAP listeners should bind to.
+    pub listen: Vec<SocketAddr>,
+    /// Settings for the DTLS secured CoAP listeners.
+    pub dtls: DtlsConfig,
+    /// Settings for the CoAP over TCP, TLS and WebSockets listeners.
+    pub tcp: TcpConfig,
+    /// Base URL of the homeserver requests should be forwarded to.
+    pub homeserver: Url,
+    /// Matrix server name whose homeserver should be discovered using
+    /// `/.well-known/matrix/client`, takes precedence over the homeserver URL.
+    pub server_name: Option<String>,
+    /// Settings for the discovery of homeservers from server names.
+    pub discovery: DiscoveryConfig,
+    /// The format of the log output.
+    pub log_format: LogFormat,
+    /// Timeout, in seconds, for a whole request towards the homeserver.
+    pub request_timeout: u64,
+    /// Timeout, in seconds, for establishing a connection to the homeserver.
+    pub connect_timeout: u64,
+    /// Maximum number of idle connections to the homeserver that are kept open.
+    pub pool_max_idle_per_host: usize,
+    /// Time, in seconds, after which idle connections to the homeserver are closed.
+    pub pool_idle_timeout: u64,
+    /// Interval, in seconds, of TCP keep-alive probes on connections to the homeserver, zero
+    /// disables keep-alive probes.
+    pub tcp_keepalive: u64,
+    /// Talk HTTP/2 to the homeserver without negotiating it first.
+    ///
+    /// HTTP/2 is always negotiated for https homeservers, this is only needed to use HTTP/2 with
+    /// plain http homeservers.
+    pub http2_prior_knowledge: bool,
+    /// Number of the CoAP option carrying the access token.
+    ///
+    /// The number needs to be in the experimental range of 65000 to 65535. Odd numbers are
+    /// critical, so a server that doesn't know about the option rejects the request instead of
+    /// silently dropping the credentials.
+    pub access_token_option: u16,
+    /// Maximum size, in bytes, of a request body, including bodies sent block-wise.
+    pub max_request_body: u32,
+    /// Settings for the session handles that stand in for access tokens.
+    pub sessions: SessionConfig,
+    /// Settings for clients observing the sync endpoint.
+    pub observe: ObserveConfig,
+    /// Settings for the cache of homeserver responses.
+    pub cache: CacheConfig,
+    /// Settings for clients naming the homeserver using the Proxy-Uri or Proxy-Scheme options.
+    pub forward: ForwardConfig,
+    /// Homeservers requests are routed to by their Uri-Host option or the listener they arrive
+    /// on, the first matching one is used.
+    pub virtual_hosts: Vec<VirtualHostConfig>,
+    /// What happens to requests no virtual host matches.
+    pub unknown_host: UnknownHost,
+    /// Settings for clients protecting their requests using OSCORE.
+    pub oscore: OscoreConfig,
+    /// Settings for the Prometheus metrics endpoint.
+    pub metrics: MetricsConfig,
+    /// Settings for limiting the request rate of clients.
+    pub rate_limit: RateLimitConfig,
+    /// Settings for limiting the responses to sources that didn't prove their address.
+    pub amplification: AmplificationConfig,
+    /// Settings for verifying the address of clients using the Echo option.
+    pub echo: EchoConfig,
+    /// Settings for answering slow requests with separate responses.
+    pub separate: SeparateConfig,
+    /// Additional short route codes, mapping a code onto a Matrix endpoint template.
+    ///
+    /// Routes configured here replace built-in routes with the same code.
+    pub routes: BTreeMap<String, String>,
+    /// Additional Content-Formats, mapping a media type onto the number of its Content-Format.
+    ///
+    /// Content-Formats configured here replace built-in ones with the same number or media type.
+    pub content_formats: BTreeMap<String, u16>,
+}
+
+/// Settings for the session handles that stand in for access tokens.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Whether clients should be handed out session handles.
+    pub enabled: bool,
+    /// Number of the CoAP option carrying the session handle, needs to be in the experimental
+    /// range.
+    pub option: u16,
+    /// Time, in seconds, a session handle stays valid after it was handed out.
+    pub lifetime: u64,
+    /// Length, in bytes, of newly created session handles.
+    pub handle_length: usize,
+    /// File the sessions should be persisted to, sessions are only kept in memory if this isn't
+    /// set.
+    pub path: Option<PathBuf>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            option: DEFAULT_SESSION_OPTION,
+            lifetime: DEFAULT_SESSION_LIFETIME,
+            handle_length: DEFAULT_SESSION_HANDLE_LENGTH,
+            path: None,
+        }
+    }
+}
+
+impl SessionConfig {
+    /// Time a session handle stays valid after it was handed out.
+    pub fn lifetime(&self) -> Duration {
+        Duration::from_secs(self.lifetime)
+    }
+}
+
+/// Settings for the DTLS secured CoAP listeners.
+///
+/// Clients either authenticate using a pre-shared key, or the proxy authenticates itself using a
+/// certificate, both modes can't be used on the same listener.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DtlsConfig {
+    /// The addresses the DTLS listeners should bind to, no DTLS listener is started if this is
+    /// empty.
+    pub listen: Vec<SocketAddr>,
+    /// Pre-shared keys, hex encoded, by the identity of the client using them.
+    pub psk: BTreeMap<String, String>,
+    /// Hint sent to clients to help them pick the identity of their pre-shared key.
+    pub psk_identity_hint: Option<String>,
+    /// PEM file containing the certificate chain of the proxy.
+    pub certificate: Option<PathBuf>,
+    /// PEM file containing the PKCS#8 encoded private key of the certificate.
+    pub private_key: Option<PathBuf>,
+    /// How clients need to authenticate when the certificate mode is used.
+    pub client_auth: DtlsClientAuth,
+    /// PEM file containing the certificates client certificates need to be signed by.
+    pub client_ca: Option<PathBuf>,
+    /// Hex encoded SHA-256 hashes of the public keys clients are allowed to use.
+    pub client_keys: Vec<String>,
+    /// Maximum number of connections, including those still in the handshake, the DTLS
+    /// listeners together keep open at the same time.
+    pub max_connections: usize,
+}
+
+impl Default for DtlsConfig {
+    fn default() -> Self {
+        Self {
+            listen: Vec::new(),
+            psk: BTreeMap::new(),
+            psk_identity_hint: None,
+            certificate: None,
+            private_key: None,
+            client_auth: DtlsClientAuth::None,
+            client_ca: None,
+            client_keys: Vec::new(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+        }
+    }
+}
+
+/// How clients need to authenticate on a DTLS listener using a certificate.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DtlsClientAuth {
+    /// Clients don't need to present a certificate.
+    #[default]
+    None,
+    /// Clients need to present a certificate signed by the configured certificate authority.
+    Ca,
+    /// Clients need to present a certificate, which may be self-signed, for one of the
+    /// configured public keys.
+    Pinned,
+}
+
+/// Settings for the CoAP over TCP, TLS and WebSockets listeners.
+///
+/// All of them are optional, TCP addresses may overlap with the addresses of the UDP and DTLS
+/// listeners.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpConfig {
+    /// The addresses the CoAP over TCP listeners should bind to.
+    pub listen: Vec<SocketAddr>,
+    /// The addresses the CoAP over TLS listeners should bind to.
+    pub tls_listen: Vec<SocketAddr>,
+    /// The addresses the CoAP over WebSockets listeners should bind to.
+    pub websocket_listen: Vec<SocketAddr>,
+    /// The addresses the CoAP over secure WebSockets listeners should bind to.
+    pub websocket_tls_listen: Vec<SocketAddr>,
+    /// PEM file containing the certificate chain of the TLS and secure WebSockets listeners.
+    pub certificate: Option<PathBuf>,
+    /// PEM file containing the PKCS#8 encoded private key of the certificate.
+    pub private_key: Option<PathBuf>,
+    /// Maximum number of connections the TCP, TLS and WebSockets listeners together keep open
+    /// at the same time.
+    pub max_connections: usize,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            listen: Vec::new(),
+            tls_listen: Vec::new(),
+            websocket_listen: Vec::new(),
+            websocket_tls_listen: Vec::new(),
+            certificate: None,
+            private_key: None,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+        }
+    }
+}
+
+/// Settings for clients observing the sync endpoint.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ObserveConfig {
+    /// Whether clients can observe the sync endpoint.
+    pub enabled: bool,
+    /// Time, in seconds, the homeserver should hold a sync request open while waiting for new
+    /// events, unless the client asked for a different timeout.
+    pub sync_timeout: u64,
+    /// Maximum number of observations that can be active at the same time.
+    pub max_observations: usize,
+}
+
+impl Default for ObserveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            sync_timeout: DEFAULT_OBSERVE_SYNC_TIMEOUT,
+            max_observations: DEFAULT_MAX_OBSERVATIONS,
+        }
+    }
+}
+
+/// Settings for the cache of homeserver responses.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Whether responses the homeserver allows to be cached should be cached.
+    pub enabled: bool,
+    /// Maximum number of cached responses.
+    pub max_entries: usize,
+    /// Maximum size, in bytes, of all cached response bodies together.
+    pub max_size: usize,
+    /// Time, in seconds, responses without a `Cache-Control` header are cached for, zero
+    /// disables caching them. Only applies to requests without an access token.
+    pub default_max_age: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_entries: DEFAULT_CACHE_MAX_ENTRIES,
+            max_size: DEFAULT_CACHE_MAX_SIZE,
+            default_max_age: 0,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Time responses without a `Cache-Control` header are cached for.
+    pub fn default_max_age(&self) -> Duration {
+        Duration::from_secs(self.default_max_age)
+    }
+}
+
+/// Settings for the discovery of homeservers from server names.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Interval, in seconds, in which the homeserver is discovered again.
+    pub refresh_interval: u64,
+    /// Time, in seconds, after which a failed discovery is retried.
+    pub retry_interval: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            refresh_interval: DEFAULT_DISCOVERY_REFRESH_INTERVAL,
+            retry_interval: DEFAULT_DISCOVERY_RETRY_INTERVAL,
+        }
+    }
+}
+
+impl DiscoveryConfig {
+    /// Interval in which the homeserver is discovered again.
+    pub fn refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.refresh_interval)
+    }
+
+    /// Time after which a failed discovery is retried.
+    pub fn retry_interval(&self) -> Duration {
+        Duration::from_secs(self.retry_interval)
+    }
+}
+
+/// Settings for the Prometheus metrics endpoint.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// The address the HTTP listener serving the metrics should bind to, `None` disables it.
+    pub listen: Option<SocketAddr>,
+    /// The path the metrics are served on.
+    pub path: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            path: DEFAULT_METRICS_PATH.to_owned(),
+        }
+    }
+}
+
+/// Settings for limiting the request rate of clients.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Whether the request rate of clients should be limited.
+    pub enabled: bool,
+    /// Requests per second a source address can send on average, all ports of an address share
+    /// the limit.
+    pub address_rate: u32,
+    /// Requests a source address can send at once.
+    pub address_burst: u32,
+    /// Requests per second that can be sent on average using the same access token.
+    pub access_token_rate: u32,
+    /// Requests that can be sent at once using the same access token.
+    pub access_token_burst: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            address_rate: DEFAULT_ADDRESS_RATE,
+            address_burst: DEFAULT_ADDRESS_BURST,
+            access_token_rate: DEFAULT_ACCESS_TOKEN_RATE,
+            access_token_burst: DEFAULT_ACCESS_TOKEN_BURST,
+        }
+    }
+}
+
+/// Settings for limiting the responses to sources that didn't prove their address.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AmplificationConfig {
+    /// Whether responses to unverified sources should be limited.
+    pub enabled: bool,
+    /// How many times the size of the request a response to an unverified source may be.
+    pub factor: u32,
+}
+
+impl Default for AmplificationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            factor: DEFAULT_AMPLIFICATION_FACTOR,
+        }
+    }
+}
+
+/// Settings for verifying the address of clients using the Echo option.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EchoConfig {
+    /// Whether requests from endpoints that didn't prove their address should be challenged
+    /// instead of being forwarded.
+    pub enabled: bool,
+    /// Time, in seconds, a client has to repeat its request with the Echo value.
+    pub challenge_lifetime: u64,
+    /// Time, in seconds, an endpoint stays verified after proving its address.
+    pub verified_lifetime: u64,
+}
+
+impl Default for EchoConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            challenge_lifetime: DEFAULT_ECHO_CHALLENGE_LIFETIME,
+            verified_lifetime: DEFAULT_ECHO_VERIFIED_LIFETIME,
+        }
+    }
+}
+
+impl EchoConfig {
+    /// Time a client has to repeat its request with the Echo value.
+    pub fn challenge_lifetime(&self) -> Duration {
+        Duration::from_secs(self.challenge_lifetime)
+    }
+
+    /// Time an endpoint stays verified after proving its address.
+    pub fn verified_lifetime(&self) -> Duration {
+        Duration::from_secs(self.verified_lifetime)
+    }
+}
+
+/// Settings for answering slow requests with separate responses.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SeparateConfig {
+    /// Whether confirmable requests without a response after `ack_delay` should be acknowledged
+    /// right away, with the response following separately.
+    pub enabled: bool,
+    /// Time, in milliseconds, to wait for the response before acknowledging the request.
+    pub ack_delay: u64,
+    /// Time, in milliseconds, to wait for the client to acknowledge a separate response before
+    /// sending it again. Doubles with every retransmission.
+    pub ack_timeout: u64,
+    /// Number of times a separate response is sent again before giving up.
+    pub max_retransmit: u32,
+}
+
+impl Default for SeparateConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ack_delay: DEFAULT_SEPARATE_ACK_DELAY,
+            ack_timeout: DEFAULT_SEPARATE_ACK_TIMEOUT,
+            max_retransmit: DEFAULT_SEPARATE_MAX_RETRANSMIT,
+        }
+    }
+}
+
+impl SeparateConfig {
+    /// Time to wait for the response before acknowledging the request.
+    pub fn ack_delay(&self) -> Duration {
+        Duration::from_millis(self.ack_delay)
+    }
+
+    /// Time to wait for the client to acknowledge a separate response the first time.
+    pub fn ack_timeout(&self) -> Duration {
+        Duration::from_millis(self.ack_timeout)
+    }
+}
+
+/// Settings for clients naming the homeserver using the Proxy-Uri or Proxy-Scheme options.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardConfig {
+    /// Whether requests may be forwarded to other homeservers than the configured one.
+    pub enabled: bool,
+    /// The hosts requests may be forwarded to, optionally with a port, e.g. `example.org:8448`.
+    pub allowed_hosts: Vec<String>,
+}
+
+impl ForwardConfig {
+    fn validate(&mut self) -> Result<()> {
+        if self.enabled && self.allowed_hosts.is_empty() {
+            bail!("Forward-proxy mode needs at least one allowed host");
+        }
+
+        for allowed in &mut self.allowed_hosts {
+            let url = Url::parse(&format!("http://{allowed}/"))
+                .ok()
+                .filter(|url| url.path() == "/" && url.username().is_empty());
+
+            let Some(url) = url else {
+                bail!(
+                    "The allowed host {allowed:?} needs to be a host name, optionally with a port"
+                );
+            };
+
+            // The URL drops default ports, but those still restrict the entry to that port.
+            let port = allowed
+                .rsplit_once(':')
+                .filter(|(_, port)| !port.contains(']'))
+                .and_then(|(_, port)| port.parse::<u16>().ok());
+
+            // Normalize the entry the same way the hosts of requests are normalized.
+            let host = url.host_str().unwrap_or_default();
+            *allowed = match port {
+                Some(port) => format!("{host}:{port}"),
+                None => host.to_owned(),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+/// A homeserver requests are routed to by their Uri-Host option or the listener they arrive on.
+///
+/// The client settings default to the global ones.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VirtualHostConfig {
+    /// The Uri-Host option requests need to carry, any if not set.
+    pub host: Option<String>,
+    /// The address of the listener requests need to arrive on, any if not set.
+    pub listen: Option<SocketAddr>,
+    /// Base URL of the homeserver requests should be forwarded to.
+    pub homeserver: Option<Url>,
+    /// Matrix server name whose homeserver should be discovered, instead of the homeserver URL.
+    pub server_name: Option<String>,
+    /// Timeout, in seconds, for a whole request towards the homeserver.
+    pub request_timeout: Option<u64>,
+    /// Timeout, in seconds, for establishing a connection to the homeserver.
+    pub connect_timeout: Option<u64>,
+    /// Maximum number of idle connections to the homeserver that are kept open.
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Time, in seconds, after which idle connections to the homeserver are closed.
+    pub pool_idle_timeout: Option<u64>,
+    /// Interval, in seconds, of TCP keep-alive probes, zero disables keep-alive probes.
+    pub tcp_keepalive: Option<u64>,
+    /// Talk HTTP/2 to the homeserver without negotiating it first.
+    pub http2_prior_knowledge: Option<bool>,
+}
+
+impl VirtualHostConfig {
+    /// The homeserver URL or server name, to refer to the virtual host in messages.
+    fn name(&self) -> String {
+        match (&self.homeserver, &self.server_name) {
+            (Some(homeserver), _) => homeserver.to_string(),
+            (None, Some(server_name)) => server_name.clone(),
+            (None, None) => String::from("unknown homeserver"),
+        }
+    }
+
+    /// The settings of the HTTP client towards the homeserver.
+    pub fn client(&self, defaults: &ClientConfig) -> ClientConfig {
+        ClientConfig {
+            request_timeout: self
+                .request_timeout
+                .map_or(defaults.request_timeout, Duration::from_secs),
+            connect_timeout: self
+                .connect_timeout
+                .map_or(defaults.connect_timeout, Duration::from_secs),
+            pool_max_idle_per_host: self
+                .pool_max_idle_per_host
+                .unwrap_or(defaults.pool_max_idle_per_host),
+            pool_idle_timeout: self
+                .pool_idle_timeout
+                .map_or(defaults.pool_idle_timeout, Duration::from_secs),
+            tcp_keepalive: self
+                .tcp_keepalive
+                .map_or(defaults.tcp_keepalive, |seconds| {
+                    (seconds > 0).then(|| Duration::from_secs(seconds))
+                }),
+            http2_prior_knowledge: self
+                .http2_prior_knowledge
+                .unwrap_or(defaults.http2_prior_knowledge),
+        }
+    }
+}
+
+/// The settings of an HTTP client towards a homeserver.
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// Timeout for a whole request towards the homeserver.
+    pub request_timeout: Duration,
+    /// Timeout for establishing a connection to the homeserver.
+    pub connect_timeout: Duration,
+    /// Maximum number of idle connections to the homeserver that are kept open.
+    pub pool_max_idle_per_host: usize,
+    /// Time after which idle connections to the homeserver are closed.
+    pub pool_idle_timeout: Duration,
+    /// Interval of TCP keep-alive probes, `None` if keep-alive probes are disabled.
+    pub tcp_keepalive: Option<Duration>,
+    /// Talk HTTP/2 to the homeserver without negotiating it first.
+    pub http2_prior_knowledge: bool,
+}
+
+/// Settings for clients protecting their requests using OSCORE.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OscoreConfig {
+    /// The security contexts the proxy shares with its clients.
+    pub contexts: Vec<OscoreContextConfig>,
+}
+
+/// The input parameters of a security context shared with a client, all hex encoded.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OscoreContextConfig {
+    /// The secret both sides derive their keys from.
+    pub master_secret: String,
+    /// Optional salt of the key derivation.
+    #[serde(default)]
+    pub master_salt: String,
+    /// The ID the proxy uses as the sender of the context.
+    pub sender_id: String,
+    /// The ID the client uses as the sender of the context.
+    pub recipient_id: String,
+    /// Optional ID of the context, needed if recipient IDs are reused across contexts.
+    pub id_context: Option<String>,
+}
+
+impl DtlsConfig {
+    fn validate(&self) -> Result<()> {
+        let certificate_mode = self.certificate.is_some() || self.private_key.is_some();
+
+        if self.psk.is_empty() != certificate_mode {
+            bail!("The DTLS listeners need either pre-shared keys or a certificate, but not both");
+        }
+
+        if certificate_mode && (self.certificate.is_none() || self.private_key.is_none()) {
+            bail!("The DTLS certificate and private key need to be configured together");
+        }
+
+        if let Some((identity, _)) = self
+            .psk
+            .iter()
+            .find(|(_, key)| hex::decode(key).is_none_or(|key| key.is_empty()))
+        {
+            bail!("The pre-shared key of {identity} needs to be a non-empty hex string");
+        }
+
+        match self.client_auth {
+            DtlsClientAuth::None => {}
+            _ if !certificate_mode => {
+                bail!("Client authentication can only be configured in the certificate mode")
+            }
+            DtlsClientAuth::Ca if self.client_ca.is_none() => {
+                bail!("The client certificate authority needs to be configured")
+            }
+            DtlsClientAuth::Pinned if self.client_keys.is_empty() => {
+                bail!("At least one client key needs to be configured")
+            }
+            DtlsClientAuth::Ca | DtlsClientAuth::Pinned => {}
+        }
+
+        if let Some(key) = self
+            .client_keys
+            .iter()
+            .find(|key| hex::decode(key).is_none_or(|key| key.len() != 32))
+        {
+            bail!("The client key {key} needs to be a hex encoded SHA-256 hash");
+        }
+
+        if self.max_connections == 0 {
+            bail!("The maximum number of DTLS connections needs to be greater than zero");
+        }
+
+        Ok(())
+    }
+}
+
+impl TcpConfig {
+    fn validate(&self) -> Result<()> {
+        let mut seen = HashSet::new();
+
+        if let Some(duplicate) = self
+            .listen
+            .iter()
+            .chain(&self.tls_listen)
+            .chain(&self.websocket_listen)
+            .chain(&self.websocket_tls_listen)
+            .find(|address| !seen.insert(*address))
+        {
+            bail!("The TCP listen address {duplicate} is configured more than once");
+        }
+
+        let uses_tls = !self.tls_listen.is_empty() || !self.websocket_tls_listen.is_empty();
+
+        if uses_tls && (self.certificate.is_none() || self.private_key.is_none()) {
+            bail!("The TLS listeners need a certificate and a private key");
+        }
+
+        if self.max_connections == 0 {
+            bail!("The maximum number of TCP connections needs to be greater than zero");
+        }
+
+        Ok(())
+    }
+}
+
+impl ObserveConfig {
+    /// Time the homeserver should hold a sync request open while waiting for new events.
+    pub fn sync_timeout(&self) -> Duration {
+        Duration::from_secs(self.sync_timeout)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![DEFAULT_LISTEN_ADDRESS
+                .parse()
+                .expect("The default listen address should be valid")],
+            dtls: DtlsConfig::default(),
+            tcp: TcpConfig::default(),
+            homeserver: Url::parse(DEFAULT_HOMESERVER)
+                .expect("The default homeserver URL should be valid"),
+            server_name: None,
+            discovery: DiscoveryConfig::default(),
+            log_format: LogFormat::default(),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            pool_max_idle_per_host: DEFAULT_POOL_MAX_IDLE_PER_HOST,
+            pool_idle_timeout: DEFAULT_POOL_IDLE_TIMEOUT,
+            tcp_keepalive: DEFAULT_TCP_KEEPALIVE,
+            http2_prior_knowledge: false,
+            access_token_option: DEFAULT_ACCESS_TOKEN_OPTION,
+            max_request_body: DEFAULT_MAX_REQUEST_BODY,
+            sessions: SessionConfig::default(),
+            observe: ObserveConfig::default(),
+            cache: CacheConfig::default(),
+            forward: ForwardConfig::default(),
+            virtual_hosts: Vec::new(),
+            unknown_host: UnknownHost::default(),
+            oscore: OscoreConfig::default(),
+            metrics: MetricsConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            amplification: AmplificationConfig::default(),
+            echo: EchoConfig::default(),
+            separate: SeparateConfig::default(),
+            routes: BTreeMap::new(),
+            content_formats: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Load the config file, if one was given, apply the overrides from the command line and
+    /// environment, and validate the result.
+    pub fn load(args: Args) -> Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        config.apply_args(args);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read the config file {}", path.display()))?;
+
+        toml::from_str(&content)
+            .with_context(|| format!("Could not parse the config file {}", path.display()))
+    }
+
+    fn apply_args(&mut self, args: Args) {
+        if !args.listen.is_empty() {
+            self.listen = args.listen;
+        }
+
+        if !args.dtls_listen.is_empty() {
+            self.dtls.listen = args.dtls_listen;
+        }
+
+        if !args.tcp_listen.is_empty() {
+            self.tcp.listen = args.tcp_listen;
+        }
+
+        if !args.tls_listen.is_empty() {
+            self.tcp.tls_listen = args.tls_listen;
+        }
+
+        if !args.websocket_listen.is_empty() {
+            self.tcp.websocket_listen = args.websocket_listen;
+        }
+
+        if !args.websocket_tls_listen.is_empty() {
+            self.tcp.websocket_tls_listen = args.websocket_tls_listen;
+        }
+
+        if let Some(homeserver) = args.homeserver {
+            self.homeserver = homeserver;
+        }
+
+        if let Some(server_name) = args.server_name {
+            self.server_name = Some(server_name);
+        }
+
+        if let Some(log_format) = args.log_format {
+            self.log_format = log_format;
+        }
+
+        if let Some(request_timeout) = args.request_timeout {
+            self.request_timeout = request_timeout;
+        }
+
+        if let Some(connect_timeout) = args.connect_timeout {
+            self.connect_timeout = connect_timeout;
+        }
+
+        if let Some(pool_max_idle_per_host) = args.pool_max_idle_per_host {
+            self.pool_max_idle_per_host = pool_max_idle_per_host;
+        }
+
+        if let Some(pool_idle_timeout) = args.pool_idle_timeout {
+            self.pool_idle_timeout = pool_idle_timeout;
+        }
+
+        if let Some(tcp_keepalive) = args.tcp_keepalive {
+            self.tcp_keepalive = tcp_keepalive;
+        }
+
+        if args.http2_prior_knowledge {
+            self.http2_prior_knowledge = true;
+        }
+
+        if let Some(access_token_option) = args.access_token_option {
+            self.access_token_option = access_token_option;
+        }
+
+        if let Some(max_request_body) = args.max_request_body {
+            self.max_request_body = max_request_body;
+        }
+
+        if let Some(metrics_listen) = args.metrics_listen {
+            self.metrics.listen = Some(metrics_listen);
+        }
+    }
+
+    fn validate(&mut self) -> Result<()> {
+        if self.listen.is_empty() {
+            bail!("At least one listen address needs to be configured");
+        }
+
+        let mut seen = HashSet::new();
+
+        if let Some(duplicate) = self
+            .listen
+            .iter()
+            .chain(&self.dtls.listen)
+            .find(|address| !seen.insert(*address))
+        {
+            bail!("The listen address {duplicate} is configured more than once");
+        }
+
+        if !self.dtls.listen.is_empty() {
+            self.dtls.validate()?;
+        }
+
+        self.tcp.validate()?;
+        self.forward.validate()?;
+
+        validate_homeserver(&mut self.homeserver)?;
+
+        if let Some(server_name) = &self.server_name {
+            validate_server_name(server_name)?;
+        }
+
+        if self.discovery.refresh_interval == 0 || self.discovery.retry_interval == 0 {
+            bail!("The discovery refresh and retry intervals need to be greater than zero");
+        }
+
+        if self.rate_limit.enabled
+            && [
+                self.rate_limit.address_rate,
+                self.rate_limit.address_burst,
+                self.rate_limit.access_token_rate,
+                self.rate_limit.access_token_burst,
+            ]
+            .contains(&0)
+        {
+            bail!("The rate limits and burst sizes need to be greater than zero");
+        }
+
+        if self.amplification.factor == 0 {
+            bail!("The amplification factor needs to be greater than zero");
+        }
+
+        if self.echo.challenge_lifetime == 0 || self.echo.verified_lifetime == 0 {
+            bail!("The Echo challenge and verification lifetimes need to be greater than zero");
+        }
+
+        if self.separate.ack_timeout == 0 {
+            bail!("The separate response acknowledgement timeout needs to be greater than zero");
+        }
+
+        if !self.metrics.path.starts_with('/') {
+            bail!("The metrics path needs to start with a slash");
+        }
+
+        if self.request_timeout == 0 {
+            bail!("The request timeout needs to be greater than zero");
+        }
+
+        if self.connect_timeout == 0 {
+            bail!("The connect timeout needs to be greater than zero");
+        }
+
+        if self.access_token_option < 65000 {
+            bail!(
+                "The access token option {} needs to be in the experimental range of 65000 to 65535",
+                self.access_token_option
+            );
+        }
+
+        if self.sessions.option < 65000 {
+            bail!(
+                "The session option {} needs to be in the experimental range of 65000 to 65535",
+                self.sessions.option
+            );
+        }
+
+        if self.sessions.option == self.access_token_option {
+            bail!("The session option and the access token option need to be different");
+        }
+
+        if self.sessions.lifetime == 0 {
+            bail!("The session lifetime needs to be greater than zero");
+        }
+
+        if !(1..=8).contains(&self.sessions.handle_length) {
+            bail!("The session handle length needs to be between 1 and 8 bytes");
+        }
+
+        // The sync requests of an observation need to finish before the request timeout aborts
+        // them.
+        if self.observe.enabled
+            && (self.observe.sync_timeout == 0 || self.observe.sync_timeout >= self.request_timeout)
+        {
+            bail!("The observe sync timeout needs to be greater than zero and less than the request timeout");
+        }
+
+        self.validate_virtual_hosts()?;
+
+        Ok(())
+    }
+
+    fn validate_virtual_hosts(&mut self) -> Result<()> {
+        if self.virtual_hosts.is_empty() && self.unknown_host != UnknownHost::Default {
+            bail!("Rejecting unknown hosts needs at least one virtual host");
+        }
+
+        let listen_addresses: HashSet<SocketAddr> = self
+            .listen
+            .iter()
+            .chain(&self.dtls.listen)
+            .chain(&self.tcp.listen)
+            .chain(&self.tcp.tls_listen)
+            .chain(&self.tcp.websocket_listen)
+            .chain(&self.tcp.websocket_tls_listen)
+            .copied()
+            .collect();
+
+        for virtual_host in &mut self.virtual_hosts {
+            if virtual_host.host.is_none() && virtual_host.listen.is_none() {
+                bail!(
+                    "The virtual host for {} needs a host, a listen address or both",
+                    virtual_host.name()
+                );
+            }
+
+            match (&mut virtual_host.homeserver, &virtual_host.server_name) {
+                (Some(homeserver), None) => validate_homeserver(homeserver)?,
+                (None, Some(server_name)) => validate_server_name(server_name)?,
+                _ => bail!(
+                    "The virtual host for {} needs either a homeserver URL or a server name",
+                    virtual_host.name()
+                ),
+            }
+
+            if let Some(host) = &mut virtual_host.host {
+                *host = host.to_ascii_lowercase();
+            }
+
+            if let Some(listen) = virtual_host.listen {
+                if !listen_addresses.contains(&listen) {
+                    bail!("The virtual host listen address {listen} isn't one of the listen addresses");
+                }
+            }
+
+            let request_timeout = virtual_host.request_timeout.unwrap_or(self.request_timeout);
+
+            if self.observe.enabled && request_timeout <= self.observe.sync_timeout {
+                bail!(
+                    "The request timeout of the virtual host for {} needs to be greater than the observe sync timeout",
+                    virtual_host.name()
+                );
+            }
+
+            if virtual_host.connect_timeout == Some(0) {
+                bail!(
+                    "The connect timeout of the virtual host for {} needs to be greater than zero",
+                    virtual_host.name()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The settings of the HTTP client towards the configured homeserver.
+    pub fn client(&self) -> ClientConfig {
+        ClientConfig {
+            request_timeout: self.request_timeout(),
+            connect_timeout: self.connect_timeout(),
+            pool_max_idle_per_host: self.pool_max_idle_per_host,
+            pool_idle_timeout: self.pool_idle_timeout(),
+            tcp_keepalive: self.tcp_keepalive(),
+            http2_prior_knowledge: self.http2_prior_knowledge,
+        }
+    }
+
+    /// Timeout for a whole request towards the homeserver.
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout)
+    }
+
+    /// Timeout for establishing a connection to the homeserver.
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout)
+    }
+
+    /// Time after which idle connections to the homeserver are closed.
+    pub fn pool_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.pool_idle_timeout)
+    }
+
+    /// Interval of TCP keep-alive probes, `None` if keep-alive probes are disabled.
+    pub fn tcp_keepalive(&self) -> Option<Duration> {
+        (self.tcp_keepalive > 0).then(|| Duration::from_secs(self.tcp_keepalive))
+    }
+}
+
+/// Check that a homeserver URL can be used as the base of upstream requests.
+pub fn validate_homeserver(homeserver: &mut Url) -> Result<()> {
+    if !matches!(homeserver.scheme(), "http" | "https") {
+        bail!("The homeserver URL {homeserver} needs to use the http or https scheme");
+    }
+
+    if homeserver.cannot_be_a_base() || homeserver.host().is_none() {
+        bail!("The homeserver URL {homeserver} is missing a host");
+    }
+
+    if homeserver.query().is_some() || homeserver.fragment().is_some() {
+        bail!("The homeserver URL {homeserver} must not contain a query or a fragment");
+    }
+
+    // The request path gets appended to the homeserver URL, so make sure that it ends with a
+    // slash, otherwise the last segment of the base path would be replaced.
+    if !homeserver.path().ends_with('/') {
+        let path = format!("{}/", homeserver.path());
+        homeserver.set_path(&path);
+    }
+
+    Ok(())
+}
+
+/// Check that a server name is a host name, optionally with a port.
+fn validate_server_name(server_name: &str) -> Result<()> {
+    let valid = Url::parse(&format!("https://{server_name}/"))
+        .is_ok_and(|url| url.path() == "/" && url.username().is_empty());
+
+    if !valid {
+        bail!("The server name {server_name:?} needs to be a host name, optionally with a port");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Result<Config> {
+        let mut config: Config = toml::from_str(toml)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        config("").unwrap();
+    }
+
+    #[test]
+    fn sync_timeout_needs_to_be_less_than_request_timeout() {
+        assert!(config("request_timeout = 20").is_err());
+        assert!(config("request_timeout = 20\n[observe]\nsync_timeout = 10").is_ok());
+    }
+
+    #[test]
+    fn sync_timeout_is_ignored_without_observe() {
+        config("request_timeout = 20\n[observe]\nenabled = false").unwrap();
+        config(
+            r#"
+            [observe]
+            enabled = false
+
+            [[virtual_hosts]]
+            host = "example.org"
+            homeserver = "https://example.org/"
+            request_timeout = 5
+            "#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        for toml in [
+            "request_timeout = 0",
+            "homeserver = \"https://example.org/?query\"",
+            "[sessions]\nhandle_length = 9",
+            "[separate]\nack_timeout = 0",
+            "[dtls]\nlisten = [\"127.0.0.1:5684\"]\nmax_connections = 0\n[dtls.psk]\na = \"00\"",
+            "[metrics]\npath = \"metrics\"",
+            "unknown = true",
+        ] {
+            assert!(config(toml).is_err(), "{toml:?} should be rejected");
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Confirmable messages the proxy sends on its own, like notifications and separate responses,
+//! which are retransmitted until the peer acknowledges them (RFC 7252, section 4.2).
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use coap::server::Responder;
+use coap_lite::{MessageType, Packet};
+use rand::Rng;
+use tokio::sync::oneshot;
+use tracing::warn;
+
+/// The ACK_RANDOM_FACTOR of RFC 7252.
+const ACK_RANDOM_FACTOR: f64 = 1.5;
+
+/// The confirmable messages waiting for an acknowledgement.
+#[derive(Debug)]
+pub struct Confirmations {
+    /// The senders of the messages by peer and message ID, they are told whether the message
+    /// was acknowledged or rejected.
+    pending: Mutex<HashMap<(SocketAddr, u16), oneshot::Sender<bool>>>,
+    next_message_id: AtomicU16,
+}
+
+impl Default for Confirmations {
+    fn default() -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+            next_message_id: AtomicU16::new(rand::thread_rng().gen()),
+        }
+    }
+}
+
+impl Confirmations {
+    /// Handle an acknowledgement or a reset message from a client.
+    pub fn handle_reply(&self, peer: SocketAddr, message: &Packet) {
+        let acknowledged = message.header.get_type() == MessageType::Acknowledgement;
+        let key = (peer, message.header.message_id);
+
+        if let Some(sender) = self.pending.lock().unwrap().remove(&key) {
+            let _ = sender.send(acknowledged);
+        }
+    }
+
+    /// Send a message as a confirmable message with a new message ID, retransmitting it with
+    /// exponential back-off until the peer acknowledges it.
+    ///
+    /// Returns `false` if the peer rejected the message or never acknowledged it.
+    pub async fn send(
+        &self,
+        responder: &dyn Responder,
+        mut message: Packet,
+        ack_timeout: Duration,
+        max_retransmit: u32,
+    ) -> bool {
+        let message_id = self.next_message_id.fetch_add(1, Ordering::Relaxed);
+        message.header.set_type(MessageType::Confirmable);
+        message.header.message_id = message_id;
+
+        let Ok(bytes) = message.to_bytes() else {
+            warn!("Could not serialize a confirmable message");
+            return false;
+        };
+
+        let key = (responder.address(), message_id);
+        let (sender, mut receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(key, sender);
+        let _guard = PendingGuard {
+            confirmations: self,
+            key,
+        };
+
+        let mut timeout = ack_timeout.mul_f64(rand::thread_rng().gen_range(1.0..ACK_RANDOM_FACTOR));
+
+        for _ in 0..=max_retransmit {
+            responder.respond(bytes.clone()).await;
+
+            match tokio::time::timeout(timeout, &mut receiver).await {
+                Ok(Ok(acknowledged)) => return acknowledged,
+                Ok(Err(_)) => return false,
+                Err(_) => timeout *= 2,
+            }
+        }
+
+        false
+    }
+}
+
+/// Removes the entry of a message from the pending table, even if the sender was cancelled
+/// while waiting for the acknowledgement.
+struct PendingGuard<'a> {
+    confirmations: &'a Confirmations,
+    key: (SocketAddr, u16),
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.confirmations.pending.lock().unwrap().remove(&self.key);
+    }
+}
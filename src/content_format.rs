@@ -0,0 +1,119 @@
+//! Mapping between CoAP Content-Formats and HTTP media types.
+//!
+//! The Content-Format of a request tells the proxy which Content-Type the upstream request needs,
+//! the Content-Type of the upstream response is turned back into a Content-Format. Formats that
+//! aren't in the built-in table can be added in the configuration, e.g. for media uploads.
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, Result};
+use reqwest::header::HeaderValue;
+
+/// The Content-Format of JSON bodies, which is assumed if a request has no Content-Format.
+pub const JSON: u16 = 50;
+
+/// The media type of JSON bodies, the only bodies the homeserver understands besides uploads.
+pub const JSON_MEDIA_TYPE: &str = "application/json";
+
+/// The Content-Format of CBOR bodies, which get converted into JSON.
+pub const CBOR: u16 = 60;
+
+/// The built-in Content-Formats from the CoAP registry and their media types.
+const DEFAULT_CONTENT_FORMATS: &[(u16, &str)] = &[
+    (0, "text/plain; charset=utf-8"),
+    (21, "image/gif"),
+    (22, "image/jpeg"),
+    (23, "image/png"),
+    (40, "application/link-format"),
+    (41, "application/xml"),
+    (42, "application/octet-stream"),
+    (47, "application/exi"),
+    (JSON, JSON_MEDIA_TYPE),
+    (51, "application/json-patch+json"),
+    (52, "application/merge-patch+json"),
+    (CBOR, "application/cbor"),
+];
+
+/// Strip the parameters from a media type, e.g. the charset, and normalize its case.
+fn essence(media_type: &str) -> String {
+    media_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// The table mapping Content-Formats onto media types and back.
+#[derive(Debug)]
+pub struct ContentFormats {
+    media_types: HashMap<u16, String>,
+    content_formats: HashMap<String, u16>,
+}
+
+impl ContentFormats {
+    /// Create the table from the built-in Content-Formats and the configured additions.
+    ///
+    /// Configured Content-Formats replace built-in ones with the same number or media type.
+    pub fn new(extra_content_formats: &BTreeMap<String, u16>) -> Result<Self> {
+        let mut table = Self {
+            media_types: HashMap::new(),
+            content_formats: HashMap::new(),
+        };
+
+        let all_content_formats = DEFAULT_CONTENT_FORMATS
+            .iter()
+            .map(|(content_format, media_type)| (*content_format, *media_type))
+            .chain(
+                extra_content_formats
+                    .iter()
+                    .map(|(media_type, content_format)| (*content_format, media_type.as_str())),
+            );
+
+        for (content_format, media_type) in all_content_formats {
+            let normalized = essence(media_type);
+
+            if normalized
+                .split('/')
+                .filter(|part| !part.is_empty())
+                .count()
+                != 2
+            {
+                bail!("The media type {media_type:?} needs to be of the form type/subtype");
+            }
+
+            if HeaderValue::from_str(media_type).is_err() {
+                bail!("The media type {media_type:?} can't be used as a Content-Type");
+            }
+
+            if content_format == CBOR && normalized != "application/cbor" {
+                bail!("The Content-Format {CBOR} is reserved for CBOR");
+            }
+
+            if let Some(previous) = table
+                .media_types
+                .insert(content_format, media_type.to_owned())
+            {
+                table.content_formats.remove(&essence(&previous));
+            }
+
+            if let Some(previous) = table.content_formats.insert(normalized, content_format) {
+                if previous != content_format {
+                    table.media_types.remove(&previous);
+                }
+            }
+        }
+
+        Ok(table)
+    }
+
+    /// Get the media type of a Content-Format, `None` if the Content-Format is unknown.
+    pub fn media_type(&self, content_format: u16) -> Option<&str> {
+        self.media_types.get(&content_format).map(String::as_str)
+    }
+
+    /// Get the Content-Format of a Content-Type, `None` if there's no matching Content-Format.
+    pub fn content_format(&self, content_type: &str) -> Option<u16> {
+        self.content_formats.get(&essence(content_type)).copied()
+    }
+}
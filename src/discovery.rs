@@ -0,0 +1,334 @@
+//! Discovery of the homeserver of a Matrix server name using `/.well-known/matrix/client`.
+//!
+//! Instead of a fixed base URL the proxy can be configured with a server name. The base URL is
+//! looked up when the proxy starts and again periodically, so a homeserver can move without
+//! restarting the proxy. If a lookup fails the last known base URL stays in use, or, before the
+//! first successful lookup, the server name itself.
+
+use std::sync::{Arc, RwLock};
+
+use anyhow::{bail, Context, Result};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::config::{self, DiscoveryConfig};
+
+/// The base URL of a homeserver, either configured or discovered from its server name.
+#[derive(Debug)]
+pub struct Homeserver {
+    server_name: Option<String>,
+    base_url: RwLock<Url>,
+}
+
+impl Homeserver {
+    /// A homeserver whose base URL never changes.
+    pub fn fixed(base_url: Url) -> Arc<Self> {
+        Arc::new(Self {
+            server_name: None,
+            base_url: RwLock::new(base_url),
+        })
+    }
+
+    /// The base URL of the homeserver, the path of requests gets appended to it.
+    pub fn base_url(&self) -> Url {
+        self.base_url.read().unwrap().clone()
+    }
+
+    /// A name of the homeserver that stays the same when it moves, the server name if it's
+    /// discovered, otherwise the origin of the base URL.
+    pub fn id(&self) -> String {
+        self.server_name
+            .clone()
+            .unwrap_or_else(|| origin(&self.base_url()))
+    }
+}
+
+/// The origin of a homeserver URL, e.g. `https://example.org:8448`.
+pub fn origin(url: &Url) -> String {
+    url.origin().ascii_serialization()
+}
+
+#[derive(Deserialize)]
+struct WellKnown {
+    #[serde(rename = "m.homeserver")]
+    homeserver: WellKnownHomeserver,
+}
+
+#[derive(Deserialize)]
+struct WellKnownHomeserver {
+    base_url: String,
+}
+
+/// The base URL used if the server name has no well-known information.
+fn fallback_url(server_name: &str) -> Result<Url> {
+    Url::parse(&format!("https://{server_name}/"))
+        .with_context(|| format!("Invalid server name {server_name}"))
+}
+
+/// Look up the base URL of the homeserver of a server name.
+async fn resolve(client: &reqwest::Client, server_name: &str) -> Result<Url> {
+    let fallback = fallback_url(server_name)?;
+
+    // The well-known information is always served on the default port.
+    let mut well_known_url = fallback.join(".well-known/matrix/client")?;
+    let _ = well_known_url.set_port(None);
+
+    resolve_from(client, well_known_url, fallback).await
+}
+
+/// Look up the base URL of a homeserver in the well-known information at the URL, using the
+/// fallback if there is none.
+async fn resolve_from(client: &reqwest::Client, well_known_url: Url, fallback: Url) -> Result<Url> {
+    let response = client
+        .get(well_known_url)
+        .send()
+        .await
+        .context("Could not fetch the well-known information")?;
+
+    let mut base_url = match response.status() {
+        StatusCode::NOT_FOUND => {
+            debug!("The server name has no well-known information, using {fallback}");
+            fallback
+        }
+        status if status.is_success() => {
+            let body = response
+                .bytes()
+                .await
+                .context("Could not fetch the well-known information")?;
+            let well_known: WellKnown =
+                serde_json::from_slice(&body).context("Invalid well-known information")?;
+
+            Url::parse(&well_known.homeserver.base_url)
+                .context("Invalid base URL in the well-known information")?
+        }
+        status => bail!("Fetching the well-known information failed with {status}"),
+    };
+
+    config::validate_homeserver(&mut base_url)?;
+
+    // Make sure that the base URL actually belongs to a homeserver.
+    let body = client
+        .get(base_url.join("_matrix/client/versions")?)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .context("Could not fetch the supported versions of the homeserver")?
+        .bytes()
+        .await
+        .context("Could not fetch the supported versions of the homeserver")?;
+
+    let versions: serde_json::Value =
+        serde_json::from_slice(&body).context("Invalid supported versions of the homeserver")?;
+
+    if !versions
+        .get("versions")
+        .is_some_and(serde_json::Value::is_array)
+    {
+        bail!("Invalid supported versions of the homeserver");
+    }
+
+    Ok(base_url)
+}
+
+/// Look up the homeserver of a server name and keep looking it up in the background.
+///
+/// Only fails if the server name is invalid, failed lookups fall back to the server name itself.
+pub async fn discover(
+    server_name: &str,
+    client: reqwest::Client,
+    config: &DiscoveryConfig,
+) -> Result<Arc<Homeserver>> {
+    let fallback = fallback_url(server_name)?;
+
+    let (base_url, mut delay) = match resolve(&client, server_name).await {
+        Ok(base_url) => {
+            info!(server_name, %base_url, "Discovered the homeserver");
+            (base_url, config.refresh_interval())
+        }
+        Err(e) => {
+            warn!(
+                server_name,
+                "Could not discover the homeserver, using {fallback}: {e:#}"
+            );
+            (fallback, config.retry_interval())
+        }
+    };
+
+    let homeserver = Arc::new(Homeserver {
+        server_name: Some(server_name.to_owned()),
+        base_url: RwLock::new(base_url),
+    });
+
+    let server_name = server_name.to_owned();
+    let refreshed = homeserver.clone();
+    let refresh_interval = config.refresh_interval();
+    let retry_interval = config.retry_interval();
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(delay).await;
+
+            delay = match resolve(&client, &server_name).await {
+                Ok(base_url) => {
+                    let mut current = refreshed.base_url.write().unwrap();
+
+                    if *current != base_url {
+                        info!(server_name, %base_url, "The homeserver moved");
+                        *current = base_url;
+                    }
+
+                    refresh_interval
+                }
+                Err(e) => {
+                    warn!(
+                        server_name,
+                        "Could not discover the homeserver, keeping {}: {e:#}",
+                        refreshed.base_url()
+                    );
+                    retry_interval
+                }
+            };
+        }
+    });
+
+    Ok(homeserver)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, net::SocketAddr};
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Response, Server,
+    };
+
+    use super::*;
+
+    const VERSIONS: &str = r#"{"versions":["v1.1"]}"#;
+
+    /// Start a server answering requests for the paths, and 404 Not Found for everything else.
+    fn serve(routes: Vec<(String, u16, String)>) -> SocketAddr {
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service_fn(
+            move |_| {
+                let routes = routes.clone();
+
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: hyper::Request<Body>| {
+                        let route = routes
+                            .iter()
+                            .find(|(path, _, _)| path == request.uri().path())
+                            .cloned();
+
+                        async move {
+                            let (status, body) = route
+                                .map_or((404, String::new()), |(_, status, body)| (status, body));
+
+                            Ok::<_, Infallible>(
+                                Response::builder()
+                                    .status(status)
+                                    .body(Body::from(body))
+                                    .unwrap(),
+                            )
+                        }
+                    }))
+                }
+            },
+        ));
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        address
+    }
+
+    /// A homeserver served below the path, answering the supported versions with the body.
+    fn homeserver(path: &str, versions: &str) -> Url {
+        let address = serve(vec![(
+            format!("{path}_matrix/client/versions"),
+            200,
+            versions.to_owned(),
+        )]);
+
+        Url::parse(&format!("http://{address}{path}")).unwrap()
+    }
+
+    /// A server name whose well-known information is answered with the status and body.
+    async fn resolve_server_name(status: u16, body: String, fallback: Url) -> Result<Url> {
+        let address = serve(vec![(
+            "/.well-known/matrix/client".to_owned(),
+            status,
+            body,
+        )]);
+        let well_known_url =
+            Url::parse(&format!("http://{address}/.well-known/matrix/client")).unwrap();
+
+        resolve_from(&reqwest::Client::new(), well_known_url, fallback).await
+    }
+
+    fn unreachable() -> Url {
+        Url::parse("http://127.0.0.1:1/").unwrap()
+    }
+
+    fn well_known(base_url: &str) -> String {
+        format!(r#"{{"m.homeserver":{{"base_url":"{base_url}"}}}}"#)
+    }
+
+    #[tokio::test]
+    async fn uses_the_base_url_of_the_well_known_information() {
+        let base_url = homeserver("/matrix/", VERSIONS);
+        let without_slash = base_url.as_str().trim_end_matches('/');
+
+        let resolved = resolve_server_name(200, well_known(without_slash), unreachable()).await;
+        assert_eq!(resolved.unwrap(), base_url);
+    }
+
+    #[tokio::test]
+    async fn falls_back_without_well_known_information() {
+        let fallback = homeserver("/", VERSIONS);
+
+        let resolved = resolve_server_name(404, String::new(), fallback.clone()).await;
+        assert_eq!(resolved.unwrap(), fallback);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_well_known_information() {
+        let fallback = homeserver("/", VERSIONS);
+
+        for (status, body) in [
+            (200, "not json".to_owned()),
+            (200, r#"{"m.homeserver":{}}"#.to_owned()),
+            (200, well_known("not a url")),
+            (200, well_known("ftp://example.org/")),
+            (500, String::new()),
+        ] {
+            let resolved = resolve_server_name(status, body.clone(), fallback.clone()).await;
+            assert!(resolved.is_err(), "{status} {body} should be rejected");
+        }
+    }
+
+    #[tokio::test]
+    async fn checks_that_the_homeserver_answers() {
+        let missing = serve(Vec::new());
+        let invalid = homeserver("/", r#"{"versions":"v1.1"}"#);
+
+        for base_url in [
+            format!("http://{missing}/"),
+            invalid.to_string(),
+            unreachable().to_string(),
+        ] {
+            let resolved = resolve_server_name(200, well_known(&base_url), unreachable()).await;
+            assert!(resolved.is_err(), "{base_url} should be rejected");
+        }
+    }
+
+    #[test]
+    fn fallback_url_uses_the_server_name() {
+        assert_eq!(
+            fallback_url("example.org:8448").unwrap().as_str(),
+            "https://example.org:8448/"
+        );
+        assert!(fallback_url("not a server name").is_err());
+    }
+}
@@ -0,0 +1,307 @@
+//! The DTLS secured transport of the CoAP server (coaps).
+//!
+//! DTLS 1.2 is provided by webrtc-dtls, which supports pre-shared keys and certificates. Raw
+//! public keys (RFC 7250) aren't supported by the library, clients that only want to trust a
+//! public key can present a self-signed certificate for it instead, which the proxy checks
+//! against the configured key hashes.
+
+use std::{collections::HashMap, io, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use coap::server::{Listener, Responder, TransportRequestSender};
+use sha2::{Digest, Sha256};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+use tracing::{debug, warn};
+use webrtc_dtls::{
+    cipher_suite::CipherSuiteId,
+    config::{ClientAuthType, Config, ExtendedMasterSecretType},
+    conn::DTLSConn,
+    crypto::{Certificate, CryptoPrivateKey},
+};
+use webrtc_util::conn::{conn_udp_listener::ListenConfig, Conn, Listener as ConnListener};
+
+use crate::{
+    config::{DtlsClientAuth, DtlsConfig},
+    hex, tls,
+    transport::ListenerPeers,
+};
+
+/// The content type of DTLS records carrying handshake messages, only those may open a new
+/// connection.
+const HANDSHAKE_CONTENT_TYPE: u8 = 22;
+
+/// Time a client has to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Connections that didn't receive anything for this long are closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Size of the buffer a single DTLS record is decrypted into.
+const RECEIVE_BUFFER_SIZE: usize = 8192;
+
+/// Cipher suites offered in the pre-shared key mode, starting with the one RFC 7252 mandates.
+const PSK_CIPHER_SUITES: &[CipherSuiteId] = &[
+    CipherSuiteId::Tls_Psk_With_Aes_128_Ccm_8,
+    CipherSuiteId::Tls_Psk_With_Aes_128_Ccm,
+    CipherSuiteId::Tls_Psk_With_Aes_128_Gcm_Sha256,
+];
+
+/// Cipher suites offered in the certificate mode, starting with the one RFC 7252 mandates.
+const CERTIFICATE_CIPHER_SUITES: &[CipherSuiteId] = &[
+    CipherSuiteId::Tls_Ecdhe_Ecdsa_With_Aes_128_Ccm_8,
+    CipherSuiteId::Tls_Ecdhe_Ecdsa_With_Aes_128_Ccm,
+    CipherSuiteId::Tls_Ecdhe_Ecdsa_With_Aes_128_Gcm_Sha256,
+    CipherSuiteId::Tls_Ecdhe_Rsa_With_Aes_128_Gcm_Sha256,
+];
+
+/// Build the DTLS configuration of the listeners, loading the configured credentials.
+pub fn server_config(config: &DtlsConfig) -> Result<Config> {
+    let mut server_config = Config {
+        extended_master_secret: ExtendedMasterSecretType::Require,
+        ..Default::default()
+    };
+
+    if !config.psk.is_empty() {
+        let keys: HashMap<Vec<u8>, Vec<u8>> = config
+            .psk
+            .iter()
+            .map(|(identity, key)| {
+                let key = hex::decode(key).expect("The pre-shared keys should have been validated");
+                (identity.as_bytes().to_vec(), key)
+            })
+            .collect();
+
+        server_config.cipher_suites = PSK_CIPHER_SUITES.to_vec();
+        server_config.psk = Some(Arc::new(move |identity: &[u8]| {
+            keys.get(identity)
+                .cloned()
+                .ok_or(webrtc_dtls::Error::ErrIdentityNoPsk)
+        }));
+        server_config.psk_identity_hint = config
+            .psk_identity_hint
+            .as_ref()
+            .map(|hint| hint.as_bytes().to_vec());
+
+        return Ok(server_config);
+    }
+
+    let (Some(certificate), Some(private_key)) = (&config.certificate, &config.private_key) else {
+        bail!("The DTLS listeners need either pre-shared keys or a certificate");
+    };
+
+    server_config.cipher_suites = CERTIFICATE_CIPHER_SUITES.to_vec();
+    server_config.certificates = vec![Certificate {
+        certificate: tls::load_certificates(certificate)?,
+        private_key: load_private_key(private_key)?,
+    }];
+
+    match config.client_auth {
+        DtlsClientAuth::None => {}
+        DtlsClientAuth::Ca => {
+            let path = config
+                .client_ca
+                .as_ref()
+                .context("The client certificate authority needs to be configured")?;
+
+            for certificate in tls::load_certificates(path)? {
+                server_config
+                    .client_cas
+                    .add(&certificate)
+                    .with_context(|| {
+                        format!("Invalid client CA certificate in {}", path.display())
+                    })?;
+            }
+
+            server_config.client_auth = ClientAuthType::RequireAndVerifyClientCert;
+        }
+        DtlsClientAuth::Pinned => {
+            let client_keys: Vec<Vec<u8>> = config
+                .client_keys
+                .iter()
+                .map(|key| hex::decode(key).expect("The client keys should have been validated"))
+                .collect();
+
+            server_config.client_auth = ClientAuthType::RequireAnyClientCert;
+            server_config.verify_peer_certificate =
+                Some(Arc::new(move |certificates: &[Vec<u8>], _| {
+                    let key_hash = certificates
+                        .first()
+                        .and_then(|certificate| public_key_hash(certificate))
+                        .ok_or(webrtc_dtls::Error::ErrCertificateVerifyNoCertificate)?;
+
+                    if client_keys.contains(&key_hash) {
+                        Ok(())
+                    } else {
+                        Err(webrtc_dtls::Error::Other(
+                            "The client key isn't allowed".to_owned(),
+                        ))
+                    }
+                }));
+        }
+    }
+
+    Ok(server_config)
+}
+
+fn load_private_key(path: &Path) -> Result<CryptoPrivateKey> {
+    let key = tls::load_private_key(path)?;
+
+    let key_pair = rcgen::KeyPair::from_der(&key.0)
+        .with_context(|| format!("Unsupported private key in {}", path.display()))?;
+
+    CryptoPrivateKey::from_key_pair(&key_pair)
+        .with_context(|| format!("Unsupported private key in {}", path.display()))
+}
+
+/// The SHA-256 hash of the public key of a DER encoded certificate.
+fn public_key_hash(certificate: &[u8]) -> Option<Vec<u8>> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(certificate).ok()?;
+
+    Some(Sha256::digest(certificate.public_key().raw).to_vec())
+}
+
+/// A listener for DTLS secured CoAP which registers every peer it receives a message from.
+pub struct DtlsListener {
+    listener: Box<dyn ConnListener + Send + Sync>,
+    config: Config,
+    peers: ListenerPeers,
+    connections: Arc<Semaphore>,
+}
+
+impl DtlsListener {
+    /// Bind a UDP socket to the address, accepting DTLS connections on it.
+    ///
+    /// Every connection, from the start of its handshake, counts towards the limit of open
+    /// connections, connections beyond it are closed right away.
+    pub async fn new(
+        address: SocketAddr,
+        config: Config,
+        peers: ListenerPeers,
+        connections: Arc<Semaphore>,
+    ) -> Result<Self> {
+        let mut listen_config = ListenConfig {
+            accept_filter: Some(Box::new(|packet: &[u8]| {
+                let handshake = packet.first() == Some(&HANDSHAKE_CONTENT_TYPE);
+                Box::pin(async move { handshake })
+            })),
+            ..Default::default()
+        };
+
+        let listener = listen_config.listen(address).await?;
+
+        Ok(Self {
+            listener: Box::new(listener),
+            config,
+            peers,
+            connections,
+        })
+    }
+
+    async fn accept_loop(self, sender: TransportRequestSender) -> io::Result<()> {
+        loop {
+            let (conn, address) = self.listener.accept().await.map_err(io::Error::other)?;
+
+            let Ok(permit) = self.connections.clone().try_acquire_owned() else {
+                warn!("Too many open DTLS connections, closing the connection of {address}");
+                let _ = conn.close().await;
+                continue;
+            };
+
+            tokio::spawn(serve(
+                conn,
+                address,
+                permit,
+                self.config.clone(),
+                self.peers.clone(),
+                sender.clone(),
+            ));
+        }
+    }
+}
+
+#[async_trait]
+impl Listener for DtlsListener {
+    async fn listen(
+        self: Box<Self>,
+        sender: TransportRequestSender,
+    ) -> io::Result<JoinHandle<io::Result<()>>> {
+        Ok(tokio::spawn(self.accept_loop(sender)))
+    }
+}
+
+/// Complete the handshake with a client and pass the messages it sends on to the server.
+async fn serve(
+    conn: Arc<dyn Conn + Send + Sync>,
+    address: SocketAddr,
+    _permit: OwnedSemaphorePermit,
+    config: Config,
+    peers: ListenerPeers,
+    sender: TransportRequestSender,
+) {
+    let handshake = DTLSConn::new(conn.clone(), config, false, None);
+
+    let dtls_conn = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok(dtls_conn)) => Arc::new(dtls_conn),
+        Ok(Err(e)) => {
+            debug!("The DTLS handshake with {address} failed: {e}");
+            let _ = conn.close().await;
+            return;
+        }
+        Err(_) => {
+            debug!("The DTLS handshake with {address} timed out");
+            let _ = conn.close().await;
+            return;
+        }
+    };
+
+    debug!("Established a DTLS connection with {address}");
+
+    loop {
+        let mut buffer = vec![0; RECEIVE_BUFFER_SIZE];
+
+        let length = match tokio::time::timeout(IDLE_TIMEOUT, dtls_conn.recv(&mut buffer)).await {
+            Ok(Ok(length)) if length > 0 => length,
+            _ => break,
+        };
+        buffer.truncate(length);
+
+        let responder: Arc<dyn Responder> = Arc::new(DtlsResponder {
+            conn: dtls_conn.clone(),
+            address,
+        });
+
+        if !peers.insert_verified(responder.clone()) {
+            debug!("Dropping a message from {address}, which is connected over TCP");
+            continue;
+        }
+
+        if sender.send((buffer, responder)).is_err() {
+            break;
+        }
+    }
+
+    debug!("Closing the DTLS connection with {address}");
+    let _ = dtls_conn.close().await;
+}
+
+struct DtlsResponder {
+    conn: Arc<DTLSConn>,
+    address: SocketAddr,
+}
+
+#[async_trait]
+impl Responder for DtlsResponder {
+    async fn respond(&self, response: Vec<u8>) {
+        if let Err(e) = self.conn.send(&response).await {
+            debug!("Could not send a message to {}: {e}", self.address);
+        }
+    }
+
+    fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
@@ -0,0 +1,246 @@
+//! Verification of the address of clients using the Echo option (RFC 9175).
+//!
+//! Requests from endpoints that haven't proven their address yet are answered with 4.01
+//! Unauthorized and an Echo option instead of being forwarded. The client repeats the request
+//! with the Echo value, which it can only know if it received the challenge at its address. A
+//! request with a forged source address therefore never reaches the homeserver.
+//!
+//! The Echo values carry the time they were issued and a MAC over the time and the address of
+//! the endpoint, so challenges don't need to be remembered. Verified endpoints are remembered
+//! for a while, so only their first request needs a round trip more.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use coap_lite::{CoapOption, Packet};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::config::EchoConfig;
+
+/// The Echo option, which coap-lite doesn't know about.
+pub const ECHO: CoapOption = CoapOption::Unknown(252);
+
+/// Length, in bytes, of the MAC in an Echo value.
+const MAC_LENGTH: usize = 8;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[derive(Debug)]
+struct VerifiedTable {
+    /// The verified endpoints and until when they stay verified.
+    endpoints: HashMap<SocketAddr, Instant>,
+    last_pruned: Instant,
+}
+
+/// Challenges unverified endpoints and remembers the verified ones.
+pub struct EchoVerifier {
+    key: [u8; 32],
+    challenge_lifetime: Duration,
+    verified_lifetime: Duration,
+    verified: Mutex<VerifiedTable>,
+}
+
+impl std::fmt::Debug for EchoVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EchoVerifier")
+            .field("challenge_lifetime", &self.challenge_lifetime)
+            .field("verified_lifetime", &self.verified_lifetime)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EchoVerifier {
+    pub fn new(config: &EchoConfig) -> Self {
+        let mut key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+
+        Self {
+            key,
+            challenge_lifetime: config.challenge_lifetime(),
+            verified_lifetime: config.verified_lifetime(),
+            verified: Mutex::new(VerifiedTable {
+                endpoints: HashMap::new(),
+                last_pruned: Instant::now(),
+            }),
+        }
+    }
+
+    fn mac(&self, timestamp: &[u8], address: SocketAddr) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC should accept any key length");
+        mac.update(timestamp);
+        mac.update(address.to_string().as_bytes());
+        mac
+    }
+
+    /// Create the Echo value for a challenge of the endpoint.
+    pub fn challenge(&self, address: SocketAddr) -> Vec<u8> {
+        let timestamp = now().to_be_bytes();
+
+        let mut echo = timestamp.to_vec();
+        echo.extend_from_slice(
+            &self.mac(&timestamp, address).finalize().into_bytes()[..MAC_LENGTH],
+        );
+        echo
+    }
+
+    /// Check whether the Echo value was issued to the endpoint and is still fresh.
+    fn is_valid(&self, echo: &[u8], address: SocketAddr) -> bool {
+        let Some((timestamp, mac)) = echo.split_first_chunk::<8>() else {
+            return false;
+        };
+
+        let age = now().saturating_sub(u64::from_be_bytes(*timestamp));
+
+        mac.len() == MAC_LENGTH
+            && age <= self.challenge_lifetime.as_secs()
+            && self
+                .mac(timestamp, address)
+                .verify_truncated_left(mac)
+                .is_ok()
+    }
+
+    /// Check whether the endpoint was verified recently, or proves its address with the Echo
+    /// option of the request, it's then remembered as verified.
+    pub fn verify(&self, address: SocketAddr, message: &Packet) -> bool {
+        if self.is_verified(address) {
+            return true;
+        }
+
+        let proven = message
+            .get_option(ECHO)
+            .is_some_and(|values| values.iter().any(|echo| self.is_valid(echo, address)));
+
+        if proven {
+            let mut table = self.verified.lock().unwrap();
+            let now = Instant::now();
+
+            if now.duration_since(table.last_pruned) > self.verified_lifetime {
+                table.endpoints.retain(|_, until| *until > now);
+                table.last_pruned = now;
+            }
+
+            table
+                .endpoints
+                .insert(address, now + self.verified_lifetime);
+        }
+
+        proven
+    }
+
+    /// Check whether the endpoint proved its address recently.
+    pub fn is_verified(&self, address: SocketAddr) -> bool {
+        self.verified
+            .lock()
+            .unwrap()
+            .endpoints
+            .get(&address)
+            .is_some_and(|until| *until > Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verifier() -> EchoVerifier {
+        EchoVerifier::new(&EchoConfig {
+            enabled: true,
+            challenge_lifetime: 60,
+            verified_lifetime: 600,
+        })
+    }
+
+    fn request(echo: Option<Vec<u8>>) -> Packet {
+        let mut message = Packet::new();
+
+        if let Some(echo) = echo {
+            message.add_option(ECHO, echo);
+        }
+
+        message
+    }
+
+    const CLIENT: ([u8; 4], u16) = ([192, 0, 2, 1], 5683);
+    const SPOOFED: ([u8; 4], u16) = ([192, 0, 2, 2], 5683);
+
+    #[test]
+    fn verifies_the_endpoint_the_value_was_issued_to() {
+        let verifier = verifier();
+        let client = SocketAddr::from(CLIENT);
+
+        assert!(!verifier.verify(client, &request(None)));
+        assert!(!verifier.is_verified(client));
+
+        let echo = verifier.challenge(client);
+        assert!(verifier.verify(client, &request(Some(echo))));
+        assert!(verifier.is_verified(client));
+        assert!(verifier.verify(client, &request(None)));
+    }
+
+    #[test]
+    fn rejects_values_issued_to_other_endpoints() {
+        let verifier = verifier();
+        let client = SocketAddr::from(CLIENT);
+        let echo = verifier.challenge(client);
+
+        for other in [
+            SocketAddr::from(SPOOFED),
+            SocketAddr::from((CLIENT.0, 5684)),
+        ] {
+            assert!(!verifier.verify(other, &request(Some(echo.clone()))));
+            assert!(!verifier.is_verified(other));
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_values() {
+        let verifier = verifier();
+        let client = SocketAddr::from(CLIENT);
+        let echo = verifier.challenge(client);
+
+        let mut tampered = echo.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(!verifier.verify(client, &request(Some(tampered))));
+
+        // Moving the timestamp invalidates the MAC.
+        let mut tampered = echo.clone();
+        tampered[7] ^= 1;
+        assert!(!verifier.verify(client, &request(Some(tampered))));
+
+        assert!(!verifier.verify(client, &request(Some(echo[..8].to_vec()))));
+        assert!(!verifier.verify(client, &request(Some(Vec::new()))));
+    }
+
+    #[test]
+    fn rejects_values_issued_by_another_proxy() {
+        let client = SocketAddr::from(CLIENT);
+        let echo = verifier().challenge(client);
+
+        assert!(!verifier().verify(client, &request(Some(echo))));
+    }
+
+    #[test]
+    fn rejects_expired_values() {
+        let verifier = verifier();
+        let client = SocketAddr::from(CLIENT);
+
+        let timestamp = (now() - 61).to_be_bytes();
+        let mut echo = timestamp.to_vec();
+        echo.extend_from_slice(
+            &verifier.mac(&timestamp, client).finalize().into_bytes()[..MAC_LENGTH],
+        );
+
+        assert!(!verifier.verify(client, &request(Some(echo))));
+    }
+}
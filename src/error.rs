@@ -0,0 +1,107 @@
+use std::fmt;
+
+use coap_lite::ResponseType;
+
+/// Errors that can happen while forwarding a request to the homeserver.
+///
+/// Every variant maps onto a fixed CoAP response code so the client can tell a failure of the
+/// proxy apart from a response of the homeserver.
+#[derive(Debug)]
+pub enum UpstreamError {
+    /// The URI options of the request could not be turned into a valid homeserver URL.
+    InvalidUrl(anyhow::Error),
+    /// No connection to the homeserver could be established.
+    Connect(reqwest::Error),
+    /// The homeserver didn't respond in time.
+    Timeout(reqwest::Error),
+    /// The response body of the homeserver could not be read.
+    Body(reqwest::Error),
+    /// Sending the request failed for some other reason.
+    Request(reqwest::Error),
+}
+
+impl UpstreamError {
+    /// Classify an error that happened while sending the request.
+    pub fn from_send(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            Self::Timeout(error)
+        } else if error.is_connect() {
+            Self::Connect(error)
+        } else {
+            Self::Request(error)
+        }
+    }
+
+    /// Classify an error that happened while reading the response body.
+    pub fn from_body(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            Self::Timeout(error)
+        } else {
+            Self::Body(error)
+        }
+    }
+
+    /// The CoAP response code the client should receive for this error.
+    pub fn response_type(&self) -> ResponseType {
+        match self {
+            Self::InvalidUrl(_) => ResponseType::BadOption,
+            Self::Timeout(_) => ResponseType::GatewayTimeout,
+            Self::Connect(_) | Self::Body(_) | Self::Request(_) => ResponseType::BadGateway,
+        }
+    }
+
+    /// The class of the error in the metrics.
+    pub fn class(&self) -> &'static str {
+        match self {
+            Self::InvalidUrl(_) => "invalid_url",
+            Self::Connect(_) => "upstream_connect",
+            Self::Timeout(_) => "upstream_timeout",
+            Self::Body(_) => "upstream_body",
+            Self::Request(_) => "upstream_request",
+        }
+    }
+
+    /// A short description of the error which is safe to send to the client.
+    ///
+    /// Unlike the [`Display`](fmt::Display) implementation this doesn't include the underlying
+    /// error, which might reveal details about the upstream network.
+    pub fn diagnostic(&self) -> &'static str {
+        match self {
+            Self::InvalidUrl(_) => "Could not build the homeserver URL",
+            Self::Connect(_) => "Could not connect to the homeserver",
+            Self::Timeout(_) => "The homeserver did not respond in time",
+            Self::Body(_) => "Could not read the response of the homeserver",
+            Self::Request(_) => "Could not send out the HTTP request",
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidUrl(e) => write!(f, "{}: {e:#}", self.diagnostic()),
+            Self::Connect(e) | Self::Timeout(e) | Self::Body(e) | Self::Request(e) => {
+                write!(f, "{}: {e}", self.diagnostic())
+            }
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_urls_are_errors_of_the_client() {
+        let error = UpstreamError::InvalidUrl(anyhow::anyhow!("The Uri-Path option isn't valid"));
+
+        assert_eq!(error.response_type(), ResponseType::BadOption);
+        assert_eq!(error.class(), "invalid_url");
+        assert_eq!(
+            error.to_string(),
+            "Could not build the homeserver URL: The Uri-Path option isn't valid"
+        );
+    }
+}
@@ -0,0 +1,17 @@
+//! Hexadecimal encoding of binary values in configuration and state files.
+
+/// Encode the bytes as lowercase hex.
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Decode a hex string, returns `None` if it isn't valid hex.
+pub fn decode(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect()
+}
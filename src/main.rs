@@ -1,127 +1,1085 @@
 use anyhow::{Context, Result};
 use bytes::Bytes;
-use coap::Server;
-use coap_lite::{CoapRequest, RequestType as Method};
-use std::net::SocketAddr;
-use tracing::{debug, error, field::debug, info, instrument, trace, Span};
-use url::Url;
+use clap::Parser;
+use coap::{server::Listener, Server};
+use coap_lite::{
+    option_value::{OptionValueU16, OptionValueU32},
+    CoapOption, CoapRequest, CoapResponse, ContentFormat, MessageType, ObserveOption, Packet,
+    RequestType as Method, ResponseType,
+};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
+use tracing::{debug, error, field::debug, info, instrument, trace, warn, Span};
 
-const LISTEN_ADDRESS: &str = "127.0.0.1:5683";
-const HOMESEVER: &str = "http://localhost:8015/";
+mod amplification;
+mod auth;
+mod block;
+mod cache;
+mod cbor;
+mod conditional;
+mod config;
+mod confirmable;
+mod content_format;
+mod discovery;
+mod dtls;
+mod echo;
+mod error;
+mod forward;
+mod hex;
+mod method;
+mod metrics;
+mod observe;
+mod oscore;
+mod proxy;
+mod rate_limit;
+mod routes;
+mod separate;
+mod session;
+mod status;
+mod tcp;
+mod tls;
+mod transport;
+mod uri;
+mod virtual_host;
+mod websocket;
+
+use block::UploadLimit;
+use cache::{Lookup, UpstreamResponse};
+use config::{Args, Config, LogFormat};
+use dtls::DtlsListener;
+use error::UpstreamError;
+use observe::Observation;
+use oscore::Oscore;
+use proxy::Proxy;
+use session::SessionUse;
+use tcp::TcpListener;
+use transport::{Peers, UdpListener};
+use websocket::WebSocketListener;
+
+/// Reply to the client with an error response carrying a diagnostic payload.
+fn set_error_response(
+    request: &mut CoapRequest<SocketAddr>,
+    status: ResponseType,
+    diagnostic: &str,
+) {
+    if let Some(message) = &mut request.response {
+        message.set_status(status);
+        message.message.payload = diagnostic.as_bytes().to_vec();
+    }
+}
+
+/// Tell the client how long to wait before sending the request again.
+fn set_retry_after(request: &mut CoapRequest<SocketAddr>, retry_after: Duration) {
+    if let Some(message) = &mut request.response {
+        let seconds =
+            u32::try_from(retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0))
+                .unwrap_or(u32::MAX);
+
+        message.message.clear_option(CoapOption::MaxAge);
+        message
+            .message
+            .add_option_as(CoapOption::MaxAge, OptionValueU32(seconds));
+    }
+}
+
+/// Reply to the client with the CoAP response code that matches the upstream failure.
+fn set_upstream_error_response(
+    proxy: &Proxy,
+    request: &mut CoapRequest<SocketAddr>,
+    error: UpstreamError,
+) {
+    error!("{error}");
+    proxy.metrics.record_error(error.class());
+    set_error_response(request, error.response_type(), error.diagnostic());
+}
+
+/// Handle a message from a client, enforcing the rate limits and answering slow requests with
+/// separate responses.
+async fn request_handler(
+    proxy: Arc<Proxy>,
+    mut request: Box<CoapRequest<SocketAddr>>,
+) -> Box<CoapRequest<SocketAddr>> {
+    let source = request
+        .source
+        .expect("The server should always set the source of a request");
+
+    // Replies to notifications aren't requests, they don't count towards the rate limit.
+    let is_reply = matches!(
+        request.message.header.get_type(),
+        MessageType::Acknowledgement | MessageType::Reset
+    );
+
+    if let (Some(rate_limits), false) = (&proxy.rate_limits, is_reply) {
+        if let Err(retry_after) = rate_limits.check_address(source.ip()) {
+            debug!(%source, "The client exceeded the rate limit of its address");
+            proxy.metrics.record_error("rate_limited");
+            set_error_response(
+                &mut request,
+                ResponseType::TooManyRequests,
+                "Too many requests",
+            );
+            set_retry_after(&mut request, retry_after);
+            record_request(&proxy, &request);
+            return request;
+        }
+    }
+
+    // Requests from endpoints that didn't prove their address yet aren't forwarded, the client
+    // needs to repeat them with the Echo value first. The Echo option of OSCORE protected
+    // requests is encrypted, it's checked once the request is decrypted.
+    if !is_reply
+        && !Oscore::is_protected(&request.message)
+        && challenge_unverified(&proxy, &mut request)
+    {
+        record_request(&proxy, &request);
+        return request;
+    }
+
+    if !is_reply && proxy.blocks.serve(&mut request) {
+        record_request(&proxy, &request);
+        return request;
+    }
+
+    // Reliable transports don't acknowledge messages, so their responses are never separate.
+    let separate = proxy
+        .separate
+        .as_ref()
+        .filter(|_| {
+            request.message.header.get_type() == MessageType::Confirmable
+                && !proxy.peers.is_connected(source)
+        })
+        .zip(proxy.peers.get(source));
+
+    let Some((separate, responder)) = separate else {
+        return handle_guarded(proxy.clone(), source, request).await;
+    };
+
+    let exchange = (source, request.message.header.message_id);
+
+    if !separate.begin(exchange) {
+        debug!(%source, "Acknowledging a retransmission of a request that is still handled");
+        separate.acknowledge(exchange, responder.as_ref()).await;
+        request.response = None;
+        return request;
+    }
+
+    let handling = handle_guarded(proxy.clone(), source, request);
+    tokio::pin!(handling);
+
+    let mut request = match tokio::time::timeout(separate.ack_delay(), &mut handling).await {
+        Ok(request) => request,
+        Err(_) => {
+            debug!(%source, "Acknowledging a slow request, its response follows separately");
+            separate.acknowledge(exchange, responder.as_ref()).await;
+            handling.await
+        }
+    };
+
+    // A retransmission may have been acknowledged while the request was handled.
+    if separate.is_acknowledged(exchange) {
+        separate
+            .deliver(
+                &proxy.confirmations,
+                &proxy.blocks,
+                responder.as_ref(),
+                &mut request,
+            )
+            .await;
+    }
+
+    separate.finish(exchange);
+    request
+}
+
+/// Handle a request, replacing responses that are too large for a source that didn't prove its
+/// address if the amplification guard is enabled.
+async fn handle_guarded(
+    proxy: Arc<Proxy>,
+    source: SocketAddr,
+    request: Box<CoapRequest<SocketAddr>>,
+) -> Box<CoapRequest<SocketAddr>> {
+    let request_size = proxy
+        .amplification
+        .as_ref()
+        .map(|_| amplification::request_size(&request.message));
+
+    let (mut request, unprotected_response) = unprotect_and_handle(proxy.clone(), request).await;
+
+    let too_large = proxy
+        .amplification
+        .as_ref()
+        .zip(request_size)
+        .zip(request.response.as_ref())
+        .is_some_and(|((guard, request_size), response)| {
+            guard.exceeds(request_size, &response.message)
+        });
+
+    if too_large && !proxy.is_verified(source) {
+        warn!(%source, "The response is too large for a source that didn't prove its address");
+        proxy.metrics.record_error("amplification");
+        // Even a diagnostic payload could exceed the limit.
+        request.response = CoapResponse::new(&request.message);
+
+        if let Some(message) = &mut request.response {
+            message.set_status(ResponseType::ServiceUnavailable);
+            // The response starts out as a copy of the request, including the payload.
+            message.message.payload.clear();
+        }
+
+        set_retry_after(&mut request, amplification::RETRY_AFTER);
+        record_request(&proxy, &request);
+        return request;
+    }
+
+    // Protected responses are counted with the code the client sees once it decrypts them.
+    match &unprotected_response {
+        Some(response) => proxy
+            .metrics
+            .record_request(&request.message, Some(response)),
+        None => record_request(&proxy, &request),
+    }
+
+    request
+}
+
+/// Answer the request with an Echo challenge if Echo is enabled and the source didn't prove its
+/// address yet.
+///
+/// Returns `true` if the request was challenged, it then must not be handled.
+fn challenge_unverified(proxy: &Proxy, request: &mut CoapRequest<SocketAddr>) -> bool {
+    let source = request
+        .source
+        .expect("The server should always set the source of a request");
+
+    let Some(echo) = &proxy.echo else {
+        return false;
+    };
+
+    if proxy.peers.is_verified(source) || echo.verify(source, &request.message) {
+        return false;
+    }
+
+    debug!(%source, "Challenging an unverified endpoint");
+
+    if let Some(message) = &mut request.response {
+        message.set_status(ResponseType::Unauthorized);
+        // The response starts out as a copy of the request, including the payload.
+        message.message.payload.clear();
+        message
+            .message
+            .add_option(echo::ECHO, echo.challenge(source));
+    }
+
+    true
+}
+
+/// Handle a request, verifying and decrypting it first if it's protected using OSCORE.
+///
+/// Returns the handled request and, if the response got protected, the response as it was
+/// before, which is what the metrics should count.
+async fn unprotect_and_handle(
+    proxy: Arc<Proxy>,
+    mut request: Box<CoapRequest<SocketAddr>>,
+) -> (Box<CoapRequest<SocketAddr>>, Option<Packet>) {
+    if !Oscore::is_protected(&request.message) {
+        return (handle_request(proxy, request).await, None);
+    }
+
+    let protection = match proxy.oscore.unprotect(&mut request.message) {
+        Ok(protection) => protection,
+        Err(e) => {
+            warn!("Could not verify an OSCORE protected request: {e}");
+            proxy.metrics.record_error("oscore");
+            set_error_response(&mut request, e.response_type(), e.diagnostic());
+
+            // Error responses aren't protected, so they shouldn't be cached either.
+            if let Some(message) = &mut request.response {
+                message
+                    .message
+                    .add_option_as(CoapOption::MaxAge, OptionValueU32(0));
+            }
+
+            return (request, None);
+        }
+    };
+
+    let mut request = if challenge_unverified(&proxy, &mut request) {
+        request
+    } else {
+        handle_request(proxy.clone(), request).await
+    };
+
+    let unprotected_response = request
+        .response
+        .as_ref()
+        .map(|response| response.message.clone());
+
+    if let Some(message) = &mut request.response {
+        protection.protect(&mut message.message);
+    }
+
+    (request, unprotected_response)
+}
+
+/// Count the request and the response it got in the metrics.
+fn record_request(proxy: &Proxy, request: &CoapRequest<SocketAddr>) {
+    proxy.metrics.record_request(
+        &request.message,
+        request.response.as_ref().map(|response| &response.message),
+    );
+}
+
+/// Get the response of the homeserver, from the cache if possible.
+///
+/// The request builder only needs the method and URL, the rest of the request is added here.
+/// Returns the response and the time it stays fresh, `None` if it can't be cached.
+async fn fetch(
+    proxy: &Proxy,
+    request_builder: reqwest::RequestBuilder,
+    mut headers: HeaderMap,
+    body: Bytes,
+    access_token: Option<&str>,
+    cache_key: Option<&cache::Key>,
+) -> Result<(UpstreamResponse, Option<Duration>), UpstreamError> {
+    let cache = proxy.cache.as_ref().zip(cache_key);
+
+    let stale = match cache.and_then(|(cache, key)| cache.lookup(key)) {
+        Some(Lookup::Fresh(upstream, freshness)) => {
+            debug!("Answering the request from the cache");
+            Span::current().record("http_status", debug(upstream.status));
+            return Ok((upstream, Some(freshness)));
+        }
+        Some(Lookup::Stale(upstream, etag)) => Some((upstream, etag)),
+        None => None,
+    };
+
+    // A stale response only needs to be fetched again if it changed. Requests carrying the ETags
+    // of the client bypass the cache, so a confirmation can only be about this entity tag.
+    if let Some((_, etag)) = &stale {
+        headers.insert(IF_NONE_MATCH, etag.clone());
+    }
+
+    let request_size = body.len();
+    let request_builder = request_builder.headers(headers);
+
+    let request_builder = if body.is_empty() {
+        request_builder
+    } else {
+        request_builder.body(body)
+    };
+
+    let request_builder = if let Some(access_token) = access_token {
+        request_builder.bearer_auth(access_token)
+    } else {
+        request_builder
+    };
+
+    trace!("Built the HTTP request");
+
+    let started = Instant::now();
+    let response = request_builder
+        .send()
+        .await
+        .map_err(UpstreamError::from_send)?;
+
+    let http_status = response.status();
+    Span::current().record("http_status", debug(http_status));
+
+    debug!("Successfully sent the HTTP response");
+
+    let headers = response.headers().clone();
+    let body = response.bytes().await.map_err(UpstreamError::from_body)?;
+
+    proxy
+        .metrics
+        .record_upstream(http_status, started.elapsed(), request_size, body.len());
+
+    if let (Some((upstream, etag)), Some((cache, key))) = (stale, cache) {
+        if cache::confirms(http_status, &headers, &etag) {
+            debug!("The homeserver confirmed that the cached response is still valid");
+            return Ok((upstream, cache.revalidate(key, &headers)));
+        }
+    }
+
+    let upstream = UpstreamResponse {
+        status: http_status,
+        content_type: headers
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(str::to_owned),
+        etag: headers.get(ETAG).cloned(),
+        body,
+    };
+
+    let freshness = match cache {
+        Some((cache, key)) => cache.store(key.clone(), &headers, &upstream),
+        None if matches!(
+            http_status,
+            reqwest::StatusCode::OK | reqwest::StatusCode::NOT_MODIFIED
+        ) =>
+        {
+            cache::freshness(&headers, access_token.is_some())
+        }
+        None => None,
+    };
+
+    Ok((upstream, freshness))
+}
 
 #[instrument(
     skip_all,
     fields(
         coap_method = ?request.get_method(),
         coap_path = request.get_path(),
-        http_url, 
+        http_url,
         http_status,
     )
 )]
-async fn request_handler(
+async fn handle_request(
+    proxy: Arc<Proxy>,
     mut request: Box<CoapRequest<SocketAddr>>,
 ) -> Box<CoapRequest<SocketAddr>> {
-    let client = reqwest::Client::new();
+    let source = request
+        .source
+        .expect("The server should always set the source of a request");
+
+    // Acknowledgements and resets are replies to our notifications, not requests.
+    if matches!(
+        request.message.header.get_type(),
+        MessageType::Acknowledgement | MessageType::Reset
+    ) {
+        proxy.confirmations.handle_reply(source, &request.message);
+        return request;
+    }
 
     info!("Received a CoAP request");
 
-    let method = match request.get_method() {
-        Method::Get => reqwest::Method::GET,
-        Method::Post => reqwest::Method::POST,
-        Method::Put => reqwest::Method::PUT,
-        Method::Delete => reqwest::Method::DELETE,
-        Method::Patch => reqwest::Method::PATCH,
-        m => unimplemented!("Method {m:?} is not supported"),
+    let Some(method) = method::http_method(request.get_method()) else {
+        warn!("Received a request with an unsupported method");
+        set_error_response(
+            &mut request,
+            ResponseType::MethodNotAllowed,
+            "The request method is not supported",
+        );
+        return request;
     };
 
-    let url = HOMESEVER.to_owned() + &request.get_path();
-    let mut url = Url::parse(&url).unwrap();
+    // Bodies sent block-wise have already been reassembled at this point, uploads growing too
+    // large were stopped before. The client might still have announced a larger body than it sent
+    // so far.
+    let request_size = u32::try_from(request.message.payload.len())
+        .unwrap_or(u32::MAX)
+        .max(block::announced_request_size(&request.message).unwrap_or(0));
 
-    let access_token: Option<String>;
+    if request_size > proxy.config.max_request_body {
+        warn!(request_size, "Received a request with a too large body");
+        set_error_response(
+            &mut request,
+            ResponseType::RequestEntityTooLarge,
+            "The request body is too large",
+        );
 
-    // Let's move the access token out of the query string so we can put it into a header.
-    {
-        let query_pairs: Vec<(String, String)>;
+        if let Some(message) = &mut request.response {
+            block::set_size1(&mut message.message, proxy.config.max_request_body);
+        }
+
+        return request;
+    }
 
-        let pairs = url.query_pairs();
-        access_token = pairs
-            .into_iter()
-            .find(|(key, _)| key == "access_token")
-            .map(|(_, value)| "Bearer ".to_owned() + &value);
+    let wants_size2 = block::wants_size2(&request.message);
+
+    let accept = request
+        .message
+        .get_first_option_as::<OptionValueU16>(CoapOption::Accept)
+        .and_then(|value| value.ok())
+        .and_then(|value| ContentFormat::try_from(usize::from(value.0)).ok());
+
+    // The route table is served by the proxy itself.
+    if request.get_path() == routes::ROUTE_TABLE_PATH {
+        if method != reqwest::Method::GET {
+            set_error_response(
+                &mut request,
+                ResponseType::MethodNotAllowed,
+                "The route table can only be fetched",
+            );
+            return request;
+        }
 
-        query_pairs = pairs
-            .filter(|(key, _)| key != "access_token")
-            .map(|(k, v)| (k.to_string(), v.to_string()))
-            .collect();
+        let table = proxy.routes.to_json();
 
-        let mut mut_query_pairs = url.query_pairs_mut();
-        mut_query_pairs.clear();
-        mut_query_pairs.extend_pairs(query_pairs).finish();
+        if let Some(message) = &mut request.response {
+            message.set_status(ResponseType::Content);
+
+            if accept == Some(ContentFormat::ApplicationCBOR) {
+                message
+                    .message
+                    .set_content_format(ContentFormat::ApplicationCBOR);
+                message.message.payload =
+                    cbor::json_to_cbor(&table).expect("The route table should be valid JSON");
+            } else {
+                message
+                    .message
+                    .set_content_format(ContentFormat::ApplicationJSON);
+                message.message.payload = table;
+            }
+
+            block::set_size2(&mut message.message, wants_size2);
+        }
+
+        return request;
     }
 
-    Span::current().record("http_url", debug(&url));
+    let request_format = request
+        .message
+        .get_first_option_as::<OptionValueU16>(CoapOption::ContentFormat)
+        .and_then(|value| value.ok())
+        .map(|value| value.0);
+
+    // Clients may send their bodies as CBOR, the homeserver only understands JSON. Bodies
+    // without a Content-Format are assumed to be JSON.
+    let (mut body, content_type) = match request_format {
+        Some(content_format::CBOR) => match cbor::cbor_to_json(&request.message.payload) {
+            Ok(body) => (Bytes::from(body), content_format::JSON_MEDIA_TYPE),
+            Err(e) => {
+                warn!("Could not convert the CBOR request body into JSON {e:#}");
+                set_error_response(&mut request, ResponseType::BadRequest, &format!("{e:#}"));
+                return request;
+            }
+        },
+        Some(request_format) => match proxy.content_formats.media_type(request_format) {
+            Some(media_type) => (Bytes::from(request.message.payload.clone()), media_type),
+            None => {
+                warn!(
+                    request_format,
+                    "Received a request with an unknown Content-Format"
+                );
+                set_error_response(
+                    &mut request,
+                    ResponseType::UnsupportedContentFormat,
+                    "The Content-Format of the request is not supported",
+                );
+                return request;
+            }
+        },
+        None => (
+            Bytes::from(request.message.payload.clone()),
+            content_format::JSON_MEDIA_TYPE,
+        ),
+    };
 
-    // TODO: If we start to transfer the body as cbor, convert it to JSON for the homeserver.
-    let body = Bytes::from(request.message.payload.clone());
+    // Requests naming the homeserver themselves are sent using the client of the configured
+    // homeserver, all others are routed by their Uri-Host option and the listener they arrived on.
+    let target = if forward::is_forwarded(&request.message) {
+        proxy.virtual_hosts.default_upstream()
+    } else {
+        let host = request
+            .message
+            .get_first_option(CoapOption::UriHost)
+            .map(|host| String::from_utf8_lossy(host).into_owned());
 
-    let request_builder = client
-        .request(method, url)
-        .body(body)
-        .header("Content-type", "application/json");
+        match proxy
+            .virtual_hosts
+            .route(host.as_deref(), proxy.peers.listener(source))
+        {
+            Ok(target) => target,
+            Err(status) => {
+                warn!(host, "Received a request for an unknown host");
+                proxy.metrics.record_error("unknown_host");
+                set_error_response(&mut request, status, "Unknown host");
+                return request;
+            }
+        }
+    };
 
-    let request_builder = if let Some(access_token) = access_token {
-        request_builder.header("Authorization", access_token)
+    // In forward-proxy mode the client may name the homeserver itself.
+    let target_base_url = target.homeserver.base_url();
+    let base_url =
+        match forward::base_url(&proxy.config.forward, &target_base_url, &request.message) {
+            Ok(base_url) => base_url,
+            Err(e) => {
+                warn!("Could not forward the request {e}");
+                proxy.metrics.record_error("forward");
+                set_error_response(&mut request, e.response_type(), e.diagnostic());
+                return request;
+            }
+        };
+
+    // Session handles belong to a homeserver, homeservers named by the client are identified by
+    // their origin.
+    let homeserver = if base_url == target_base_url {
+        target.homeserver.id()
     } else {
-        request_builder
+        discovery::origin(&base_url)
     };
 
-    trace!("Built the HTTP request");
+    let mut url = match uri::upstream_url(&base_url, &proxy.routes, &request.message) {
+        Ok(url) => url,
+        Err(e) => {
+            set_upstream_error_response(&proxy, &mut request, UpstreamError::InvalidUrl(e));
+            return request;
+        }
+    };
+
+    // The body of a FETCH request carries the query parameters of the equivalent GET request.
+    if *request.get_method() == Method::Fetch {
+        match method::fetch_query(&body) {
+            Ok(query_pairs) => {
+                if !query_pairs.is_empty() {
+                    url.query_pairs_mut().extend_pairs(query_pairs);
+                }
+                body = Bytes::new();
+            }
+            Err(e) => {
+                warn!("Could not convert the FETCH body into query parameters {e:#}");
+                set_error_response(&mut request, ResponseType::BadRequest, &format!("{e:#}"));
+                return request;
+            }
+        }
+    }
 
-    match request_builder.send().await {
-        Ok(response) => {
-            Span::current().record("http_status", debug(response.status()));
+    // Let's move the access token out of the query string so we can put it into a header, a
+    // token in the dedicated CoAP option takes precedence.
+    let query_access_token = uri::take_access_token(&mut url);
 
-            debug!("Successfully sent the HTTP response");
+    let access_token =
+        match auth::option_access_token(&request.message, proxy.config.access_token_option) {
+            Ok(access_token) => access_token.or(query_access_token),
+            Err(e) => {
+                warn!("Could not read the access token option {e:#}");
+                set_error_response(&mut request, ResponseType::BadOption, &format!("{e:#}"));
+                return request;
+            }
+        };
 
-            match response.bytes().await {
-                Ok(body) => {
-                    if let Some(message) = &mut request.response {
-                        trace!("Setting the CoAP response");
+    // Without an explicit access token a session handle may stand in for it, an empty session
+    // option asks for a new handle.
+    let mut session_use = SessionUse::None;
 
-                        // TODO: If we start to transfer the body as cbor, convert the JSON
-                        // response into cbor.
-                        message.set_status(coap_lite::ResponseType::Valid);
-                        message.message.payload = body.to_vec();
-                    }
+    let access_token = match (&proxy.sessions, access_token) {
+        (Some(sessions), access_token) => match sessions.request_handle(&request.message) {
+            Some([]) => {
+                session_use = SessionUse::Requested;
+                access_token
+            }
+            Some(handle) if access_token.is_none() => {
+                if let Err(retry_after) = sessions.check_lookup(source.ip()) {
+                    warn!("The client made too many failed session handle lookups");
+                    proxy.metrics.record_error("rate_limited");
+                    set_error_response(
+                        &mut request,
+                        ResponseType::TooManyRequests,
+                        "Too many unknown session handles",
+                    );
+                    set_retry_after(&mut request, retry_after);
+                    return request;
                 }
-                Err(e) => {
-                    error!("Could not send out the HTTP request {e}")
+
+                match sessions.resolve(handle, &homeserver, source.ip()) {
+                    Some(access_token) => {
+                        session_use = SessionUse::Handle;
+                        Some(access_token)
+                    }
+                    None => {
+                        warn!("Received an unknown or expired session handle");
+                        set_error_response(
+                            &mut request,
+                            ResponseType::Unauthorized,
+                            "Unknown or expired session handle",
+                        );
+                        return request;
+                    }
                 }
             }
+            _ => access_token,
+        },
+        (None, access_token) => access_token,
+    };
+
+    // A client can observe the sync endpoint instead of long-polling it, every other resource
+    // is answered as if the Observe option wasn't there.
+    let observe = request.get_observe_flag().and_then(Result::ok);
+
+    if observe == Some(ObserveOption::Deregister) {
+        proxy
+            .observations
+            .cancel(source, request.message.get_token());
+    }
+
+    // Notifications keep coming without further requests, so only sources that proved their
+    // address can observe if the amplification guard is enabled.
+    let observer = (observe == Some(ObserveOption::Register)
+        && proxy.config.observe.enabled
+        && method == reqwest::Method::GET
+        && observe::is_sync(&url)
+        && (proxy.amplification.is_none() || proxy.is_verified(source)))
+    .then(|| proxy.peers.get(source))
+    .flatten();
+
+    if let (Some(rate_limits), Some(access_token)) = (&proxy.rate_limits, &access_token) {
+        if let Err(retry_after) = rate_limits.check_access_token(access_token) {
+            warn!("The client exceeded the rate limit of its access token");
+            proxy.metrics.record_error("rate_limited");
+            set_error_response(
+                &mut request,
+                ResponseType::TooManyRequests,
+                "Too many requests",
+            );
+            set_retry_after(&mut request, retry_after);
+            return request;
         }
+    }
+
+    Span::current().record("http_url", debug(&url));
+
+    let conditions = proxy.entity_tags.conditions(&request.message);
+
+    if conditions.unsatisfiable {
+        debug!("None of the ETags in the If-Match options belongs to the current representation");
+        set_error_response(
+            &mut request,
+            ResponseType::PreconditionFailed,
+            "The representation has changed",
+        );
+        return request;
+    }
+
+    let mut headers = conditions.headers();
+
+    if !body.is_empty() {
+        let content_type =
+            HeaderValue::from_str(content_type).expect("The media types should have been checked");
+        headers.insert(CONTENT_TYPE, content_type);
+    }
+
+    // Plain GET requests can be answered from the cache, observations need a fresh response and
+    // conditional requests need to be decided by the homeserver.
+    let cache_key =
+        (method == reqwest::Method::GET && observer.is_none() && !conditions.is_conditional())
+            .then(|| cache::Key::new(&url, access_token.as_deref()));
+
+    let (upstream, freshness) = match fetch(
+        &proxy,
+        target.client.request(method.clone(), url.clone()),
+        headers,
+        body,
+        access_token.as_deref(),
+        cache_key.as_ref(),
+    )
+    .await
+    {
+        Ok(fetched) => fetched,
         Err(e) => {
-            error!("Could not send out the HTTP request {e}")
+            set_upstream_error_response(&proxy, &mut request, e);
+            return request;
+        }
+    };
+
+    let http_status = upstream.status;
+    let status = status::coap_status(&method, http_status);
+    let body = upstream.body;
+
+    let response_format = upstream
+        .content_type
+        .as_deref()
+        .and_then(|content_type| proxy.content_formats.content_format(content_type));
+
+    // Changing a resource makes the cached representations of it stale.
+    if let Some(cache) = &proxy.cache {
+        if !method.is_safe() && http_status.is_success() {
+            cache.invalidate(&url);
         }
     }
 
+    let session_handle = proxy.sessions.as_ref().and_then(|sessions| {
+        sessions.update_from_response(
+            &method,
+            &url,
+            &homeserver,
+            http_status,
+            access_token.as_deref(),
+            session_use,
+            &body,
+        )
+    });
+
+    let observe_sequence = match observer {
+        Some(responder) if http_status == reqwest::StatusCode::OK => observe::next_batch(&body)
+            .and_then(|since| {
+                proxy.observations.register(
+                    proxy.clone(),
+                    Observation {
+                        reliable: proxy.peers.is_connected(source),
+                        responder,
+                        token: request.message.get_token().to_vec(),
+                        registration: observe::registration(&request.message),
+                        url: url.clone(),
+                        client: target.client.clone(),
+                        access_token: access_token.clone(),
+                        cbor: accept == Some(ContentFormat::ApplicationCBOR),
+                        since,
+                    },
+                )
+            }),
+        _ => None,
+    };
+
+    // Only JSON bodies can be converted, everything else, like media downloads, is passed through
+    // as it is.
+    let to_cbor = accept == Some(ContentFormat::ApplicationCBOR)
+        && response_format == Some(content_format::JSON)
+        && !body.is_empty();
+
+    let body = if to_cbor {
+        match cbor::json_to_cbor(&body) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Could not convert the JSON response body into CBOR {e:#}");
+                set_error_response(&mut request, ResponseType::NotAcceptable, &format!("{e:#}"));
+                return request;
+            }
+        }
+    } else {
+        body.to_vec()
+    };
+
+    let payload_format = if to_cbor {
+        Some(content_format::CBOR)
+    } else {
+        response_format
+    };
+
+    // Clients can validate the representation they have using its ETag, or make changes
+    // conditional on it if the homeserver gave it an entity tag. An observation always gets the
+    // full representation.
+    let etag = if status == ResponseType::Valid {
+        conditions.validated(upstream.etag.as_ref())
+    } else if upstream.etag.is_some() && http_status.is_success() {
+        Some(
+            proxy
+                .entity_tags
+                .etag(upstream.etag.as_ref(), &body, payload_format),
+        )
+    } else if method == reqwest::Method::GET
+        && status == ResponseType::Content
+        && observe_sequence.is_none()
+    {
+        Some(proxy.entity_tags.etag(None, &body, payload_format))
+    } else {
+        None
+    };
+
+    let valid = status == ResponseType::Content
+        && etag.as_ref().is_some_and(|etag| {
+            request
+                .message
+                .get_option(CoapOption::ETag)
+                .is_some_and(|etags| etags.contains(etag))
+        });
+
+    if let Some(message) = &mut request.response {
+        trace!("Setting the CoAP response");
+
+        if let Some(etag) = etag {
+            message.message.add_option(CoapOption::ETag, etag);
+        }
+
+        // Without a Max-Age option clients would reuse responses for 60 seconds, a registration
+        // for an observation stays fresh until the next notification.
+        if method == reqwest::Method::GET && observe_sequence.is_none() {
+            let max_age = freshness.map_or(0, |freshness| {
+                u32::try_from(freshness.as_secs()).unwrap_or(u32::MAX)
+            });
+            message
+                .message
+                .add_option_as(CoapOption::MaxAge, OptionValueU32(max_age));
+        }
+
+        if let (Some(sessions), Some(handle)) = (&proxy.sessions, session_handle) {
+            sessions.set_response_handle(&mut message.message, handle);
+        }
+
+        if valid {
+            debug!("The client already has the current representation");
+            message.set_status(ResponseType::Valid);
+            message.message.payload.clear();
+            return request;
+        }
+
+        message.set_status(status);
+
+        if to_cbor {
+            message
+                .message
+                .set_content_format(ContentFormat::ApplicationCBOR);
+        } else if let Some(response_format) = response_format.filter(|_| !body.is_empty()) {
+            message
+                .message
+                .add_option_as(CoapOption::ContentFormat, OptionValueU16(response_format));
+        }
+
+        if let Some(sequence) = observe_sequence {
+            message.message.set_observe_value(sequence);
+        }
+
+        message.message.payload = body;
+        block::set_size2(&mut message.message, wants_size2);
+    }
+
     info!("Forwarded the request to the homeserver, replying to the client");
 
-    return request;
+    request
+}
+
+fn init_logging(format: LogFormat) {
+    let subscriber = tracing_subscriber::fmt();
+
+    match format {
+        LogFormat::Pretty => subscriber.pretty().init(),
+        LogFormat::Full => subscriber.init(),
+        LogFormat::Compact => subscriber.compact().init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt().pretty().init();
+    let config = Config::load(Args::parse()).context("Invalid configuration")?;
+
+    init_logging(config.log_format);
+
+    let peers = Arc::new(Peers::default());
+    let mut listeners: Vec<Box<dyn Listener>> = Vec::with_capacity(config.listen.len());
+
+    for address in &config.listen {
+        let listener = UdpListener::new(*address, peers.for_listener(*address))
+            .with_context(|| format!("Could not listen on {address}"))?;
+        listeners.push(Box::new(listener));
+    }
+
+    if !config.dtls.listen.is_empty() {
+        let dtls_config =
+            dtls::server_config(&config.dtls).context("Invalid DTLS configuration")?;
+
+        // The connections of all DTLS listeners count towards the same limit.
+        let connections = Arc::new(Semaphore::new(config.dtls.max_connections));
+
+        for address in &config.dtls.listen {
+            let listener = DtlsListener::new(
+                *address,
+                dtls_config.clone(),
+                peers.for_listener(*address),
+                connections.clone(),
+            )
+            .await
+            .with_context(|| format!("Could not listen on {address}"))?;
+            listeners.push(Box::new(listener));
+        }
+    }
+
+    // The connections of all TCP, TLS and WebSocket listeners count towards the same limit.
+    let connections = Arc::new(Semaphore::new(config.tcp.max_connections));
+
+    for address in &config.tcp.listen {
+        let listener = TcpListener::new(
+            *address,
+            None,
+            peers.for_listener(*address),
+            connections.clone(),
+        )
+        .await
+        .with_context(|| format!("Could not listen on {address}"))?;
+        listeners.push(Box::new(listener));
+    }
 
-    let server = Server::new_udp(LISTEN_ADDRESS).context("Could not start up the server")?;
+    if !config.tcp.tls_listen.is_empty() {
+        let acceptor =
+            tls::acceptor(&config.tcp, &[b"coap"]).context("Invalid TLS configuration")?;
+
+        for address in &config.tcp.tls_listen {
+            let listener = TcpListener::new(
+                *address,
+                Some(acceptor.clone()),
+                peers.for_listener(*address),
+                connections.clone(),
+            )
+            .await
+            .with_context(|| format!("Could not listen on {address}"))?;
+            listeners.push(Box::new(listener));
+        }
+    }
+
+    for address in &config.tcp.websocket_listen {
+        let listener = WebSocketListener::new(
+            *address,
+            None,
+            peers.for_listener(*address),
+            connections.clone(),
+        )
+        .await
+        .with_context(|| format!("Could not listen on {address}"))?;
+        listeners.push(Box::new(listener));
+    }
+
+    if !config.tcp.websocket_tls_listen.is_empty() {
+        let acceptor =
+            tls::acceptor(&config.tcp, &[b"http/1.1"]).context("Invalid TLS configuration")?;
+
+        for address in &config.tcp.websocket_tls_listen {
+            let listener = WebSocketListener::new(
+                *address,
+                Some(acceptor.clone()),
+                peers.for_listener(*address),
+                connections.clone(),
+            )
+            .await
+            .with_context(|| format!("Could not listen on {address}"))?;
+            listeners.push(Box::new(listener));
+        }
+    }
+
+    let metrics_listener = config.metrics.listen.map(metrics::bind).transpose()?;
+
+    let listeners = listeners
+        .into_iter()
+        .map(|listener| -> Box<dyn Listener> {
+            Box::new(UploadLimit::new(listener, config.max_request_body))
+        })
+        .collect();
+
+    let mut server = Server::from_listeners(listeners);
+    // Observations are handled by the proxy, the built-in observer only supports local
+    // resources.
+    server.disable_observe_handling(true).await;
+
+    let proxy = Arc::new(Proxy::new(config, peers).await?);
+    let config = &proxy.config;
+
+    // The homeserver of a server name is only known once it was discovered.
     info!(
-        listen_address = LISTEN_ADDRESS,
-        homeserver_address = HOMESEVER,
+        listen_addresses = ?config.listen,
+        dtls_listen_addresses = ?config.dtls.listen,
+        tcp_listen_addresses = ?config.tcp.listen,
+        tls_listen_addresses = ?config.tcp.tls_listen,
+        websocket_listen_addresses = ?config.tcp.websocket_listen,
+        websocket_tls_listen_addresses = ?config.tcp.websocket_tls_listen,
+        homeserver_address = %proxy.virtual_hosts.default_upstream().homeserver.base_url(),
+        server_name = ?config.server_name,
+        virtual_hosts = config.virtual_hosts.len(),
+        metrics_listen_address = ?config.metrics.listen,
         "Server up"
     );
 
+    if let Some(metrics_listener) = metrics_listener {
+        tokio::spawn(metrics::serve(metrics_listener, proxy.clone()));
+    }
+
     server
-        .run(request_handler)
+        .run(move |request| request_handler(proxy.clone(), request))
         .await
         .context("Failed to run the CoAP server")?;
 
@@ -0,0 +1,49 @@
+//! Mapping of CoAP request methods onto HTTP methods.
+//!
+//! Besides the methods CoAP shares with HTTP, RFC 8132 defines FETCH and iPATCH. Matrix has no
+//! endpoints that accept a body on a GET request, so a FETCH is forwarded as a GET where the
+//! members of the JSON body become query parameters. This lets constrained clients put large
+//! query parameters, like sync filters, into the payload instead of the URI.
+
+use anyhow::{bail, Context, Result};
+use coap_lite::RequestType as Method;
+use serde_json::Value as JsonValue;
+
+/// Get the HTTP method a CoAP request should be forwarded with.
+///
+/// Returns `None` if the method isn't supported by the proxy.
+pub fn http_method(method: &Method) -> Option<reqwest::Method> {
+    Some(match method {
+        Method::Get | Method::Fetch => reqwest::Method::GET,
+        Method::Post => reqwest::Method::POST,
+        Method::Put => reqwest::Method::PUT,
+        Method::Delete => reqwest::Method::DELETE,
+        Method::Patch | Method::IPatch => reqwest::Method::PATCH,
+        Method::UnKnown => return None,
+    })
+}
+
+/// Turn the JSON body of a FETCH request into query parameters.
+///
+/// String members are used verbatim, every other member is serialized as JSON, which is what
+/// Matrix expects for parameters like `filter`.
+pub fn fetch_query(body: &[u8]) -> Result<Vec<(String, String)>> {
+    if body.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let body: JsonValue =
+        serde_json::from_slice(body).context("The FETCH body isn't a valid JSON document")?;
+
+    let JsonValue::Object(members) = body else {
+        bail!("The FETCH body needs to be a JSON object");
+    };
+
+    Ok(members
+        .into_iter()
+        .map(|(key, value)| match value {
+            JsonValue::String(value) => (key, value),
+            value => (key, value.to_string()),
+        })
+        .collect())
+}
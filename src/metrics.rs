@@ -0,0 +1,218 @@
+//! Prometheus metrics about the traffic of the proxy, served over HTTP in the text format.
+
+use std::{convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use coap_lite::{MessageClass, MessageType, Packet};
+use hyper::{
+    header::CONTENT_TYPE,
+    server::conn::AddrIncoming,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use tracing::{error, info};
+
+use crate::proxy::Proxy;
+
+/// Buckets, in seconds, of the upstream latency histogram, long-polled sync requests can take
+/// as long as the sync timeout.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// The metrics the proxy collects.
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    /// CoAP requests by method and the code of the response.
+    requests: IntCounterVec,
+    /// Homeserver responses by HTTP status.
+    upstream_responses: IntCounterVec,
+    /// Time until the homeserver sent the whole response.
+    upstream_duration: Histogram,
+    /// Payload bytes by peer and direction.
+    payload_bytes: IntCounterVec,
+    /// Failed requests by the class of the error.
+    errors: IntCounterVec,
+    /// Clients currently observing the sync endpoint, updated when the metrics are gathered.
+    active_observations: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new();
+
+        let requests = IntCounterVec::new(
+            Opts::new("coap_proxy_requests_total", "CoAP requests handled"),
+            &["method", "code"],
+        )?;
+        let upstream_responses = IntCounterVec::new(
+            Opts::new(
+                "coap_proxy_upstream_responses_total",
+                "Responses received from the homeserver",
+            ),
+            &["status"],
+        )?;
+        let upstream_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "coap_proxy_upstream_duration_seconds",
+                "Time until the homeserver sent the whole response",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+        )?;
+        let payload_bytes = IntCounterVec::new(
+            Opts::new(
+                "coap_proxy_payload_bytes_total",
+                "Payload bytes received and sent",
+            ),
+            &["peer", "direction"],
+        )?;
+        let errors = IntCounterVec::new(
+            Opts::new("coap_proxy_errors_total", "Requests that failed"),
+            &["class"],
+        )?;
+        let active_observations = IntGauge::new(
+            "coap_proxy_active_observations",
+            "Clients observing the sync endpoint",
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(upstream_responses.clone()))?;
+        registry.register(Box::new(upstream_duration.clone()))?;
+        registry.register(Box::new(payload_bytes.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(active_observations.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            upstream_responses,
+            upstream_duration,
+            payload_bytes,
+            errors,
+            active_observations,
+        })
+    }
+
+    fn add_payload_bytes(&self, peer: &str, direction: &str, bytes: usize) {
+        self.payload_bytes
+            .with_label_values(&[peer, direction])
+            .inc_by(bytes as u64);
+    }
+
+    /// Count a request of a client and the response it got.
+    ///
+    /// Acknowledgements and resets of notifications aren't requests and are skipped.
+    pub fn record_request(&self, request: &Packet, response: Option<&Packet>) {
+        let (MessageClass::Request(method), Some(response)) = (request.header.code, response)
+        else {
+            return;
+        };
+
+        if matches!(
+            request.header.get_type(),
+            MessageType::Acknowledgement | MessageType::Reset
+        ) {
+            return;
+        }
+
+        let method = format!("{method:?}").to_ascii_uppercase();
+        let code = response.header.code.to_string();
+
+        self.requests.with_label_values(&[&method, &code]).inc();
+        self.add_payload_bytes("client", "received", request.payload.len());
+        self.add_payload_bytes("client", "sent", response.payload.len());
+    }
+
+    /// Count a notification sent to an observer.
+    pub fn record_notification(&self, notification: &Packet) {
+        self.add_payload_bytes("client", "sent", notification.payload.len());
+    }
+
+    /// Count a response of the homeserver, with the size of the request body it answered.
+    pub fn record_upstream(
+        &self,
+        status: reqwest::StatusCode,
+        duration: Duration,
+        sent: usize,
+        received: usize,
+    ) {
+        self.upstream_responses
+            .with_label_values(&[status.as_str()])
+            .inc();
+        self.upstream_duration.observe(duration.as_secs_f64());
+        self.add_payload_bytes("homeserver", "sent", sent);
+        self.add_payload_bytes("homeserver", "received", received);
+    }
+
+    /// Count a failed request.
+    pub fn record_error(&self, class: &str) {
+        self.errors.with_label_values(&[class]).inc();
+    }
+
+    /// Encode the current values of all metrics in the text format.
+    fn encode(&self, active_observations: usize) -> Result<Vec<u8>> {
+        self.active_observations
+            .set(i64::try_from(active_observations).unwrap_or(i64::MAX));
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        Ok(buffer)
+    }
+}
+
+fn respond(proxy: &Proxy, request: &Request<Body>) -> Response<Body> {
+    let status = if request.uri().path() != proxy.config.metrics.path {
+        StatusCode::NOT_FOUND
+    } else if request.method() != Method::GET && request.method() != Method::HEAD {
+        StatusCode::METHOD_NOT_ALLOWED
+    } else {
+        match proxy.metrics.encode(proxy.observations.active()) {
+            Ok(body) => {
+                return Response::builder()
+                    .header(CONTENT_TYPE, TextEncoder::new().format_type())
+                    .body(Body::from(body))
+                    .expect("The metrics response should be valid");
+            }
+            Err(e) => {
+                error!("Could not encode the metrics {e:#}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    };
+
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// Bind the HTTP listener serving the metrics.
+pub fn bind(address: SocketAddr) -> Result<hyper::server::Builder<AddrIncoming>> {
+    hyper::Server::try_bind(&address)
+        .with_context(|| format!("Could not listen for metrics requests on {address}"))
+}
+
+/// Serve the metrics until the listener fails.
+pub async fn serve(listener: hyper::server::Builder<AddrIncoming>, proxy: Arc<Proxy>) {
+    let make_service = make_service_fn(move |_| {
+        let proxy = proxy.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = respond(&proxy, &request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    let server = listener.serve(make_service);
+    info!(address = %server.local_addr(), "Serving metrics");
+
+    if let Err(e) = server.await {
+        error!("The metrics listener failed {e}");
+    }
+}
@@ -0,0 +1,382 @@
+//! Observation of the Matrix sync endpoint (RFC 7641).
+//!
+//! Instead of long-polling `/sync` through the proxy, a client can register as an observer of the
+//! sync endpoint. The proxy answers the registration with the result of the first sync request and
+//! then keeps long-polling the homeserver on behalf of the client, pushing every new sync batch as
+//! a notification. Notifications are confirmable, the observation ends when the client
+//! deregisters, rejects a notification or stops acknowledging them. Clients connected over a
+//! reliable transport don't acknowledge notifications, their observations end with the
+//! connection.
+//!
+//! Notifications that don't fit into a single message are sent block-wise to clients using an
+//! unreliable transport, the client then asks for the further blocks with the registration
+//! request (RFC 7959, section 2.6).
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use coap::server::Responder;
+use coap_lite::{ContentFormat, MessageClass, Packet, ResponseType};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use tokio::task::AbortHandle;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::{block, cbor, error::UpstreamError, proxy::Proxy, status};
+
+/// Observe sequence numbers are 24 bit wide.
+const SEQUENCE_MASK: u32 = 0xFF_FFFF;
+
+/// Transmission parameters of notifications, the defaults of RFC 7252.
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_RETRANSMIT: u32 = 4;
+
+/// Number of consecutive failed sync requests after which the observation is given up.
+const MAX_UPSTREAM_FAILURES: u32 = 3;
+
+/// Time to wait before retrying a failed sync request, multiplied by the number of failures.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Minimum time between the starts of two sync requests that returned nothing new.
+///
+/// The homeserver should hold such requests open for the sync timeout, but it might ignore the
+/// timeout or something in between might cut the request short.
+const MIN_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Check if the upstream URL points to the sync endpoint.
+pub fn is_sync(url: &Url) -> bool {
+    let path = url.path();
+
+    path.starts_with("/_matrix/client/") && path.ends_with("/sync")
+}
+
+/// The minimal subset of a sync response the observation cares about.
+#[derive(Deserialize)]
+struct SyncResponse {
+    next_batch: String,
+}
+
+/// Get the token the next sync request should continue from.
+pub fn next_batch(body: &[u8]) -> Option<String> {
+    serde_json::from_slice::<SyncResponse>(body)
+        .ok()
+        .map(|sync| sync.next_batch)
+}
+
+/// Keep the registration request, without the body of a FETCH request.
+pub fn registration(message: &Packet) -> Packet {
+    let mut registration = message.clone();
+    registration.payload.clear();
+    registration
+}
+
+/// An observation of the sync endpoint by a client.
+pub struct Observation {
+    /// Sends the notifications to the client.
+    pub responder: Arc<dyn Responder>,
+    /// Whether the client is connected over a reliable transport.
+    pub reliable: bool,
+    /// The token of the registration, notifications need to carry the same token.
+    pub token: Vec<u8>,
+    /// The registration request without its payload, further blocks of notifications are asked
+    /// for with the same request.
+    pub registration: Packet,
+    /// The URL of the sync request the client registered with.
+    pub url: Url,
+    /// The HTTP client used to talk to the homeserver of the sync endpoint.
+    pub client: reqwest::Client,
+    /// The access token the sync requests are authenticated with.
+    pub access_token: Option<String>,
+    /// Whether the client asked for CBOR bodies.
+    pub cbor: bool,
+    /// The token the next sync request should continue from.
+    pub since: String,
+}
+
+#[derive(Debug)]
+struct ActiveObservation {
+    id: u64,
+    task: AbortHandle,
+}
+
+/// The observations of the sync endpoint that are currently active.
+#[derive(Debug)]
+pub struct Observations {
+    active: Mutex<HashMap<(SocketAddr, Vec<u8>), ActiveObservation>>,
+    next_id: AtomicU64,
+    max_observations: usize,
+}
+
+impl Observations {
+    pub fn new(max_observations: usize) -> Self {
+        Self {
+            active: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            max_observations,
+        }
+    }
+
+    /// The number of observations that are currently active.
+    pub fn active(&self) -> usize {
+        self.active.lock().unwrap().len()
+    }
+
+    /// Start pushing new sync batches to the client.
+    ///
+    /// An existing observation with the same token is replaced. Returns the sequence number the
+    /// response to the registration should carry in its Observe option, or `None` if too many
+    /// observations are active.
+    pub fn register(&self, proxy: Arc<Proxy>, observation: Observation) -> Option<u32> {
+        let key = (observation.responder.address(), observation.token.clone());
+        let mut active = self.active.lock().unwrap();
+
+        if let Some(previous) = active.remove(&key) {
+            previous.task.abort();
+        } else if active.len() >= self.max_observations {
+            warn!("Too many active observations, not registering the client");
+            return None;
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let task = tokio::spawn(run(proxy, id, observation));
+
+        active.insert(
+            key,
+            ActiveObservation {
+                id,
+                task: task.abort_handle(),
+            },
+        );
+
+        info!(active_observations = active.len(), "Registered an observer");
+
+        Some(0)
+    }
+
+    /// Stop the observation the client registered with the token.
+    pub fn cancel(&self, peer: SocketAddr, token: &[u8]) {
+        if let Some(observation) = self.active.lock().unwrap().remove(&(peer, token.to_vec())) {
+            debug!("Deregistered an observer");
+            observation.task.abort();
+        }
+    }
+
+    /// Forget the observation once its task ended on its own.
+    fn remove(&self, peer: SocketAddr, token: &[u8], id: u64) {
+        let mut active = self.active.lock().unwrap();
+        let key = (peer, token.to_vec());
+
+        if active
+            .get(&key)
+            .is_some_and(|observation| observation.id == id)
+        {
+            active.remove(&key);
+        }
+    }
+}
+
+/// Send a notification, to clients using an unreliable transport as a confirmable message which
+/// carries the first block if the notification is too large for a single message.
+///
+/// Returns `false` if the client rejected the notification or never acknowledged it, or if the
+/// connection of a client using a reliable transport closed.
+async fn notify(proxy: &Proxy, observation: &Observation, mut message: Packet) -> bool {
+    let responder = &*observation.responder;
+
+    // Reliable transports take care of the delivery, there are no acknowledgements.
+    if observation.reliable {
+        if !proxy.peers.is_connected(responder.address()) {
+            return false;
+        }
+
+        let Ok(bytes) = message.to_bytes() else {
+            warn!("Could not serialize a notification");
+            return false;
+        };
+
+        responder.respond(bytes).await;
+        return true;
+    }
+
+    block::set_size2(&mut message, false);
+    let message =
+        proxy
+            .blocks
+            .slice_notification(&observation.registration, responder.address(), message);
+
+    proxy
+        .confirmations
+        .send(responder, message, ACK_TIMEOUT, MAX_RETRANSMIT)
+        .await
+}
+
+/// The outcome of a single sync request of an observation.
+enum Poll {
+    /// The homeserver returned a new sync batch.
+    Batch(Vec<u8>, String),
+    /// The sync request timed out without anything new happening.
+    Unchanged,
+    /// The homeserver rejected the sync request, the observation can't continue.
+    Failed(ResponseType, Vec<u8>),
+}
+
+async fn poll(proxy: &Proxy, observation: &Observation) -> Result<Poll, UpstreamError> {
+    let mut url = observation.url.clone();
+    let query_pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| key != "since" && key != "timeout")
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(query_pairs)
+        .append_pair("since", &observation.since)
+        .append_pair(
+            "timeout",
+            &proxy.config.observe.sync_timeout().as_millis().to_string(),
+        );
+
+    let request_builder = observation.client.get(url);
+
+    let request_builder = if let Some(access_token) = &observation.access_token {
+        request_builder.bearer_auth(access_token)
+    } else {
+        request_builder
+    };
+
+    let started = Instant::now();
+    let response = request_builder
+        .send()
+        .await
+        .map_err(UpstreamError::from_send)?;
+    let http_status = response.status();
+    let body = response.bytes().await.map_err(UpstreamError::from_body)?;
+
+    proxy
+        .metrics
+        .record_upstream(http_status, started.elapsed(), 0, body.len());
+
+    if http_status != StatusCode::OK {
+        return Ok(Poll::Failed(
+            status::coap_status(&reqwest::Method::GET, http_status),
+            body.to_vec(),
+        ));
+    }
+
+    match next_batch(&body) {
+        Some(next_batch) if next_batch == observation.since => Ok(Poll::Unchanged),
+        Some(next_batch) => Ok(Poll::Batch(body.to_vec(), next_batch)),
+        None => Ok(Poll::Failed(
+            ResponseType::BadGateway,
+            b"The homeserver returned an invalid sync response".to_vec(),
+        )),
+    }
+}
+
+/// Build a notification carrying the body, converting it to CBOR if the client asked for it.
+fn notification(
+    observation: &Observation,
+    status: ResponseType,
+    body: Vec<u8>,
+    is_batch: bool,
+) -> Packet {
+    let mut message = Packet::new();
+    message.header.code = MessageClass::Response(status);
+    message.set_token(observation.token.clone());
+
+    if observation.cbor && !body.is_empty() {
+        if let Ok(body) = cbor::json_to_cbor(&body) {
+            message.set_content_format(ContentFormat::ApplicationCBOR);
+            message.payload = body;
+            return message;
+        }
+    }
+
+    // Sync batches are JSON, errors are passed on as the homeserver sent them.
+    if is_batch {
+        message.set_content_format(ContentFormat::ApplicationJSON);
+    }
+
+    message.payload = body;
+    message
+}
+
+/// Long-poll the sync endpoint and push every new batch to the client.
+async fn run(proxy: Arc<Proxy>, id: u64, mut observation: Observation) {
+    let observations = &proxy.observations;
+    let mut sequence = 0;
+    let mut failures = 0;
+
+    loop {
+        // There might be nothing to notify for a long time, don't wait for the next notification
+        // to notice that the client disconnected.
+        if observation.reliable && !proxy.peers.is_connected(observation.responder.address()) {
+            debug!("The observer disconnected");
+            break;
+        }
+
+        let started = Instant::now();
+
+        let message = match poll(&proxy, &observation).await {
+            Ok(Poll::Batch(body, next_batch)) => {
+                failures = 0;
+                sequence = (sequence + 1) & SEQUENCE_MASK;
+
+                let mut message = notification(&observation, ResponseType::Content, body, true);
+                message.set_observe_value(sequence);
+                observation.since = next_batch;
+
+                message
+            }
+            Ok(Poll::Unchanged) => {
+                failures = 0;
+                tokio::time::sleep_until((started + MIN_POLL_INTERVAL).into()).await;
+                continue;
+            }
+            Ok(Poll::Failed(status, body)) => {
+                warn!("The homeserver rejected the sync request of an observation");
+                let message = notification(&observation, status, body, false);
+                proxy.metrics.record_notification(&message);
+                notify(&proxy, &observation, message).await;
+                break;
+            }
+            Err(e) => {
+                warn!("{e}");
+                proxy.metrics.record_error(e.class());
+                failures += 1;
+
+                if failures < MAX_UPSTREAM_FAILURES {
+                    tokio::time::sleep(RETRY_DELAY * failures).await;
+                    continue;
+                }
+
+                let message = notification(
+                    &observation,
+                    e.response_type(),
+                    e.diagnostic().as_bytes().to_vec(),
+                    false,
+                );
+                proxy.metrics.record_notification(&message);
+                notify(&proxy, &observation, message).await;
+                break;
+            }
+        };
+
+        proxy.metrics.record_notification(&message);
+        if !notify(&proxy, &observation, message).await {
+            info!("The client rejected or stopped acknowledging a notification");
+            break;
+        }
+    }
+
+    observations.remove(observation.responder.address(), &observation.token, id);
+}
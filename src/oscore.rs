@@ -0,0 +1,794 @@
+//! Object security for constrained RESTful environments (OSCORE, RFC 8613).
+//!
+//! OSCORE protects requests end-to-end between a client and the proxy without the handshakes of
+//! DTLS. A protected request carries the OSCORE option and an encrypted payload holding the real
+//! method, the options that need protection and the body. The proxy decrypts the request using
+//! the security context of the client, handles the inner request as usual and encrypts the
+//! response using the same context.
+//!
+//! Only the AES-CCM-16-64-128 algorithm and HKDF-SHA-256 key derivation are supported. The proxy
+//! never uses its own sender sequence numbers, responses reuse the nonce of the request, so
+//! observations aren't available over OSCORE.
+
+use std::{collections::HashMap, fmt, sync::Mutex};
+
+use aes::Aes128;
+use anyhow::{bail, Result};
+use ccm::{
+    aead::{Aead, KeyInit, Payload},
+    consts::{U13, U8},
+    Ccm,
+};
+use ciborium::Value;
+use coap_lite::{CoapOption, MessageClass, Packet, RequestType as Method, ResponseType};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::{config::OscoreContextConfig, hex};
+
+type AesCcm = Ccm<Aes128, U8, U13>;
+
+/// The COSE algorithm identifier of AES-CCM-16-64-128.
+const AEAD_ALGORITHM: i64 = 10;
+const KEY_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 13;
+
+/// Sender and recipient IDs can't be longer than the nonce minus six bytes.
+const MAX_ID_LENGTH: usize = NONCE_LENGTH - 6;
+
+/// Partial IVs are at most five bytes long.
+const MAX_PARTIAL_IV_LENGTH: usize = 5;
+
+/// Number of sequence numbers below the highest one received which are still accepted.
+const REPLAY_WINDOW_SIZE: u64 = 32;
+
+/// Errors that can happen while verifying a protected request.
+///
+/// Every variant maps onto the unprotected error response RFC 8613 asks for.
+#[derive(Debug)]
+pub enum OscoreError {
+    /// The OSCORE option couldn't be parsed.
+    InvalidOption,
+    /// No security context matches the key ID of the request.
+    UnknownContext,
+    /// The request was received before.
+    Replay,
+    /// The request couldn't be decrypted or the decrypted request is malformed.
+    Decryption,
+}
+
+impl OscoreError {
+    /// The CoAP response code the client should receive for this error.
+    pub fn response_type(&self) -> ResponseType {
+        match self {
+            Self::InvalidOption => ResponseType::BadOption,
+            Self::UnknownContext | Self::Replay => ResponseType::Unauthorized,
+            Self::Decryption => ResponseType::BadRequest,
+        }
+    }
+
+    /// The diagnostic payload of the error response.
+    pub fn diagnostic(&self) -> &'static str {
+        match self {
+            Self::InvalidOption => "Invalid OSCORE option",
+            Self::UnknownContext => "Security context not found",
+            Self::Replay => "Replay detected",
+            Self::Decryption => "Decryption failed",
+        }
+    }
+}
+
+impl fmt::Display for OscoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.diagnostic())
+    }
+}
+
+impl std::error::Error for OscoreError {}
+
+/// The sliding window of recently received sequence numbers of a client.
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    /// Bit `n` is set if the sequence number `highest - n` was received.
+    received: u32,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, sequence: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if sequence > highest => true,
+            Some(highest) => {
+                let offset = highest - sequence;
+                offset < REPLAY_WINDOW_SIZE && self.received & (1 << offset) == 0
+            }
+        }
+    }
+
+    fn mark_received(&mut self, sequence: u64) {
+        match self.highest {
+            Some(highest) if sequence <= highest => {
+                self.received |= 1 << (highest - sequence);
+            }
+            Some(highest) => {
+                let shift = sequence - highest;
+                self.received = if shift < REPLAY_WINDOW_SIZE {
+                    (self.received << shift) | 1
+                } else {
+                    1
+                };
+                self.highest = Some(sequence);
+            }
+            None => {
+                self.received = 1;
+                self.highest = Some(sequence);
+            }
+        }
+    }
+}
+
+/// The security context the proxy shares with a single client.
+struct SecurityContext {
+    id_context: Option<Vec<u8>>,
+    recipient_id: Vec<u8>,
+    sender_key: [u8; KEY_LENGTH],
+    recipient_key: [u8; KEY_LENGTH],
+    common_iv: [u8; NONCE_LENGTH],
+    replay_window: Mutex<ReplayWindow>,
+}
+
+impl SecurityContext {
+    fn derive(config: &OscoreContextConfig) -> Result<Self> {
+        let decode = |name: &str, value: &str| {
+            hex::decode(value)
+                .ok_or_else(|| anyhow::anyhow!("The OSCORE {name} {value} isn't valid hex"))
+        };
+
+        let master_secret = decode("master secret", &config.master_secret)?;
+        let master_salt = decode("master salt", &config.master_salt)?;
+        let sender_id = decode("sender ID", &config.sender_id)?;
+        let recipient_id = decode("recipient ID", &config.recipient_id)?;
+        let id_context = config
+            .id_context
+            .as_deref()
+            .map(|id_context| decode("ID context", id_context))
+            .transpose()?;
+
+        if sender_id.len() > MAX_ID_LENGTH || recipient_id.len() > MAX_ID_LENGTH {
+            bail!("OSCORE sender and recipient IDs can't be longer than {MAX_ID_LENGTH} bytes");
+        }
+
+        if sender_id == recipient_id {
+            bail!("The OSCORE sender and recipient IDs of a context need to be different");
+        }
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&master_salt), &master_secret);
+
+        let expand = |id: &[u8], kind: &str, output: &mut [u8]| {
+            let info = Value::Array(vec![
+                Value::Bytes(id.to_vec()),
+                id_context.clone().map_or(Value::Null, Value::Bytes),
+                Value::Integer(AEAD_ALGORITHM.into()),
+                Value::Text(kind.to_owned()),
+                Value::Integer(output.len().into()),
+            ]);
+
+            hkdf.expand(&cbor(&info), output)
+                .expect("The output length should be valid for HKDF-SHA-256");
+        };
+
+        let mut sender_key = [0; KEY_LENGTH];
+        let mut recipient_key = [0; KEY_LENGTH];
+        let mut common_iv = [0; NONCE_LENGTH];
+
+        expand(&sender_id, "Key", &mut sender_key);
+        expand(&recipient_id, "Key", &mut recipient_key);
+        expand(&[], "IV", &mut common_iv);
+
+        Ok(Self {
+            id_context,
+            recipient_id,
+            sender_key,
+            recipient_key,
+            common_iv,
+            replay_window: Mutex::default(),
+        })
+    }
+
+    /// Compute the AEAD nonce from the ID of the sender and the partial IV.
+    fn nonce(&self, id: &[u8], partial_iv: &[u8]) -> [u8; NONCE_LENGTH] {
+        let mut nonce = [0; NONCE_LENGTH];
+
+        nonce[0] = id.len() as u8;
+        nonce[1 + MAX_ID_LENGTH - id.len()..1 + MAX_ID_LENGTH].copy_from_slice(id);
+        nonce[NONCE_LENGTH - partial_iv.len()..].copy_from_slice(partial_iv);
+
+        for (byte, iv) in nonce.iter_mut().zip(self.common_iv) {
+            *byte ^= iv;
+        }
+
+        nonce
+    }
+}
+
+/// The fields of the OSCORE option of a request.
+struct OptionValue<'a> {
+    partial_iv: &'a [u8],
+    kid_context: Option<&'a [u8]>,
+    kid: Option<&'a [u8]>,
+}
+
+impl<'a> OptionValue<'a> {
+    fn parse(value: &'a [u8]) -> Result<Self, OscoreError> {
+        let Some((&flags, mut rest)) = value.split_first() else {
+            return Ok(Self {
+                partial_iv: &[],
+                kid_context: None,
+                kid: None,
+            });
+        };
+
+        // Bits five to seven are reserved, partial IV lengths six and seven too.
+        let partial_iv_length = usize::from(flags & 0x07);
+
+        if flags & 0xe0 != 0 || partial_iv_length > MAX_PARTIAL_IV_LENGTH {
+            return Err(OscoreError::InvalidOption);
+        }
+
+        if rest.len() < partial_iv_length {
+            return Err(OscoreError::InvalidOption);
+        }
+
+        let (partial_iv, remaining) = rest.split_at(partial_iv_length);
+        rest = remaining;
+
+        let kid_context = if flags & 0x10 != 0 {
+            let (&length, remaining) = rest.split_first().ok_or(OscoreError::InvalidOption)?;
+            let length = usize::from(length);
+
+            if remaining.len() < length {
+                return Err(OscoreError::InvalidOption);
+            }
+
+            let (kid_context, remaining) = remaining.split_at(length);
+            rest = remaining;
+
+            Some(kid_context)
+        } else {
+            None
+        };
+
+        let kid = if flags & 0x08 != 0 {
+            Some(rest)
+        } else if rest.is_empty() {
+            None
+        } else {
+            return Err(OscoreError::InvalidOption);
+        };
+
+        Ok(Self {
+            partial_iv,
+            kid_context,
+            kid,
+        })
+    }
+}
+
+/// Everything needed to protect the response to a request that was verified.
+pub struct ResponseProtection {
+    key: [u8; KEY_LENGTH],
+    nonce: [u8; NONCE_LENGTH],
+    aad: Vec<u8>,
+}
+
+impl ResponseProtection {
+    /// Encrypt the response, leaving only the options intermediaries need outside.
+    pub fn protect(&self, message: &mut Packet) {
+        let plaintext = inner_message(message, u8::from(message.header.code));
+
+        let ciphertext = AesCcm::new(&self.key.into())
+            .encrypt(
+                &self.nonce.into(),
+                Payload {
+                    msg: &plaintext,
+                    aad: &self.aad,
+                },
+            )
+            .expect("Encrypting a CoAP message should never fail");
+
+        let outer_options: Vec<_> = message
+            .options()
+            .filter(|(number, _)| is_outer_option(CoapOption::from(**number)))
+            .map(|(number, values)| (*number, values.clone()))
+            .collect();
+
+        message.clear_all_options();
+
+        for (number, values) in outer_options {
+            message.set_option(CoapOption::from(number), values);
+        }
+
+        message.add_option(CoapOption::Oscore, Vec::new());
+        message.header.code = MessageClass::Response(ResponseType::Changed);
+        message.payload = ciphertext;
+    }
+}
+
+/// The security contexts of all clients using OSCORE.
+pub struct Oscore {
+    contexts: HashMap<(Option<Vec<u8>>, Vec<u8>), SecurityContext>,
+}
+
+impl fmt::Debug for Oscore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Oscore")
+            .field("contexts", &self.contexts.len())
+            .finish()
+    }
+}
+
+impl Oscore {
+    /// Derive the keys of all configured security contexts.
+    pub fn new(contexts: &[OscoreContextConfig]) -> Result<Self> {
+        let mut derived = HashMap::new();
+
+        for config in contexts {
+            let context = SecurityContext::derive(config)?;
+            let key = (context.id_context.clone(), context.recipient_id.clone());
+
+            if derived.insert(key, context).is_some() {
+                bail!(
+                    "The OSCORE recipient ID {} is configured more than once",
+                    config.recipient_id
+                );
+            }
+        }
+
+        Ok(Self { contexts: derived })
+    }
+
+    /// Check if the request is protected using OSCORE.
+    pub fn is_protected(message: &Packet) -> bool {
+        message.get_option(CoapOption::Oscore).is_some()
+    }
+
+    /// Verify and decrypt a protected request, replacing it with the inner request.
+    ///
+    /// Returns what's needed to protect the response to the request.
+    pub fn unprotect(&self, message: &mut Packet) -> Result<ResponseProtection, OscoreError> {
+        let option = message
+            .get_first_option(CoapOption::Oscore)
+            .ok_or(OscoreError::InvalidOption)?
+            .clone();
+        let option = OptionValue::parse(&option)?;
+
+        // Requests need to identify the context and carry a sequence number.
+        let (Some(kid), false) = (option.kid, option.partial_iv.is_empty()) else {
+            return Err(OscoreError::InvalidOption);
+        };
+
+        let context = self
+            .contexts
+            .get(&(option.kid_context.map(<[u8]>::to_vec), kid.to_vec()))
+            .ok_or(OscoreError::UnknownContext)?;
+
+        let sequence = option
+            .partial_iv
+            .iter()
+            .fold(0, |sequence, byte| (sequence << 8) | u64::from(*byte));
+
+        let mut replay_window = context.replay_window.lock().unwrap();
+
+        if !replay_window.is_fresh(sequence) {
+            return Err(OscoreError::Replay);
+        }
+
+        let nonce = context.nonce(kid, option.partial_iv);
+        let aad = additional_data(kid, option.partial_iv);
+
+        let plaintext = AesCcm::new(&context.recipient_key.into())
+            .decrypt(
+                &nonce.into(),
+                Payload {
+                    msg: &message.payload,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| OscoreError::Decryption)?;
+
+        replay_window.mark_received(sequence);
+        drop(replay_window);
+
+        let inner = parse_inner_message(&plaintext).ok_or(OscoreError::Decryption)?;
+
+        if !matches!(inner.header.code, MessageClass::Request(method) if method != Method::UnKnown)
+        {
+            return Err(OscoreError::Decryption);
+        }
+
+        let outer_options: Vec<_> = message
+            .options()
+            .filter(|(number, _)| {
+                let option = CoapOption::from(**number);
+                is_outer_option(option) && option != CoapOption::Oscore
+            })
+            .map(|(number, values)| (*number, values.clone()))
+            .collect();
+
+        message.clear_all_options();
+
+        for (number, values) in outer_options.into_iter().chain(
+            inner
+                .options()
+                .map(|(number, values)| (*number, values.clone())),
+        ) {
+            message.set_option(CoapOption::from(number), values);
+        }
+
+        // Observations need sequence numbers of the proxy, which it doesn't keep.
+        message.clear_option(CoapOption::Observe);
+
+        message.header.code = inner.header.code;
+        message.payload = inner.payload;
+
+        Ok(ResponseProtection {
+            key: context.sender_key,
+            nonce,
+            aad,
+        })
+    }
+}
+
+/// Options that stay outside of the encrypted payload, so intermediaries can process them.
+fn is_outer_option(option: CoapOption) -> bool {
+    matches!(
+        option,
+        CoapOption::UriHost
+            | CoapOption::Observe
+            | CoapOption::UriPort
+            | CoapOption::Oscore
+            | CoapOption::ProxyUri
+            | CoapOption::ProxyScheme
+            | CoapOption::Block1
+            | CoapOption::Block2
+            | CoapOption::NoResponse
+    )
+}
+
+/// Serialize the code, the protected options and the payload of a message, the plaintext of a
+/// protected message.
+fn inner_message(message: &Packet, code: u8) -> Vec<u8> {
+    let mut inner = Packet::new();
+
+    for (number, values) in message.options() {
+        if !is_outer_option(CoapOption::from(*number)) {
+            inner.set_option(CoapOption::from(*number), values.clone());
+        }
+    }
+
+    inner.payload = message.payload.clone();
+
+    let bytes = inner
+        .to_bytes_unlimited()
+        .expect("A message without a token should always serialize");
+
+    // Replace the fixed header, the message has no token, with the code.
+    let mut plaintext = Vec::with_capacity(bytes.len() - 3);
+    plaintext.push(code);
+    plaintext.extend_from_slice(&bytes[4..]);
+
+    plaintext
+}
+
+/// Parse the plaintext of a protected message.
+fn parse_inner_message(plaintext: &[u8]) -> Option<Packet> {
+    let (&code, rest) = plaintext.split_first()?;
+
+    // Prepend a header without a token so the plaintext can be parsed as a regular message.
+    let mut bytes = vec![0x40, code, 0, 0];
+    bytes.extend_from_slice(rest);
+
+    Packet::from_bytes(&bytes).ok()
+}
+
+/// Build the additional authenticated data of a message protected with the request's key ID and
+/// partial IV.
+fn additional_data(kid: &[u8], partial_iv: &[u8]) -> Vec<u8> {
+    let external_aad = Value::Array(vec![
+        Value::Integer(1.into()),
+        Value::Array(vec![Value::Integer(AEAD_ALGORITHM.into())]),
+        Value::Bytes(kid.to_vec()),
+        Value::Bytes(partial_iv.to_vec()),
+        Value::Bytes(Vec::new()),
+    ]);
+
+    cbor(&Value::Array(vec![
+        Value::Text("Encrypt0".to_owned()),
+        Value::Bytes(Vec::new()),
+        Value::Bytes(cbor(&external_aad)),
+    ]))
+}
+
+fn cbor(value: &Value) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    ciborium::into_writer(value, &mut bytes).expect("Writing CBOR into a vector should never fail");
+
+    bytes
+}
+
+/// The test vectors of RFC 8613, appendix C, from the point of view of the server.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::echo::ECHO;
+
+    const MASTER_SECRET: &str = "0102030405060708090a0b0c0d0e0f10";
+    const MASTER_SALT: &str = "9e7ca92223786340";
+
+    fn bytes(value: &str) -> Vec<u8> {
+        hex::decode(value).unwrap()
+    }
+
+    /// C.1.2, the server context without an ID context.
+    fn context_c1() -> OscoreContextConfig {
+        OscoreContextConfig {
+            master_secret: MASTER_SECRET.to_owned(),
+            master_salt: MASTER_SALT.to_owned(),
+            sender_id: "01".to_owned(),
+            recipient_id: String::new(),
+            id_context: None,
+        }
+    }
+
+    /// C.2.2, the server context without a master salt.
+    fn context_c2() -> OscoreContextConfig {
+        OscoreContextConfig {
+            master_secret: MASTER_SECRET.to_owned(),
+            master_salt: String::new(),
+            sender_id: "01".to_owned(),
+            recipient_id: "00".to_owned(),
+            id_context: None,
+        }
+    }
+
+    /// C.3.2, the server context with an ID context.
+    fn context_c3() -> OscoreContextConfig {
+        OscoreContextConfig {
+            id_context: Some("37cbf3210017a2d3".to_owned()),
+            ..context_c1()
+        }
+    }
+
+    #[test]
+    fn derives_the_keys() {
+        let context = SecurityContext::derive(&context_c1()).unwrap();
+        assert_eq!(
+            context.sender_key.to_vec(),
+            bytes("ffb14e093c94c9cac9471648b4f98710")
+        );
+        assert_eq!(
+            context.recipient_key.to_vec(),
+            bytes("f0910ed7295e6ad4b54fc793154302ff")
+        );
+        assert_eq!(
+            context.common_iv.to_vec(),
+            bytes("4622d4dd6d944168eefb54987c")
+        );
+
+        let context = SecurityContext::derive(&context_c2()).unwrap();
+        assert_eq!(
+            context.sender_key.to_vec(),
+            bytes("e57b5635815177cd679ab4bcec9d7dda")
+        );
+        assert_eq!(
+            context.recipient_key.to_vec(),
+            bytes("321b26943253c7ffb6003b0b64d74041")
+        );
+        assert_eq!(
+            context.common_iv.to_vec(),
+            bytes("be35ae297d2dace910c52e99f9")
+        );
+
+        let context = SecurityContext::derive(&context_c3()).unwrap();
+        assert_eq!(
+            context.sender_key.to_vec(),
+            bytes("e39a0c7c77b43f03b4b39ab9a268699f")
+        );
+        assert_eq!(
+            context.recipient_key.to_vec(),
+            bytes("af2a1300a5e95788b356336eeecd2b92")
+        );
+        assert_eq!(
+            context.common_iv.to_vec(),
+            bytes("2ca58fb85ff1b81c0b7181b85e")
+        );
+    }
+
+    #[test]
+    fn computes_nonces() {
+        let context = SecurityContext::derive(&context_c1()).unwrap();
+        assert_eq!(
+            context.nonce(&[], &[]).to_vec(),
+            bytes("4622d4dd6d944168eefb54987c")
+        );
+        assert_eq!(
+            context.nonce(&[1], &[]).to_vec(),
+            bytes("4722d4dd6d944169eefb54987c")
+        );
+        assert_eq!(
+            context.nonce(&[], &[0x14]).to_vec(),
+            bytes("4622d4dd6d944168eefb549868")
+        );
+
+        let context = SecurityContext::derive(&context_c2()).unwrap();
+        assert_eq!(
+            context.nonce(&[0], &[]).to_vec(),
+            bytes("bf35ae297d2dace910c52e99f9")
+        );
+        assert_eq!(
+            context.nonce(&[1], &[]).to_vec(),
+            bytes("bf35ae297d2dace810c52e99f9")
+        );
+    }
+
+    #[test]
+    fn builds_the_additional_data() {
+        assert_eq!(
+            additional_data(&[], &[0x14]),
+            bytes("8368456e63727970743040488501810a40411440")
+        );
+        assert_eq!(
+            additional_data(&[0], &[0x14]),
+            bytes("8368456e63727970743040498501810a4100411440")
+        );
+    }
+
+    fn unprotect(context: OscoreContextConfig, request: &str) -> Result<Packet, OscoreError> {
+        let oscore = Oscore::new(&[context]).unwrap();
+        let mut message = Packet::from_bytes(&bytes(request)).unwrap();
+        oscore.unprotect(&mut message)?;
+        Ok(message)
+    }
+
+    fn assert_is_tv1(message: &Packet) {
+        assert_eq!(message.header.code, MessageClass::Request(Method::Get));
+        assert_eq!(
+            message.get_first_option(CoapOption::UriHost),
+            Some(&b"localhost".to_vec())
+        );
+        assert_eq!(
+            message.get_first_option(CoapOption::UriPath),
+            Some(&b"tv1".to_vec())
+        );
+        assert!(message.get_option(CoapOption::Oscore).is_none());
+        assert!(message.payload.is_empty());
+    }
+
+    #[test]
+    fn unprotects_requests() {
+        // C.4, C.5 and C.6.
+        let message = unprotect(
+            context_c1(),
+            "44025d1f00003974396c6f63616c686f7374620914ff612f1092f1776f1c1668b3825e",
+        )
+        .unwrap();
+        assert_is_tv1(&message);
+
+        let message = unprotect(
+            context_c2(),
+            "44025d1f00003974396c6f63616c686f737463091400ff4ed339a5a379b0b8bc731fffb0",
+        )
+        .unwrap();
+        assert_is_tv1(&message);
+
+        let message = unprotect(
+            context_c3(),
+            "44025d1f00003974396c6f63616c686f73746b19140837cbf3210017a2d3ff72cd7273fd331ac45cffbe55c3",
+        )
+        .unwrap();
+        assert_is_tv1(&message);
+    }
+
+    #[test]
+    fn protects_responses() {
+        // C.7, the response to the request of C.4.
+        let oscore = Oscore::new(&[context_c1()]).unwrap();
+        let mut request = Packet::from_bytes(&bytes(
+            "44025d1f00003974396c6f63616c686f7374620914ff612f1092f1776f1c1668b3825e",
+        ))
+        .unwrap();
+        let protection = oscore.unprotect(&mut request).unwrap();
+
+        let mut response =
+            Packet::from_bytes(&bytes("64455d1f00003974ff48656c6c6f20576f726c6421")).unwrap();
+        protection.protect(&mut response);
+
+        assert_eq!(
+            response.to_bytes().unwrap(),
+            bytes("64445d1f0000397490ffdbaad1e9a7e7b2a813d3c31524378303cdafae119106")
+        );
+    }
+
+    #[test]
+    fn rejects_replayed_and_tampered_requests() {
+        let oscore = Oscore::new(&[context_c1()]).unwrap();
+        let request =
+            bytes("44025d1f00003974396c6f63616c686f7374620914ff612f1092f1776f1c1668b3825e");
+
+        let mut message = Packet::from_bytes(&request).unwrap();
+        assert!(oscore.unprotect(&mut message).is_ok());
+
+        let mut message = Packet::from_bytes(&request).unwrap();
+        assert!(matches!(
+            oscore.unprotect(&mut message),
+            Err(OscoreError::Replay)
+        ));
+
+        let mut tampered = request.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let oscore = Oscore::new(&[context_c1()]).unwrap();
+        let mut message = Packet::from_bytes(&tampered).unwrap();
+        assert!(matches!(
+            oscore.unprotect(&mut message),
+            Err(OscoreError::Decryption)
+        ));
+
+        // The same request protected with a context the proxy doesn't know.
+        assert!(matches!(
+            unprotect(
+                context_c2(),
+                "44025d1f00003974396c6f63616c686f7374620914ff612f1092f1776f1c1668b3825e"
+            ),
+            Err(OscoreError::UnknownContext)
+        ));
+    }
+
+    /// Protect a request the way the client of C.1 does.
+    fn protect_request(partial_iv: u8, inner: &Packet) -> Packet {
+        let context = SecurityContext::derive(&context_c1()).unwrap();
+        let nonce = context.nonce(&[], &[partial_iv]);
+        let ciphertext = AesCcm::new(&context.recipient_key.into())
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: &inner_message(inner, u8::from(inner.header.code)),
+                    aad: &additional_data(&[], &[partial_iv]),
+                },
+            )
+            .unwrap();
+
+        let mut message = Packet::new();
+        message.header.code = MessageClass::Request(Method::Post);
+        message.add_option(CoapOption::Oscore, vec![0x09, partial_iv]);
+        message.payload = ciphertext;
+        message
+    }
+
+    #[test]
+    fn protects_the_echo_option() {
+        let oscore = Oscore::new(&[context_c1()]).unwrap();
+
+        let mut inner = Packet::new();
+        inner.header.code = MessageClass::Request(Method::Get);
+        inner.add_option(CoapOption::UriPath, b"tv1".to_vec());
+        inner.add_option(ECHO, vec![1, 2, 3]);
+
+        let mut request = protect_request(0x20, &inner);
+        assert!(request.get_option(ECHO).is_none());
+
+        let protection = oscore.unprotect(&mut request).unwrap();
+        assert_eq!(request.get_first_option(ECHO), Some(&vec![1, 2, 3]));
+
+        let mut response = Packet::new();
+        response.header.code = MessageClass::Response(ResponseType::Unauthorized);
+        response.add_option(ECHO, vec![4, 5, 6]);
+        protection.protect(&mut response);
+
+        assert!(response.get_option(ECHO).is_none());
+        assert_eq!(
+            response.header.code,
+            MessageClass::Response(ResponseType::Changed)
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    amplification::AmplificationGuard, block::ResponseBlocks, cache::Cache,
+    conditional::EntityTags, config::Config, confirmable::Confirmations,
+    content_format::ContentFormats, echo::EchoVerifier, metrics::Metrics, observe::Observations,
+    oscore::Oscore, rate_limit::RateLimits, routes::RouteTable, separate::SeparateResponses,
+    session::SessionStore, transport::Peers, virtual_host::VirtualHosts,
+};
+
+/// State shared between all requests the proxy handles.
+#[derive(Debug)]
+pub struct Proxy {
+    /// The configuration the proxy was started with.
+    pub config: Config,
+    /// The homeservers requests can be routed to, with the HTTP clients used to talk to them.
+    pub virtual_hosts: VirtualHosts,
+    /// The session handles handed out to clients, `None` if sessions are disabled.
+    pub sessions: Option<SessionStore>,
+    /// The cached homeserver responses, `None` if caching is disabled.
+    pub cache: Option<Cache>,
+    /// The entity tags of the homeserver behind the ETags handed out to clients.
+    pub entity_tags: EntityTags,
+    /// The short route codes clients can use instead of full Matrix paths.
+    pub routes: RouteTable,
+    /// The Content-Formats the proxy can translate into media types and back.
+    pub content_formats: ContentFormats,
+    /// The peers the listeners recently received messages from.
+    pub peers: Arc<Peers>,
+    /// The clients observing the sync endpoint.
+    pub observations: Observations,
+    /// The confirmable messages sent by the proxy that weren't acknowledged yet.
+    pub confirmations: Confirmations,
+    /// The security contexts of clients using OSCORE.
+    pub oscore: Oscore,
+    /// The metrics about the traffic of the proxy.
+    pub metrics: Metrics,
+    /// The request rate limits of clients, `None` if rate limiting is disabled.
+    pub rate_limits: Option<RateLimits>,
+    /// Limits the responses to unverified sources, `None` if that's disabled.
+    pub amplification: Option<AmplificationGuard>,
+    /// Verifies the address of clients using the Echo option, `None` if that's disabled.
+    pub echo: Option<EchoVerifier>,
+    /// Acknowledges slow requests and sends their responses separately, `None` if that's
+    /// disabled.
+    pub separate: Option<SeparateResponses>,
+    /// The separate responses and notifications whose further blocks clients can still ask for.
+    pub blocks: ResponseBlocks,
+}
+
+impl Proxy {
+    /// Create the proxy state and the HTTP clients for the given configuration.
+    pub async fn new(config: Config, peers: Arc<Peers>) -> Result<Self> {
+        let virtual_hosts = VirtualHosts::new(&config).await?;
+
+        let sessions = config
+            .sessions
+            .enabled
+            .then(|| {
+                SessionStore::new(
+                    &config.sessions,
+                    &virtual_hosts.default_upstream().homeserver.id(),
+                )
+            })
+            .transpose()?;
+
+        let cache = config.cache.enabled.then(|| Cache::new(&config.cache));
+
+        let routes = RouteTable::new(&config.routes).context("Invalid route table")?;
+
+        let content_formats =
+            ContentFormats::new(&config.content_formats).context("Invalid Content-Format table")?;
+
+        let observations = Observations::new(config.observe.max_observations);
+
+        let oscore =
+            Oscore::new(&config.oscore.contexts).context("Invalid OSCORE configuration")?;
+
+        let rate_limits = config
+            .rate_limit
+            .enabled
+            .then(|| RateLimits::new(&config.rate_limit));
+
+        let amplification = config
+            .amplification
+            .enabled
+            .then(|| AmplificationGuard::new(&config.amplification));
+
+        let echo = config.echo.enabled.then(|| EchoVerifier::new(&config.echo));
+
+        let separate = config
+            .separate
+            .enabled
+            .then(|| SeparateResponses::new(&config.separate));
+
+        let metrics = Metrics::new().context("Could not register the metrics")?;
+
+        Ok(Self {
+            config,
+            virtual_hosts,
+            sessions,
+            cache,
+            entity_tags: EntityTags::default(),
+            routes,
+            content_formats,
+            peers,
+            observations,
+            confirmations: Confirmations::default(),
+            oscore,
+            metrics,
+            rate_limits,
+            amplification,
+            echo,
+            separate,
+            blocks: ResponseBlocks::default(),
+        })
+    }
+
+    /// Check whether the peer proved that it receives what the proxy sends to its address,
+    /// either through its transport or using the Echo option.
+    pub fn is_verified(&self, address: SocketAddr) -> bool {
+        self.peers.is_verified(address)
+            || self
+                .echo
+                .as_ref()
+                .is_some_and(|echo| echo.is_verified(address))
+    }
+}
@@ -0,0 +1,194 @@
+//! Rate limiting of clients by their source address and their access token.
+//!
+//! Every source address and every access token gets a token bucket. A request takes one token
+//! out of its bucket, the buckets refill at a constant rate up to the burst size. The same buckets
+//! limit how often a client can guess session handles.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::RateLimitConfig;
+
+/// Buckets aren't pruned more often than this.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct BucketTable<K> {
+    buckets: HashMap<K, Bucket>,
+    last_pruned: Instant,
+}
+
+/// Token buckets by key.
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    table: Mutex<BucketTable<K>>,
+    /// Tokens added per second.
+    rate: f64,
+    /// Maximum number of tokens in a bucket.
+    burst: f64,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            table: Mutex::new(BucketTable {
+                buckets: HashMap::new(),
+                last_pruned: Instant::now(),
+            }),
+            rate: f64::from(rate),
+            burst: f64::from(burst),
+        }
+    }
+
+    /// Get the time until the bucket of the key has a token again, without taking one out.
+    ///
+    /// Returns `None` if a token is available.
+    pub fn retry_after(&self, key: &K) -> Option<Duration> {
+        let table = self.table.lock().unwrap();
+        let bucket = table.buckets.get(key)?;
+
+        let tokens = (bucket.tokens
+            + Instant::now().duration_since(bucket.updated).as_secs_f64() * self.rate)
+            .min(self.burst);
+
+        (tokens < 1.0).then(|| Duration::from_secs_f64((1.0 - tokens) / self.rate))
+    }
+
+    /// Take a token out of the bucket of the key.
+    ///
+    /// Returns the time until the next token is available if the bucket is empty.
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        let mut table = self.table.lock().unwrap();
+        let now = Instant::now();
+
+        // Full buckets behave the same as missing ones, so they can be forgotten.
+        if now.duration_since(table.last_pruned) > PRUNE_INTERVAL {
+            table.buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate
+                    < self.burst
+            });
+            table.last_pruned = now;
+        }
+
+        let bucket = table.buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.updated).as_secs_f64() * self.rate)
+            .min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate));
+        }
+
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// The request rate limits of source addresses and access tokens.
+#[derive(Debug)]
+pub struct RateLimits {
+    addresses: RateLimiter<IpAddr>,
+    access_tokens: RateLimiter<String>,
+}
+
+impl RateLimits {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            addresses: RateLimiter::new(config.address_rate, config.address_burst),
+            access_tokens: RateLimiter::new(config.access_token_rate, config.access_token_burst),
+        }
+    }
+
+    /// Count a request from the address, all ports of an address share their limit.
+    ///
+    /// Returns the time the client should wait before sending the next request if the limit is
+    /// exceeded.
+    pub fn check_address(&self, address: IpAddr) -> Result<(), Duration> {
+        self.addresses.check(address)
+    }
+
+    /// Count a request using the access token, no matter which address it came from.
+    pub fn check_access_token(&self, access_token: &str) -> Result<(), Duration> {
+        self.access_tokens.check(access_token.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// Pretend the bucket of the key was last updated longer ago.
+    fn age(limiter: &RateLimiter<IpAddr>, key: IpAddr, by: Duration) {
+        let mut table = limiter.table.lock().unwrap();
+        let bucket = table.buckets.get_mut(&key).unwrap();
+        bucket.updated -= by;
+    }
+
+    #[test]
+    fn refills_buckets_up_to_the_burst() {
+        let limiter = RateLimiter::new(10, 3);
+        let address = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        for _ in 0..3 {
+            assert!(limiter.check(address).is_ok());
+        }
+
+        let retry_after = limiter.check(address).unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_millis(100));
+        assert!(limiter.retry_after(&address).is_some());
+
+        // A quarter of a second adds two tokens.
+        age(&limiter, address, Duration::from_millis(250));
+        assert_eq!(limiter.retry_after(&address), None);
+        assert!(limiter.check(address).is_ok());
+        assert!(limiter.check(address).is_ok());
+        assert!(limiter.check(address).is_err());
+
+        // A bucket never holds more than the burst.
+        age(&limiter, address, Duration::from_secs(60));
+        for _ in 0..3 {
+            assert!(limiter.check(address).is_ok());
+        }
+        assert!(limiter.check(address).is_err());
+    }
+
+    #[test]
+    fn limits_addresses_and_access_tokens_separately() {
+        let rate_limits = RateLimits::new(&RateLimitConfig {
+            address_rate: 1,
+            address_burst: 1,
+            access_token_rate: 1,
+            access_token_burst: 1,
+            ..RateLimitConfig::default()
+        });
+        let address = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        assert!(rate_limits.check_address(address).is_ok());
+        assert!(rate_limits.check_address(address).is_err());
+        assert!(rate_limits
+            .check_address(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)))
+            .is_ok());
+
+        assert!(rate_limits.check_access_token("alice").is_ok());
+        assert!(rate_limits.check_access_token("alice").is_err());
+        assert!(rate_limits.check_access_token("bob").is_ok());
+    }
+}
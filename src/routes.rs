@@ -0,0 +1,187 @@
+//! Short paths for Matrix client-server endpoints, in the style of MSC3079.
+//!
+//! Full Matrix paths like `_matrix/client/v3/rooms/{roomId}/send/{eventType}/{txnId}` dominate
+//! the size of requests on constrained links. Clients can instead use a short route code as the
+//! first path segment, followed by the parameters of the endpoint as the remaining segments, e.g.
+//! `9/!room:example.org/m.room.message/1`.
+//!
+//! The table is published at [`ROUTE_TABLE_PATH`] so clients can use the same codes.
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+
+/// The CoAP path the route table is published at.
+pub const ROUTE_TABLE_PATH: &str = ".well-known/matrix/coap-routes";
+
+/// The built-in route codes and the endpoint templates they expand to.
+const DEFAULT_ROUTES: &[(&str, &str)] = &[
+    ("0", "_matrix/client/versions"),
+    ("1", "_matrix/client/v3/login"),
+    ("2", "_matrix/client/v3/capabilities"),
+    ("3", "_matrix/client/v3/logout"),
+    ("4", "_matrix/client/v3/register"),
+    ("5", "_matrix/client/v3/user/{userId}/filter"),
+    ("6", "_matrix/client/v3/user/{userId}/filter/{filterId}"),
+    ("7", "_matrix/client/v3/sync"),
+    (
+        "8",
+        "_matrix/client/v3/rooms/{roomId}/state/{eventType}/{stateKey}",
+    ),
+    (
+        "9",
+        "_matrix/client/v3/rooms/{roomId}/send/{eventType}/{txnId}",
+    ),
+    ("A", "_matrix/client/v3/rooms/{roomId}/event/{eventId}"),
+    ("B", "_matrix/client/v3/rooms/{roomId}/joined_members"),
+    ("C", "_matrix/client/v3/rooms/{roomId}/messages"),
+    (
+        "D",
+        "_matrix/client/v3/rooms/{roomId}/receipt/{receiptType}/{eventId}",
+    ),
+    ("E", "_matrix/client/v3/rooms/{roomId}/typing/{userId}"),
+    ("F", "_matrix/client/v3/rooms/{roomId}/read_markers"),
+    ("G", "_matrix/client/v3/join/{roomIdOrAlias}"),
+    ("H", "_matrix/client/v3/rooms/{roomId}/leave"),
+    ("I", "_matrix/client/v3/rooms/{roomId}/invite"),
+    ("J", "_matrix/client/v3/createRoom"),
+    ("K", "_matrix/client/v3/profile/{userId}"),
+    ("L", "_matrix/client/v3/presence/{userId}/status"),
+    ("M", "_matrix/client/v3/keys/upload"),
+    ("N", "_matrix/client/v3/keys/query"),
+    ("O", "_matrix/client/v3/keys/claim"),
+    ("P", "_matrix/client/v3/sendToDevice/{eventType}/{txnId}"),
+    ("Q", "_matrix/client/v3/joined_rooms"),
+    ("R", "_matrix/client/v3/account/whoami"),
+];
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Parameter,
+}
+
+/// An endpoint template, the path of a Matrix endpoint with `{name}` placeholders for the
+/// parameters.
+#[derive(Debug)]
+struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    fn parse(template: &str) -> Result<Self> {
+        let segments = template
+            .trim_start_matches('/')
+            .split('/')
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                    if name.is_empty() || name.contains(['{', '}']) {
+                        bail!("The template {template} contains an invalid parameter {segment}");
+                    }
+
+                    Ok(Segment::Parameter)
+                } else if segment.contains(['{', '}']) {
+                    bail!("The template {template} contains an invalid segment {segment}");
+                } else {
+                    Ok(Segment::Literal(segment.to_owned()))
+                }
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            source: template.trim_start_matches('/').to_owned(),
+            segments,
+        })
+    }
+
+    fn parameter_count(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|segment| matches!(segment, Segment::Parameter))
+            .count()
+    }
+}
+
+/// The table mapping route codes onto Matrix endpoint templates.
+#[derive(Debug)]
+pub struct RouteTable {
+    routes: BTreeMap<String, Template>,
+}
+
+impl RouteTable {
+    /// Create the route table from the built-in routes and the configured additions.
+    ///
+    /// Configured routes replace built-in routes with the same code.
+    pub fn new(extra_routes: &BTreeMap<String, String>) -> Result<Self> {
+        let mut routes = BTreeMap::new();
+
+        let all_routes = DEFAULT_ROUTES
+            .iter()
+            .map(|(code, template)| (*code, *template))
+            .chain(
+                extra_routes
+                    .iter()
+                    .map(|(code, template)| (code.as_str(), template.as_str())),
+            );
+
+        for (code, template) in all_routes {
+            if code.is_empty() || code.contains('/') {
+                bail!("The route code {code:?} needs to be a single, non-empty path segment");
+            }
+
+            routes.insert(code.to_owned(), Template::parse(template)?);
+        }
+
+        Ok(Self { routes })
+    }
+
+    /// Expand the path segments of a request if the first segment is a route code.
+    ///
+    /// Returns `None` if the path doesn't start with a route code, in which case it should be
+    /// used verbatim.
+    pub fn expand(&self, segments: &[&str]) -> Result<Option<Vec<String>>> {
+        let Some((code, parameters)) = segments.split_first() else {
+            return Ok(None);
+        };
+
+        let Some(template) = self.routes.get(*code) else {
+            return Ok(None);
+        };
+
+        if parameters.len() != template.parameter_count() {
+            bail!(
+                "The route {code} expects {} parameters, but {} were given",
+                template.parameter_count(),
+                parameters.len()
+            );
+        }
+
+        let mut parameters = parameters.iter();
+
+        Ok(Some(
+            template
+                .segments
+                .iter()
+                .map(|segment| match segment {
+                    Segment::Literal(literal) => literal.clone(),
+                    Segment::Parameter => parameters
+                        .next()
+                        .expect("The number of parameters was checked")
+                        .to_string(),
+                })
+                .collect(),
+        ))
+    }
+
+    /// Serialize the route table as a JSON object mapping route codes to templates.
+    pub fn to_json(&self) -> Vec<u8> {
+        let routes: BTreeMap<&str, &str> = self
+            .routes
+            .iter()
+            .map(|(code, template)| (code.as_str(), template.source.as_str()))
+            .collect();
+
+        serde_json::to_vec(&routes).expect("A map of strings should always serialize")
+    }
+}
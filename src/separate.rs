@@ -0,0 +1,186 @@
+//! Separate responses to confirmable requests the homeserver is slow to answer (RFC 7252,
+//! section 5.2.2).
+//!
+//! A client retransmits a confirmable request as long as it isn't acknowledged, which would make
+//! the proxy send the request to the homeserver once more for every retransmission. If the
+//! response isn't ready after a short delay the proxy acknowledges the request with an empty ACK
+//! instead, and sends the response later as a confirmable message of its own. Retransmissions of
+//! a request that is still being handled are acknowledged again instead of being handled twice.
+//!
+//! The block handler of the CoAP server never sees separate responses, so large ones are split
+//! into blocks by the proxy and the further blocks are served from its own cache.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex, time::Duration};
+
+use coap::server::Responder;
+use coap_lite::{CoapRequest, MessageClass, MessageType, Packet};
+use tracing::{debug, warn};
+
+use crate::{block::ResponseBlocks, config::SeparateConfig, confirmable::Confirmations};
+
+/// Handles the confirmable requests that might need a separate response.
+pub struct SeparateResponses {
+    ack_delay: Duration,
+    ack_timeout: Duration,
+    max_retransmit: u32,
+    /// The requests being handled by peer and message ID, and whether they were acknowledged.
+    exchanges: Mutex<HashMap<(SocketAddr, u16), bool>>,
+}
+
+impl std::fmt::Debug for SeparateResponses {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SeparateResponses")
+            .field("ack_delay", &self.ack_delay)
+            .field("exchanges", &self.exchanges.lock().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SeparateResponses {
+    pub fn new(config: &SeparateConfig) -> Self {
+        Self {
+            ack_delay: config.ack_delay(),
+            ack_timeout: config.ack_timeout(),
+            max_retransmit: config.max_retransmit,
+            exchanges: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Time after which a request without a response is acknowledged.
+    pub fn ack_delay(&self) -> Duration {
+        self.ack_delay
+    }
+
+    /// Start handling a request with the message ID from the peer.
+    ///
+    /// Returns `false` if the request is a retransmission of one that is still being handled.
+    pub fn begin(&self, exchange: (SocketAddr, u16)) -> bool {
+        let mut exchanges = self.exchanges.lock().unwrap();
+
+        if exchanges.contains_key(&exchange) {
+            return false;
+        }
+
+        exchanges.insert(exchange, false);
+        true
+    }
+
+    /// Acknowledge the request with an empty ACK, its response then needs to be sent separately.
+    pub async fn acknowledge(&self, exchange: (SocketAddr, u16), responder: &dyn Responder) {
+        if let Some(acknowledged) = self.exchanges.lock().unwrap().get_mut(&exchange) {
+            *acknowledged = true;
+        }
+
+        let mut message = Packet::new();
+        message.header.set_type(MessageType::Acknowledgement);
+        message.header.code = MessageClass::Empty;
+        message.header.message_id = exchange.1;
+
+        match message.to_bytes() {
+            Ok(bytes) => responder.respond(bytes).await,
+            Err(e) => warn!("Could not serialize an empty acknowledgement {e}"),
+        }
+    }
+
+    /// Check whether the request was acknowledged, so its response needs to be sent separately.
+    pub fn is_acknowledged(&self, exchange: (SocketAddr, u16)) -> bool {
+        self.exchanges
+            .lock()
+            .unwrap()
+            .get(&exchange)
+            .is_some_and(|acknowledged| *acknowledged)
+    }
+
+    /// Stop treating retransmissions of the request as duplicates.
+    pub fn finish(&self, exchange: (SocketAddr, u16)) {
+        self.exchanges.lock().unwrap().remove(&exchange);
+    }
+
+    /// Send the response of the request as a confirmable message, retransmitting it until the
+    /// client acknowledges it.
+    ///
+    /// Responses that are too large for a single message are sent block-wise.
+    pub async fn deliver(
+        &self,
+        confirmations: &Confirmations,
+        blocks: &ResponseBlocks,
+        responder: &dyn Responder,
+        request: &mut CoapRequest<SocketAddr>,
+    ) {
+        blocks.slice(request);
+
+        let Some(response) = request.response.take() else {
+            return;
+        };
+
+        if confirmations
+            .send(
+                responder,
+                response.message,
+                self.ack_timeout,
+                self.max_retransmit,
+            )
+            .await
+        {
+            debug!("The client acknowledged the separate response");
+        } else {
+            warn!("The client rejected or never acknowledged the separate response");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use super::*;
+
+    /// Records the messages sent to the client.
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Vec<u8>>>);
+
+    #[async_trait]
+    impl Responder for Recorder {
+        async fn respond(&self, response: Vec<u8>) {
+            self.0.lock().unwrap().push(response);
+        }
+
+        fn address(&self) -> SocketAddr {
+            SocketAddr::from(([192, 0, 2, 1], 5683))
+        }
+    }
+
+    #[tokio::test]
+    async fn acknowledges_retransmissions_of_requests_being_handled() {
+        let separate = SeparateResponses::new(&SeparateConfig::default());
+        let responder = Recorder::default();
+        let exchange = (responder.address(), 7);
+
+        assert!(separate.begin(exchange));
+        assert!(!separate.is_acknowledged(exchange));
+
+        // The homeserver is slow, so the request gets an empty ACK.
+        separate.acknowledge(exchange, &responder).await;
+        assert!(separate.is_acknowledged(exchange));
+
+        // A retransmission that crossed the ACK isn't handled a second time.
+        assert!(!separate.begin(exchange));
+        assert!(separate.is_acknowledged(exchange));
+
+        // Other message IDs and other peers are separate exchanges.
+        assert!(separate.begin((exchange.0, 8)));
+        assert!(separate.begin((SocketAddr::from(([192, 0, 2, 2], 5683)), 7)));
+
+        let messages = responder.0.lock().unwrap().clone();
+        assert_eq!(messages.len(), 1);
+        let ack = Packet::from_bytes(&messages[0]).unwrap();
+        assert_eq!(ack.header.get_type(), MessageType::Acknowledgement);
+        assert_eq!(ack.header.code, MessageClass::Empty);
+        assert_eq!(ack.header.message_id, 7);
+
+        // Once the response was sent, the message ID starts a new exchange.
+        separate.finish(exchange);
+        assert!(!separate.is_acknowledged(exchange));
+        assert!(separate.begin(exchange));
+    }
+}
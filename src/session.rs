@@ -0,0 +1,596 @@
+//! Short session handles that stand in for Matrix access tokens.
+//!
+//! Access tokens are long opaque strings which would need to be sent with every request. A client
+//! asks for a session handle by sending an empty session option along with a request using an
+//! access token, or with a login request, and gets a handle of a few bytes in the session option
+//! of the response. The client can then put the handle into the session option of its requests
+//! instead of sending the access token, the proxy substitutes the real access token when it builds
+//! the upstream request.
+//!
+//! Handles are bearer secrets, every source address can only make a few failed lookups before it
+//! has to slow down, so they can't be guessed.
+//!
+//! A handle is only valid for requests to the homeserver the access token belongs to, which
+//! matters in forward-proxy mode. Homeservers configured by their server name are identified by
+//! it, so handles keep working when the homeserver moves to another base URL.
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use coap_lite::{CoapOption, Packet};
+use rand::RngCore;
+use reqwest::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error};
+use url::Url;
+
+use crate::{config::SessionConfig, hex, rate_limit::RateLimiter};
+
+/// Number of failed lookups of session handles a source address can make in a row.
+const FAILED_LOOKUP_BURST: u32 = 10;
+
+/// Number of failed lookups per second a source address can make once it used up its burst.
+const FAILED_LOOKUP_RATE: u32 = 1;
+
+/// How a request uses the session option.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionUse {
+    /// The request doesn't carry the session option.
+    None,
+    /// The request carries an empty session option, the client asks for a session handle.
+    Requested,
+    /// The request carries a session handle instead of the access token.
+    Handle,
+}
+
+/// A session handle and the access token it stands in for.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Session {
+    access_token: String,
+    /// The homeserver the access token belongs to, its server name if it's discovered, otherwise
+    /// the origin of its base URL. Sessions persisted before this was recorded belong to the
+    /// configured homeserver.
+    #[serde(default)]
+    homeserver: Option<String>,
+    /// The user the access token belongs to, only known if the session was created at login.
+    user_id: Option<String>,
+    /// Unix timestamp, in seconds, after which the handle isn't valid anymore.
+    expires_at: u64,
+}
+
+#[derive(Debug, Default)]
+struct Sessions {
+    by_handle: HashMap<Vec<u8>, Session>,
+    by_access_token: HashMap<String, Vec<u8>>,
+    /// Incremented whenever the sessions are persisted.
+    version: u64,
+}
+
+impl Sessions {
+    fn insert(&mut self, handle: Vec<u8>, session: Session) {
+        self.by_access_token
+            .insert(session.access_token.clone(), handle.clone());
+        self.by_handle.insert(handle, session);
+    }
+
+    fn remove(&mut self, handle: &[u8]) -> Option<Session> {
+        let session = self.by_handle.remove(handle)?;
+        self.by_access_token.remove(&session.access_token);
+
+        Some(session)
+    }
+
+    fn remove_expired(&mut self, now: u64) {
+        self.by_handle.retain(|_, session| session.expires_at > now);
+        self.by_access_token
+            .retain(|_, handle| self.by_handle.contains_key(handle));
+    }
+
+    /// Generate a random handle which isn't in use yet.
+    ///
+    /// The handle grows beyond the configured length if too many handles of that length are
+    /// already taken.
+    fn new_handle(&self, mut length: usize) -> Vec<u8> {
+        const ATTEMPTS_PER_LENGTH: usize = 16;
+
+        loop {
+            let mut handle = vec![0; length];
+
+            for _ in 0..ATTEMPTS_PER_LENGTH {
+                rand::thread_rng().fill_bytes(&mut handle);
+
+                if !self.by_handle.contains_key(&handle) {
+                    return handle;
+                }
+            }
+
+            length += 1;
+        }
+    }
+}
+
+/// The table of session handles the proxy has handed out.
+#[derive(Debug)]
+pub struct SessionStore {
+    sessions: Mutex<Sessions>,
+    option: u16,
+    lifetime: Duration,
+    handle_length: usize,
+    path: Option<PathBuf>,
+    /// The version of the sessions that was last written to disk.
+    written: Arc<Mutex<u64>>,
+    /// Failed lookups of session handles by source address.
+    failed_lookups: RateLimiter<IpAddr>,
+}
+
+/// The minimal subset of a login response the session store cares about.
+#[derive(Deserialize)]
+struct LoginResponse {
+    access_token: String,
+    user_id: Option<String>,
+}
+
+impl SessionStore {
+    /// Create the session store, loading the persisted sessions if a path is configured.
+    pub fn new(config: &SessionConfig, homeserver: &str) -> Result<Self> {
+        let mut sessions = Sessions::default();
+
+        if let Some(path) = &config.path {
+            if path.exists() {
+                for (handle, mut session) in Self::load(path)? {
+                    session
+                        .homeserver
+                        .get_or_insert_with(|| homeserver.to_owned());
+                    sessions.insert(handle, session);
+                }
+            }
+        }
+
+        sessions.remove_expired(unix_time());
+
+        Ok(Self {
+            sessions: Mutex::new(sessions),
+            option: config.option,
+            lifetime: config.lifetime(),
+            handle_length: config.handle_length,
+            path: config.path.clone(),
+            written: Arc::default(),
+            failed_lookups: RateLimiter::new(FAILED_LOOKUP_RATE, FAILED_LOOKUP_BURST),
+        })
+    }
+
+    fn load(path: &Path) -> Result<Vec<(Vec<u8>, Session)>> {
+        let content = fs::read(path)
+            .with_context(|| format!("Could not read the session store {}", path.display()))?;
+        let sessions: HashMap<String, Session> = serde_json::from_slice(&content)
+            .with_context(|| format!("Could not parse the session store {}", path.display()))?;
+
+        sessions
+            .into_iter()
+            .map(|(handle, session)| Ok((decode_handle(&handle)?, session)))
+            .collect()
+    }
+
+    /// Write the sessions to disk in the background, if a path is configured.
+    ///
+    /// The sessions are copied while the table is locked, the file is written on the blocking
+    /// thread pool so the lock isn't held while waiting for the disk.
+    fn persist(&self, sessions: &mut Sessions) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        sessions.version += 1;
+        let version = sessions.version;
+
+        let snapshot: HashMap<String, Session> = sessions
+            .by_handle
+            .iter()
+            .map(|(handle, session)| (hex::encode(handle), session.clone()))
+            .collect();
+
+        let path = path.clone();
+        let written = self.written.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut written = written.lock().unwrap();
+
+            // Writes can finish out of order, a newer snapshot may already be on disk.
+            if *written >= version {
+                return;
+            }
+
+            match write_sessions(&path, &snapshot) {
+                Ok(()) => *written = version,
+                Err(e) => error!("{e:#}"),
+            }
+        });
+    }
+
+    /// Get the session handle carried in the session option of the request, if there is one.
+    ///
+    /// An empty handle asks for a new session handle.
+    pub fn request_handle<'a>(&self, message: &'a Packet) -> Option<&'a [u8]> {
+        message
+            .get_first_option(CoapOption::Unknown(self.option))
+            .map(Vec::as_slice)
+    }
+
+    /// Check if the address may look up a session handle.
+    ///
+    /// Returns the time the client should wait before trying again if the address made too many
+    /// failed lookups recently.
+    pub fn check_lookup(&self, address: IpAddr) -> Result<(), Duration> {
+        self.failed_lookups
+            .retry_after(&address)
+            .map_or(Ok(()), Err)
+    }
+
+    /// Attach the session handle to the response.
+    pub fn set_response_handle(&self, message: &mut Packet, handle: Vec<u8>) {
+        message.clear_option(CoapOption::Unknown(self.option));
+        message.add_option(CoapOption::Unknown(self.option), handle);
+    }
+
+    /// Get the access token the session handle stands in for when sending a request to the
+    /// homeserver.
+    ///
+    /// Returns `None` if the handle is unknown, has expired or belongs to another homeserver, the
+    /// failed lookup then counts against the address.
+    pub fn resolve(&self, handle: &[u8], homeserver: &str, address: IpAddr) -> Option<String> {
+        let access_token = self.lookup(handle, homeserver);
+
+        if access_token.is_none() {
+            let _ = self.failed_lookups.check(address);
+        }
+
+        access_token
+    }
+
+    fn lookup(&self, handle: &[u8], homeserver: &str) -> Option<String> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        let session = sessions.by_handle.get(handle)?;
+
+        if session.homeserver.as_deref() != Some(homeserver) {
+            debug!("The session handle belongs to another homeserver");
+            return None;
+        }
+
+        if session.expires_at > unix_time() {
+            Some(session.access_token.clone())
+        } else {
+            debug!("The session handle has expired");
+            sessions.remove(handle);
+            self.persist(&mut sessions);
+
+            None
+        }
+    }
+
+    /// Get the session handle for the access token of a homeserver, creating a new session if
+    /// needed.
+    pub fn handle_for(
+        &self,
+        access_token: &str,
+        user_id: Option<&str>,
+        homeserver: &str,
+    ) -> Vec<u8> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = unix_time();
+
+        if let Some(handle) = sessions.by_access_token.get(access_token).cloned() {
+            let session = sessions
+                .by_handle
+                .get_mut(&handle)
+                .expect("Both session maps should always be in sync");
+
+            if session.expires_at > now && session.homeserver.as_deref() == Some(homeserver) {
+                if session.user_id.is_none() {
+                    session.user_id = user_id.map(ToOwned::to_owned);
+                }
+
+                return handle;
+            }
+
+            sessions.remove(&handle);
+        }
+
+        sessions.remove_expired(now);
+
+        let handle = sessions.new_handle(self.handle_length);
+
+        debug!("Created a new session");
+
+        sessions.insert(
+            handle.clone(),
+            Session {
+                access_token: access_token.to_owned(),
+                homeserver: Some(homeserver.to_owned()),
+                user_id: user_id.map(ToOwned::to_owned),
+                expires_at: now + self.lifetime.as_secs(),
+            },
+        );
+        self.persist(&mut sessions);
+
+        handle
+    }
+
+    /// Remove the session of the access token.
+    ///
+    /// Returns the user the access token belonged to, if it is known.
+    pub fn revoke_access_token(&self, access_token: &str) -> Option<String> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        let handle = sessions.by_access_token.get(access_token).cloned()?;
+        let session = sessions.remove(&handle)?;
+        self.persist(&mut sessions);
+
+        session.user_id
+    }
+
+    /// Remove all sessions of the user.
+    pub fn revoke_user(&self, user_id: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        let handles: Vec<_> = sessions
+            .by_handle
+            .iter()
+            .filter(|(_, session)| session.user_id.as_deref() == Some(user_id))
+            .map(|(handle, _)| handle.clone())
+            .collect();
+
+        for handle in &handles {
+            sessions.remove(handle);
+        }
+
+        self.persist(&mut sessions);
+    }
+
+    /// Update the session table after the homeserver responded to a request.
+    ///
+    /// `homeserver` identifies the homeserver the request was sent to, like for
+    /// [`Self::resolve`]. Returns the session handle that should be handed to the client, only if
+    /// the client asked for one.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_from_response(
+        &self,
+        method: &Method,
+        url: &Url,
+        homeserver: &str,
+        status: StatusCode,
+        access_token: Option<&str>,
+        session_use: SessionUse,
+        body: &[u8],
+    ) -> Option<Vec<u8>> {
+        let path = url.path();
+
+        if status == StatusCode::UNAUTHORIZED {
+            if let Some(access_token) = access_token {
+                self.revoke_access_token(access_token);
+            }
+
+            return None;
+        }
+
+        if !status.is_success() {
+            return None;
+        }
+
+        if *method == Method::POST && path.starts_with("/_matrix/client/") {
+            if path.ends_with("/login") && session_use == SessionUse::Requested {
+                let login: LoginResponse = serde_json::from_slice(body).ok()?;
+
+                return Some(self.handle_for(
+                    &login.access_token,
+                    login.user_id.as_deref(),
+                    homeserver,
+                ));
+            } else if path.ends_with("/logout") {
+                self.revoke_access_token(access_token?);
+
+                return None;
+            } else if path.ends_with("/logout/all") {
+                if let Some(user_id) = self.revoke_access_token(access_token?) {
+                    self.revoke_user(&user_id);
+                }
+
+                return None;
+            }
+        }
+
+        if session_use != SessionUse::Requested {
+            return None;
+        }
+
+        access_token.map(|access_token| self.handle_for(access_token, None, homeserver))
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Replace the session store with the sessions.
+///
+/// The sessions are written to a temporary file which only the proxy can read, since they hold
+/// access tokens, and then moved into place so a crash never leaves a partial file behind.
+fn write_sessions(path: &Path, sessions: &HashMap<String, Session>) -> Result<()> {
+    let content = serde_json::to_vec(sessions).context("Could not serialize the sessions")?;
+    let temp_path = path.with_extension("tmp");
+
+    let write = || {
+        // A leftover temporary file might have been created with other permissions.
+        let _ = fs::remove_file(&temp_path);
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        let mut file = options.open(&temp_path)?;
+        file.write_all(&content)?;
+        file.sync_all()?;
+
+        fs::rename(&temp_path, path)
+    };
+
+    write().with_context(|| format!("Could not write the session store {}", path.display()))
+}
+
+fn decode_handle(handle: &str) -> Result<Vec<u8>> {
+    hex::decode(handle)
+        .with_context(|| format!("Invalid session handle {handle} in the session store"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn store(path: Option<PathBuf>) -> SessionStore {
+        let config = SessionConfig {
+            enabled: true,
+            path,
+            ..SessionConfig::default()
+        };
+
+        SessionStore::new(&config, "https://example.org").unwrap()
+    }
+
+    #[test]
+    fn handles_are_bound_to_their_homeserver() {
+        let store = store(None);
+        let handle = store.handle_for("token", None, "example.org");
+
+        assert_eq!(handle.len(), SessionConfig::default().handle_length);
+        assert_eq!(store.handle_for("token", None, "example.org"), handle);
+        assert_eq!(
+            store.resolve(&handle, "example.org", ADDRESS).as_deref(),
+            Some("token")
+        );
+        assert_eq!(store.resolve(&handle, "example.com", ADDRESS), None);
+        assert_eq!(store.resolve(&handle, "https://example.org", ADDRESS), None);
+    }
+
+    #[test]
+    fn logout_revokes_the_handle() {
+        let store = store(None);
+        let url = Url::parse("https://example.org/_matrix/client/v3/logout").unwrap();
+
+        let handle = store.handle_for("token", Some("@alice:example.org"), "example.org");
+        store.update_from_response(
+            &Method::POST,
+            &url,
+            "example.org",
+            StatusCode::OK,
+            Some("token"),
+            SessionUse::Handle,
+            b"{}",
+        );
+
+        assert_eq!(store.resolve(&handle, "example.org", ADDRESS), None);
+    }
+
+    #[tokio::test]
+    async fn persists_sessions_privately() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("sessions.json");
+        let handle = store(Some(path.clone())).handle_for("token", None, "example.org");
+
+        // The file is written in the background.
+        for _ in 0..100 {
+            if path.exists() {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let reloaded = store(Some(path));
+        assert_eq!(
+            reloaded.resolve(&handle, "example.org", ADDRESS).as_deref(),
+            Some("token")
+        );
+    }
+
+    #[test]
+    fn hands_out_handles_only_when_asked() {
+        let store = store(None);
+        let url = Url::parse("https://example.org/_matrix/client/v3/sync").unwrap();
+        let login = Url::parse("https://example.org/_matrix/client/v3/login").unwrap();
+        let update = |url: &Url, session_use| {
+            store.update_from_response(
+                &Method::POST,
+                url,
+                "example.org",
+                StatusCode::OK,
+                Some("token"),
+                session_use,
+                br#"{"access_token":"login","user_id":"@alice:example.org"}"#,
+            )
+        };
+
+        assert_eq!(update(&url, SessionUse::None), None);
+        assert_eq!(update(&url, SessionUse::Handle), None);
+        assert_eq!(update(&login, SessionUse::None), None);
+        assert!(store.sessions.lock().unwrap().by_handle.is_empty());
+
+        let handle = update(&url, SessionUse::Requested).unwrap();
+        assert_eq!(
+            store.resolve(&handle, "example.org", ADDRESS).as_deref(),
+            Some("token")
+        );
+
+        let handle = update(&login, SessionUse::Requested).unwrap();
+        assert_eq!(
+            store.resolve(&handle, "example.org", ADDRESS).as_deref(),
+            Some("login")
+        );
+    }
+
+    #[test]
+    fn limits_failed_lookups_by_address() {
+        let store = store(None);
+        let handle = store.handle_for("token", None, "example.org");
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+        for _ in 0..FAILED_LOOKUP_BURST {
+            assert!(store.check_lookup(ADDRESS).is_ok());
+            assert_eq!(store.resolve(&[0; 8], "example.org", ADDRESS), None);
+        }
+
+        assert!(store.check_lookup(ADDRESS).is_err());
+        assert!(store.check_lookup(other).is_ok());
+
+        // Successful lookups don't count.
+        for _ in 0..FAILED_LOOKUP_BURST {
+            assert!(store.resolve(&handle, "example.org", other).is_some());
+        }
+        assert!(store.check_lookup(other).is_ok());
+    }
+}
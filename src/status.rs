@@ -0,0 +1,110 @@
+//! Mapping of HTTP status codes onto CoAP response codes.
+//!
+//! The mapping follows the recommendations of RFC 8075 section 7, status codes which don't have a
+//! direct CoAP equivalent are mapped onto the generic code of their class.
+
+use coap_lite::ResponseType;
+use reqwest::{Method, StatusCode};
+
+/// Get the CoAP response code for the status code the homeserver replied with.
+///
+/// The method of the upstream request is needed since CoAP distinguishes between successful
+/// responses that return a representation and those that only acknowledge a change.
+pub fn coap_status(method: &Method, status: StatusCode) -> ResponseType {
+    match status {
+        StatusCode::CREATED => ResponseType::Created,
+        StatusCode::NOT_MODIFIED => ResponseType::Valid,
+        StatusCode::NO_CONTENT if method == Method::DELETE => ResponseType::Deleted,
+        StatusCode::NO_CONTENT => ResponseType::Changed,
+        _ if status.is_success() => success_status(method),
+
+        StatusCode::BAD_REQUEST => ResponseType::BadRequest,
+        StatusCode::UNAUTHORIZED => ResponseType::Unauthorized,
+        StatusCode::FORBIDDEN => ResponseType::Forbidden,
+        StatusCode::NOT_FOUND | StatusCode::GONE => ResponseType::NotFound,
+        StatusCode::METHOD_NOT_ALLOWED => ResponseType::MethodNotAllowed,
+        StatusCode::NOT_ACCEPTABLE => ResponseType::NotAcceptable,
+        StatusCode::CONFLICT => ResponseType::Conflict,
+        StatusCode::PRECONDITION_FAILED => ResponseType::PreconditionFailed,
+        StatusCode::PAYLOAD_TOO_LARGE => ResponseType::RequestEntityTooLarge,
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => ResponseType::UnsupportedContentFormat,
+        StatusCode::UNPROCESSABLE_ENTITY => ResponseType::UnprocessableEntity,
+        StatusCode::TOO_MANY_REQUESTS => ResponseType::TooManyRequests,
+        _ if status.is_client_error() => ResponseType::BadRequest,
+
+        StatusCode::NOT_IMPLEMENTED => ResponseType::NotImplemented,
+        StatusCode::BAD_GATEWAY => ResponseType::BadGateway,
+        StatusCode::SERVICE_UNAVAILABLE => ResponseType::ServiceUnavailable,
+        StatusCode::GATEWAY_TIMEOUT => ResponseType::GatewayTimeout,
+        _ if status.is_server_error() => ResponseType::InternalServerError,
+
+        // Redirects are followed by the HTTP client and informational responses are never
+        // surfaced, if we still see one of those the homeserver isn't behaving correctly.
+        _ => ResponseType::BadGateway,
+    }
+}
+
+fn success_status(method: &Method) -> ResponseType {
+    match *method {
+        Method::DELETE => ResponseType::Deleted,
+        Method::POST | Method::PUT | Method::PATCH => ResponseType::Changed,
+        _ => ResponseType::Content,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_status_codes() {
+        let cases = [
+            (Method::GET, 200, ResponseType::Content),
+            (Method::HEAD, 200, ResponseType::Content),
+            (Method::POST, 200, ResponseType::Changed),
+            (Method::PUT, 200, ResponseType::Changed),
+            (Method::PATCH, 200, ResponseType::Changed),
+            (Method::DELETE, 200, ResponseType::Deleted),
+            (Method::GET, 203, ResponseType::Content),
+            (Method::POST, 201, ResponseType::Created),
+            (Method::PUT, 201, ResponseType::Created),
+            (Method::POST, 202, ResponseType::Changed),
+            (Method::PUT, 204, ResponseType::Changed),
+            (Method::DELETE, 204, ResponseType::Deleted),
+            (Method::GET, 304, ResponseType::Valid),
+            (Method::GET, 400, ResponseType::BadRequest),
+            (Method::GET, 401, ResponseType::Unauthorized),
+            (Method::GET, 403, ResponseType::Forbidden),
+            (Method::GET, 404, ResponseType::NotFound),
+            (Method::GET, 410, ResponseType::NotFound),
+            (Method::GET, 405, ResponseType::MethodNotAllowed),
+            (Method::GET, 406, ResponseType::NotAcceptable),
+            (Method::PUT, 409, ResponseType::Conflict),
+            (Method::PUT, 412, ResponseType::PreconditionFailed),
+            (Method::POST, 413, ResponseType::RequestEntityTooLarge),
+            (Method::POST, 415, ResponseType::UnsupportedContentFormat),
+            (Method::POST, 422, ResponseType::UnprocessableEntity),
+            (Method::GET, 429, ResponseType::TooManyRequests),
+            (Method::GET, 418, ResponseType::BadRequest),
+            (Method::GET, 500, ResponseType::InternalServerError),
+            (Method::GET, 501, ResponseType::NotImplemented),
+            (Method::GET, 502, ResponseType::BadGateway),
+            (Method::GET, 503, ResponseType::ServiceUnavailable),
+            (Method::GET, 504, ResponseType::GatewayTimeout),
+            (Method::GET, 507, ResponseType::InternalServerError),
+            (Method::GET, 100, ResponseType::BadGateway),
+            (Method::GET, 301, ResponseType::BadGateway),
+            (Method::GET, 302, ResponseType::BadGateway),
+        ];
+
+        for (method, status, expected) in cases {
+            let status = StatusCode::from_u16(status).unwrap();
+
+            assert_eq!(
+                coap_status(&method, status),
+                expected,
+                "{method} {status} should map onto {expected:?}"
+            );
+        }
+    }
+}
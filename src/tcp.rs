@@ -0,0 +1,463 @@
+//! The CoAP over TCP and TLS transports of the CoAP server (RFC 8323).
+//!
+//! Reliable transports frame messages differently than UDP, there's no type and no message ID,
+//! instead the header carries the length of the message. The listeners convert received messages
+//! into the UDP format the server understands and convert the responses back. Signaling messages,
+//! which only concern the connection, are answered here without involving the server.
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use coap::server::{Listener, Responder, TransportRequestSender};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, warn};
+
+use crate::transport::ListenerPeers;
+
+/// The largest message, without the length, the proxy accepts. It's announced to clients in the
+/// capabilities message.
+pub const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+/// Time a client has to complete the TLS or WebSocket handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Connections that didn't receive anything for this long are closed, clients can keep an idle
+/// connection open by sending pings.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Number of messages waiting to be written to a connection, once it's full responses wait for
+/// the client to read what was sent before.
+const OUTGOING_QUEUE_LENGTH: usize = 64;
+
+/// Time to wait before accepting connections again after accepting one failed, e.g. because the
+/// proxy ran out of file descriptors.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Codes of the signaling messages, all of them are in the class 7.
+const CODE_CSM: u8 = 0xE1;
+const CODE_PING: u8 = 0xE2;
+const CODE_PONG: u8 = 0xE3;
+const CODE_RELEASE: u8 = 0xE4;
+const CODE_ABORT: u8 = 0xE5;
+
+/// The Max-Message-Size option of the capabilities and settings message.
+const OPTION_MAX_MESSAGE_SIZE: u8 = 2;
+
+/// Tokens are at most 8 bytes long, longer token lengths are reserved.
+const MAX_TOKEN_LENGTH: usize = 8;
+
+/// The first byte of the UDP header of a non-confirmable message, without the token length.
+const NON_CONFIRMABLE_HEADER: u8 = 0x50;
+
+/// A CoAP message in the framing of the reliable transports.
+pub struct Message {
+    code: u8,
+    token: Vec<u8>,
+    /// The options and the payload.
+    body: Vec<u8>,
+}
+
+impl Message {
+    /// Split a message in the UDP format, as sent by the server, into its parts.
+    fn from_datagram(datagram: &[u8]) -> Option<Self> {
+        let token_length = usize::from(datagram.first()? & 0x0F);
+
+        Some(Self {
+            code: *datagram.get(1)?,
+            token: datagram.get(4..4 + token_length)?.to_vec(),
+            body: datagram[4 + token_length..].to_vec(),
+        })
+    }
+
+    /// Convert the message into the UDP format, as a non-confirmable message.
+    fn into_datagram(self, message_id: u16) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(4 + self.token.len() + self.body.len());
+        datagram.push(NON_CONFIRMABLE_HEADER | self.token.len() as u8);
+        datagram.push(self.code);
+        datagram.extend_from_slice(&message_id.to_be_bytes());
+        datagram.extend(self.token);
+        datagram.extend(self.body);
+
+        datagram
+    }
+
+    /// Serialize the message, WebSockets frame messages themselves so their length is left out.
+    fn encode(&self, include_length: bool) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(6 + self.token.len() + self.body.len());
+        let token_length = self.token.len() as u8;
+        let length = self.body.len();
+
+        match length {
+            _ if !include_length => bytes.push(token_length),
+            0..=12 => bytes.push((length as u8) << 4 | token_length),
+            13..=268 => {
+                bytes.push(13 << 4 | token_length);
+                bytes.push((length - 13) as u8);
+            }
+            269..=65804 => {
+                bytes.push(14 << 4 | token_length);
+                bytes.extend_from_slice(&((length - 269) as u16).to_be_bytes());
+            }
+            _ => {
+                bytes.push(15 << 4 | token_length);
+                bytes.extend_from_slice(&((length - 65805) as u32).to_be_bytes());
+            }
+        }
+
+        bytes.push(self.code);
+        bytes.extend_from_slice(&self.token);
+        bytes.extend_from_slice(&self.body);
+
+        bytes
+    }
+
+    /// Parse a message received as a WebSocket message.
+    pub fn from_websocket(bytes: &[u8]) -> Option<Self> {
+        let first = *bytes.first()?;
+        let token_length = usize::from(first & 0x0F);
+
+        // The length is always zero, the WebSocket message carries it instead.
+        if first >> 4 != 0 || token_length > MAX_TOKEN_LENGTH {
+            return None;
+        }
+
+        Some(Self {
+            code: *bytes.get(1)?,
+            token: bytes.get(2..2 + token_length)?.to_vec(),
+            body: bytes[2 + token_length..].to_vec(),
+        })
+    }
+}
+
+/// Read a message from a TCP or TLS stream, `None` if the client closed the connection.
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Message>> {
+    let first = match reader.read_u8().await {
+        Ok(first) => first,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let length = match first >> 4 {
+        13 => usize::from(reader.read_u8().await?) + 13,
+        14 => usize::from(reader.read_u16().await?) + 269,
+        15 => usize::try_from(reader.read_u32().await?)
+            .unwrap_or(usize::MAX)
+            .saturating_add(65805),
+        length => usize::from(length),
+    };
+    let token_length = usize::from(first & 0x0F);
+
+    if token_length > MAX_TOKEN_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The message has an invalid token length",
+        ));
+    }
+
+    if length > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The message is too large",
+        ));
+    }
+
+    let code = reader.read_u8().await?;
+    let mut token = vec![0; token_length];
+    reader.read_exact(&mut token).await?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+
+    Ok(Some(Message { code, token, body }))
+}
+
+/// Accept the next connection on the socket, failures to accept a connection don't stop the
+/// listener.
+///
+/// Connections beyond the limit of open connections are closed right away, the connection
+/// counts towards the limit until the returned permit is dropped.
+pub async fn accept(
+    listener: &tokio::net::TcpListener,
+    connections: &Arc<Semaphore>,
+) -> (TcpStream, SocketAddr, OwnedSemaphorePermit) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, address)) => {
+                let Ok(permit) = connections.clone().try_acquire_owned() else {
+                    warn!("Too many open connections, closing the connection of {address}");
+                    continue;
+                };
+
+                if let Err(e) = stream.set_nodelay(true) {
+                    debug!("Could not disable Nagle's algorithm for {address}: {e}");
+                }
+
+                return (stream, address, permit);
+            }
+            Err(e) => {
+                warn!("Could not accept a connection: {e}");
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+            }
+        }
+    }
+}
+
+/// A connection carrying CoAP messages, shared by the TCP, TLS and WebSocket transports.
+///
+/// The peer stays registered until the connection is dropped.
+pub struct Connection {
+    responder: Arc<StreamResponder>,
+    peers: ListenerPeers,
+    sender: TransportRequestSender,
+    next_message_id: u16,
+}
+
+impl Connection {
+    /// Register the peer and send the capabilities of the proxy.
+    ///
+    /// Messages for the client are sent to the returned receiver, the caller needs to write them
+    /// to the connection.
+    pub fn open(
+        address: SocketAddr,
+        websocket: bool,
+        peers: ListenerPeers,
+        sender: TransportRequestSender,
+    ) -> (Self, mpsc::Receiver<Vec<u8>>) {
+        let (outgoing, receiver) = mpsc::channel(OUTGOING_QUEUE_LENGTH);
+        let responder = Arc::new(StreamResponder {
+            outgoing,
+            address,
+            websocket,
+        });
+        peers.connect(responder.clone());
+
+        let max_message_size = (MAX_MESSAGE_SIZE as u16).to_be_bytes();
+        let mut body = vec![OPTION_MAX_MESSAGE_SIZE << 4 | max_message_size.len() as u8];
+        body.extend_from_slice(&max_message_size);
+
+        // The queue is still empty.
+        responder.try_send(&Message {
+            code: CODE_CSM,
+            token: Vec::new(),
+            body,
+        });
+
+        debug!("Established a connection with {address}");
+
+        let connection = Self {
+            responder,
+            peers,
+            sender,
+            next_message_id: 0,
+        };
+
+        (connection, receiver)
+    }
+
+    /// Handle a message received on the connection.
+    ///
+    /// Returns `false` if the connection should be closed.
+    pub fn receive(&mut self, message: Message) -> bool {
+        match message.code {
+            // Empty messages can be used to keep the connection alive and need to be ignored.
+            0 => true,
+            // A client that keeps sending pings without reading the pongs is disconnected.
+            CODE_PING => self.responder.try_send(&Message {
+                code: CODE_PONG,
+                token: message.token,
+                body: Vec::new(),
+            }),
+            CODE_RELEASE | CODE_ABORT => false,
+            // The capabilities of the client don't matter, responses are small enough for any
+            // client and the proxy doesn't make use of any of the optional features.
+            code if code >> 5 == 7 => true,
+            _ => {
+                let datagram = message.into_datagram(self.next_message_id);
+                self.next_message_id = self.next_message_id.wrapping_add(1);
+
+                let responder: Arc<dyn Responder> = self.responder.clone();
+                self.sender.send((datagram, responder)).is_ok()
+            }
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        debug!("Closing the connection with {}", self.responder.address);
+        self.peers.disconnect(self.responder.address);
+    }
+}
+
+struct StreamResponder {
+    outgoing: mpsc::Sender<Vec<u8>>,
+    address: SocketAddr,
+    websocket: bool,
+}
+
+impl StreamResponder {
+    /// Queue the message, waiting for the client to read earlier messages if the queue is full.
+    async fn send(&self, message: &Message) {
+        if self
+            .outgoing
+            .send(message.encode(!self.websocket))
+            .await
+            .is_err()
+        {
+            debug!(
+                "Could not send a message to {}, the connection is closed",
+                self.address
+            );
+        }
+    }
+
+    /// Queue the message without waiting, returns `false` if the queue is full or the connection
+    /// is closed.
+    fn try_send(&self, message: &Message) -> bool {
+        self.outgoing
+            .try_send(message.encode(!self.websocket))
+            .is_ok()
+    }
+}
+
+#[async_trait]
+impl Responder for StreamResponder {
+    async fn respond(&self, response: Vec<u8>) {
+        match Message::from_datagram(&response) {
+            // Empty acknowledgements and resets have no meaning on reliable transports.
+            Some(message) if message.code == 0 => {}
+            Some(message) => self.send(&message).await,
+            None => debug!("Could not send an invalid message to {}", self.address),
+        }
+    }
+
+    fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+/// A listener for CoAP over TCP, or over TLS if it's given an acceptor, which registers every
+/// peer that connects.
+pub struct TcpListener {
+    listener: tokio::net::TcpListener,
+    tls: Option<TlsAcceptor>,
+    peers: ListenerPeers,
+    connections: Arc<Semaphore>,
+}
+
+impl TcpListener {
+    /// Bind a TCP socket to the address.
+    ///
+    /// The connections of all listeners sharing the semaphore count towards the same limit.
+    pub async fn new(
+        address: SocketAddr,
+        tls: Option<TlsAcceptor>,
+        peers: ListenerPeers,
+        connections: Arc<Semaphore>,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            listener: tokio::net::TcpListener::bind(address).await?,
+            tls,
+            peers,
+            connections,
+        })
+    }
+
+    async fn accept_loop(self, sender: TransportRequestSender) -> io::Result<()> {
+        loop {
+            let (stream, address, permit) = accept(&self.listener, &self.connections).await;
+            let served = serve(
+                stream,
+                address,
+                self.tls.clone(),
+                self.peers.clone(),
+                sender.clone(),
+            );
+
+            tokio::spawn(async move {
+                served.await;
+                drop(permit);
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl Listener for TcpListener {
+    async fn listen(
+        self: Box<Self>,
+        sender: TransportRequestSender,
+    ) -> io::Result<JoinHandle<io::Result<()>>> {
+        Ok(tokio::spawn(self.accept_loop(sender)))
+    }
+}
+
+/// Complete the TLS handshake, if needed, and pass the messages the client sends on to the
+/// server.
+async fn serve(
+    stream: TcpStream,
+    address: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    peers: ListenerPeers,
+    sender: TransportRequestSender,
+) {
+    let Some(acceptor) = tls else {
+        serve_stream(stream, address, peers, sender).await;
+        return;
+    };
+
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => serve_stream(stream, address, peers, sender).await,
+        Ok(Err(e)) => debug!("The TLS handshake with {address} failed: {e}"),
+        Err(_) => debug!("The TLS handshake with {address} timed out"),
+    }
+}
+
+async fn serve_stream<S>(
+    stream: S,
+    address: SocketAddr,
+    peers: ListenerPeers,
+    sender: TransportRequestSender,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (mut connection, mut outgoing) = Connection::open(address, false, peers, sender);
+
+    let write_task = tokio::spawn(async move {
+        while let Some(bytes) = outgoing.recv().await {
+            let written = async {
+                writer.write_all(&bytes).await?;
+                writer.flush().await
+            };
+
+            if let Err(e) = written.await {
+                debug!("Could not send a message to {address}: {e}");
+                break;
+            }
+        }
+    });
+
+    loop {
+        match tokio::time::timeout(IDLE_TIMEOUT, read_message(&mut reader)).await {
+            Ok(Ok(Some(message))) => {
+                if !connection.receive(message) {
+                    break;
+                }
+            }
+            Ok(Ok(None)) | Err(_) => break,
+            Ok(Err(e)) => {
+                debug!("Could not read a message from {address}: {e}");
+                break;
+            }
+        }
+    }
+
+    drop(connection);
+    write_task.abort();
+}
@@ -0,0 +1,70 @@
+//! Loading of the certificates and private keys used by the DTLS and TLS listeners.
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TcpConfig;
+
+/// Load the certificate chain from a PEM file.
+pub fn load_certificates(path: &Path) -> Result<Vec<rustls::Certificate>> {
+    let file = File::open(path)
+        .with_context(|| format!("Could not open the certificate file {}", path.display()))?;
+
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map(|certificate| certificate.map(|certificate| rustls::Certificate(certificate.to_vec())))
+        .collect::<io::Result<Vec<_>>>()
+        .with_context(|| format!("Could not parse the certificate file {}", path.display()))?;
+
+    if certificates.is_empty() {
+        bail!(
+            "The certificate file {} contains no certificates",
+            path.display()
+        );
+    }
+
+    Ok(certificates)
+}
+
+/// Load the first PKCS#8 encoded private key from a PEM file.
+pub fn load_private_key(path: &Path) -> Result<rustls::PrivateKey> {
+    let file = File::open(path)
+        .with_context(|| format!("Could not open the private key file {}", path.display()))?;
+
+    let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(file))
+        .next()
+        .transpose()
+        .with_context(|| format!("Could not parse the private key file {}", path.display()))?
+        .with_context(|| format!("The file {} contains no PKCS#8 private key", path.display()))?;
+
+    Ok(rustls::PrivateKey(key.secret_pkcs8_der().to_vec()))
+}
+
+/// Build an acceptor for the TLS and secure WebSockets listeners, loading the configured
+/// certificate.
+///
+/// Clients using ALPN need to offer one of the given protocols.
+pub fn acceptor(config: &TcpConfig, protocols: &[&[u8]]) -> Result<TlsAcceptor> {
+    let (Some(certificate), Some(private_key)) = (&config.certificate, &config.private_key) else {
+        bail!("The TLS listeners need a certificate and a private key");
+    };
+
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            load_certificates(certificate)?,
+            load_private_key(private_key)?,
+        )
+        .with_context(|| format!("Unsupported private key in {}", private_key.display()))?;
+
+    server_config.alpn_protocols = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
@@ -0,0 +1,333 @@
+//! The UDP transport of the CoAP server.
+//!
+//! The listener of the coap crate only lets the request handler answer the request it was given.
+//! Observe notifications are sent long after the handler returned, so the proxy uses its own
+//! listeners which remember how to reach the peers they recently heard from, or are connected to.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use coap::server::{Listener, Responder, TransportRequestSender};
+use tokio::{net::UdpSocket, task::JoinHandle};
+use tracing::debug;
+
+/// Peers that didn't send anything for this long are forgotten.
+///
+/// This is the EXCHANGE_LIFETIME of RFC 7252, after which the peer can't expect a response to
+/// its last message anymore.
+const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(247);
+
+struct Peer {
+    responder: Arc<dyn Responder>,
+    /// The address of the listener the peer talks to.
+    listener: SocketAddr,
+    last_seen: Instant,
+    /// Whether the peer is reachable over a connection, it's then kept until the connection
+    /// closes instead of being forgotten when idle.
+    connected: bool,
+    /// Whether the transport proved that the peer receives what is sent to its address.
+    verified: bool,
+}
+
+#[derive(Default)]
+struct PeerTable {
+    peers: HashMap<SocketAddr, Peer>,
+    last_pruned: Option<Instant>,
+}
+
+/// The peers the proxy recently received messages from.
+#[derive(Default)]
+pub struct Peers {
+    table: Mutex<PeerTable>,
+}
+
+impl std::fmt::Debug for Peers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Peers")
+            .field("count", &self.table.lock().unwrap().peers.len())
+            .finish()
+    }
+}
+
+impl Peers {
+    /// Get the view of the registry for the listener bound to the address.
+    pub fn for_listener(self: &Arc<Self>, listener: SocketAddr) -> ListenerPeers {
+        ListenerPeers {
+            peers: self.clone(),
+            listener,
+        }
+    }
+
+    /// Remember the peer, unless the address is connected over a reliable transport, or recently
+    /// verified by a transport while this message isn't.
+    ///
+    /// The request handler only knows the address of a peer, a datagram from the address of a
+    /// connected or DTLS peer, possibly with a forged source address, must not take over its
+    /// entry. Responses would otherwise be sent unprotected over a path nobody verified.
+    fn insert(&self, responder: Arc<dyn Responder>, listener: SocketAddr, verified: bool) -> bool {
+        let mut table = self.table.lock().unwrap();
+        let now = Instant::now();
+
+        if table
+            .last_pruned
+            .is_none_or(|last_pruned| now.duration_since(last_pruned) > PEER_IDLE_TIMEOUT)
+        {
+            table.peers.retain(|_, peer| {
+                peer.connected || now.duration_since(peer.last_seen) < PEER_IDLE_TIMEOUT
+            });
+            table.last_pruned = Some(now);
+        }
+
+        if table.peers.get(&responder.address()).is_some_and(|peer| {
+            peer.connected
+                || (peer.verified
+                    && !verified
+                    && now.duration_since(peer.last_seen) < PEER_IDLE_TIMEOUT)
+        }) {
+            return false;
+        }
+
+        table.peers.insert(
+            responder.address(),
+            Peer {
+                responder,
+                listener,
+                last_seen: now,
+                connected: false,
+                verified,
+            },
+        );
+
+        true
+    }
+
+    fn connect(&self, responder: Arc<dyn Responder>, listener: SocketAddr) {
+        self.table.lock().unwrap().peers.insert(
+            responder.address(),
+            Peer {
+                responder,
+                listener,
+                last_seen: Instant::now(),
+                connected: true,
+                verified: true,
+            },
+        );
+    }
+
+    fn disconnect(&self, address: SocketAddr) {
+        let mut table = self.table.lock().unwrap();
+
+        if table.peers.get(&address).is_some_and(|peer| peer.connected) {
+            table.peers.remove(&address);
+        }
+    }
+
+    /// Check if the peer is still connected over a reliable transport.
+    pub fn is_connected(&self, address: SocketAddr) -> bool {
+        self.table
+            .lock()
+            .unwrap()
+            .peers
+            .get(&address)
+            .is_some_and(|peer| peer.connected)
+    }
+
+    /// Check if the transport the peer last used proved that the peer owns its address.
+    pub fn is_verified(&self, address: SocketAddr) -> bool {
+        self.table
+            .lock()
+            .unwrap()
+            .peers
+            .get(&address)
+            .is_some_and(|peer| peer.verified)
+    }
+
+    /// Get the address of the listener the peer last talked to.
+    pub fn listener(&self, address: SocketAddr) -> Option<SocketAddr> {
+        self.table
+            .lock()
+            .unwrap()
+            .peers
+            .get(&address)
+            .map(|peer| peer.listener)
+    }
+
+    /// Get the responder that sends messages to the given peer over the transport the peer last
+    /// used.
+    pub fn get(&self, address: SocketAddr) -> Option<Arc<dyn Responder>> {
+        self.table
+            .lock()
+            .unwrap()
+            .peers
+            .get(&address)
+            .map(|peer| peer.responder.clone())
+    }
+}
+
+/// The peer registry as seen by a single listener, which registers its peers as talking to it.
+#[derive(Clone)]
+pub struct ListenerPeers {
+    peers: Arc<Peers>,
+    listener: SocketAddr,
+}
+
+impl ListenerPeers {
+    /// Remember how to reach the peer a message was received from.
+    ///
+    /// Returns `false` if the address is connected over a reliable transport or was verified by
+    /// another transport, the message then needs to be dropped.
+    pub fn insert(&self, responder: Arc<dyn Responder>) -> bool {
+        self.peers.insert(responder, self.listener, false)
+    }
+
+    /// Remember how to reach a peer whose address was verified by a handshake, like the cookie
+    /// exchange of DTLS.
+    ///
+    /// Returns `false` if the address is connected over a reliable transport, the message then
+    /// needs to be dropped.
+    pub fn insert_verified(&self, responder: Arc<dyn Responder>) -> bool {
+        self.peers.insert(responder, self.listener, true)
+    }
+
+    /// Remember a peer connected over a reliable transport until it disconnects.
+    pub fn connect(&self, responder: Arc<dyn Responder>) {
+        self.peers.connect(responder, self.listener);
+    }
+
+    /// Forget a peer whose connection closed.
+    pub fn disconnect(&self, address: SocketAddr) {
+        self.peers.disconnect(address);
+    }
+}
+
+/// A listener for a UDP socket which registers every peer it receives a message from.
+pub struct UdpListener {
+    socket: Arc<UdpSocket>,
+    peers: ListenerPeers,
+}
+
+impl UdpListener {
+    /// Bind a UDP socket to the address.
+    pub fn new(address: SocketAddr, peers: ListenerPeers) -> io::Result<Self> {
+        let socket = std::net::UdpSocket::bind(address)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket: Arc::new(UdpSocket::from_std(socket)?),
+            peers,
+        })
+    }
+
+    async fn receive_loop(self, sender: TransportRequestSender) -> io::Result<()> {
+        loop {
+            let mut buffer = Vec::with_capacity(usize::from(u16::MAX));
+            let (_, address) = self.socket.recv_buf_from(&mut buffer).await?;
+
+            let responder: Arc<dyn Responder> = Arc::new(UdpResponder {
+                socket: self.socket.clone(),
+                address,
+            });
+
+            if !self.peers.insert(responder.clone()) {
+                debug!("Dropping a datagram from {address}, which uses a secure transport");
+                continue;
+            }
+
+            sender
+                .send((buffer, responder))
+                .map_err(|_| io::Error::other("The CoAP server has stopped"))?;
+        }
+    }
+}
+
+#[async_trait]
+impl Listener for UdpListener {
+    async fn listen(
+        self: Box<Self>,
+        sender: TransportRequestSender,
+    ) -> io::Result<JoinHandle<io::Result<()>>> {
+        Ok(tokio::spawn(self.receive_loop(sender)))
+    }
+}
+
+struct UdpResponder {
+    socket: Arc<UdpSocket>,
+    address: SocketAddr,
+}
+
+#[async_trait]
+impl Responder for UdpResponder {
+    async fn respond(&self, response: Vec<u8>) {
+        if let Err(e) = self.socket.send_to(&response, self.address).await {
+            debug!("Could not send a message to {}: {e}", self.address);
+        }
+    }
+
+    fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestResponder(SocketAddr);
+
+    #[async_trait]
+    impl Responder for TestResponder {
+        async fn respond(&self, _: Vec<u8>) {}
+
+        fn address(&self) -> SocketAddr {
+            self.0
+        }
+    }
+
+    #[test]
+    fn datagrams_dont_take_over_connected_peers() {
+        let peers = Arc::new(Peers::default());
+        let udp = peers.for_listener(SocketAddr::from(([127, 0, 0, 1], 5683)));
+        let tcp = peers.for_listener(SocketAddr::from(([127, 0, 0, 1], 5684)));
+        let address = SocketAddr::from(([192, 0, 2, 1], 40000));
+
+        tcp.connect(Arc::new(TestResponder(address)));
+        assert!(!udp.insert(Arc::new(TestResponder(address))));
+        assert!(!udp.insert_verified(Arc::new(TestResponder(address))));
+        assert!(peers.is_connected(address));
+        assert_eq!(peers.listener(address), Some(tcp.listener));
+
+        tcp.disconnect(address);
+        assert!(udp.insert(Arc::new(TestResponder(address))));
+        assert!(!peers.is_connected(address));
+        assert!(!peers.is_verified(address));
+
+        // A connection takes over the entry of a peer that used UDP before.
+        tcp.connect(Arc::new(TestResponder(address)));
+        assert!(peers.is_connected(address));
+        assert!(peers.is_verified(address));
+    }
+
+    #[test]
+    fn datagrams_dont_take_over_verified_peers() {
+        let peers = Arc::new(Peers::default());
+        let udp = peers.for_listener(SocketAddr::from(([127, 0, 0, 1], 5683)));
+        let dtls = peers.for_listener(SocketAddr::from(([127, 0, 0, 1], 5684)));
+        let address = SocketAddr::from(([192, 0, 2, 1], 40000));
+
+        assert!(dtls.insert_verified(Arc::new(TestResponder(address))));
+        assert!(!udp.insert(Arc::new(TestResponder(address))));
+        assert!(peers.is_verified(address));
+        assert_eq!(peers.listener(address), Some(dtls.listener));
+
+        // Verified peers can still move to another verified transport.
+        let other = peers.for_listener(SocketAddr::from(([127, 0, 0, 1], 5685)));
+        assert!(other.insert_verified(Arc::new(TestResponder(address))));
+        assert_eq!(peers.listener(address), Some(other.listener));
+    }
+}
@@ -0,0 +1,77 @@
+//! Construction of the homeserver URL from the URI options of a CoAP request.
+
+use anyhow::{Context, Result};
+use coap_lite::{CoapOption, Packet};
+use url::Url;
+
+use crate::routes::RouteTable;
+
+/// Build the URL of the upstream request.
+///
+/// Every Uri-Path option becomes a path segment below the homeserver base URL, after short route
+/// codes have been expanded, every Uri-Query option becomes a query parameter. The option values
+/// aren't percent-encoded in CoAP, so this takes care of encoding them for HTTP.
+pub fn upstream_url(base: &Url, routes: &RouteTable, message: &Packet) -> Result<Url> {
+    let mut url = base.clone();
+
+    if let Some(segments) = message.get_option(CoapOption::UriPath) {
+        let segments = segments
+            .iter()
+            .map(|segment| std::str::from_utf8(segment))
+            .collect::<Result<Vec<_>, _>>()
+            .context("The Uri-Path option isn't valid UTF-8")?;
+
+        let mut path = url
+            .path_segments_mut()
+            .expect("The homeserver URL should be usable as a base");
+        path.pop_if_empty();
+
+        match routes.expand(&segments)? {
+            Some(expanded) => path.extend(expanded),
+            None => path.extend(segments),
+        };
+    }
+
+    if let Some(queries) = message.get_option(CoapOption::UriQuery) {
+        let mut query_pairs = url.query_pairs_mut();
+
+        for query in queries {
+            let query =
+                std::str::from_utf8(query).context("The Uri-Query option isn't valid UTF-8")?;
+
+            match query.split_once('=') {
+                Some((key, value)) => query_pairs.append_pair(key, value),
+                None => query_pairs.append_key_only(query),
+            };
+        }
+    }
+
+    Ok(url)
+}
+
+/// Remove the `access_token` query parameter from the URL.
+///
+/// Returns the access token, if the URL contained one.
+pub fn take_access_token(url: &mut Url) -> Option<String> {
+    let access_token = url
+        .query_pairs()
+        .find(|(key, _)| key == "access_token")
+        .map(|(_, value)| value.into_owned())?;
+
+    let query_pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| key != "access_token")
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+    if query_pairs.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut()
+            .clear()
+            .extend_pairs(query_pairs)
+            .finish();
+    }
+
+    Some(access_token)
+}
@@ -0,0 +1,266 @@
+//! Virtual hosting, routing requests to one of several homeservers by their Uri-Host option or
+//! the listener they arrived on.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{Context, Result};
+use coap_lite::ResponseType;
+use url::Url;
+
+use crate::{
+    config::{ClientConfig, Config, DiscoveryConfig, ForwardConfig, UnknownHost},
+    discovery::{self, Homeserver},
+    forward,
+};
+
+/// A homeserver and the HTTP client used to talk to it.
+#[derive(Debug)]
+pub struct Upstream {
+    /// The homeserver, its base URL may change if it's discovered from a server name.
+    pub homeserver: Arc<Homeserver>,
+    /// The HTTP client used to talk to the homeserver.
+    ///
+    /// The client is shared so connections, TLS sessions and DNS lookups towards the homeserver
+    /// are reused between requests.
+    pub client: reqwest::Client,
+}
+
+impl Upstream {
+    fn new(
+        homeserver: Arc<Homeserver>,
+        client: &ClientConfig,
+        forward: &ForwardConfig,
+    ) -> Result<Self> {
+        let base_url = homeserver.base_url();
+
+        let mut builder = reqwest::Client::builder()
+            .timeout(client.request_timeout)
+            .connect_timeout(client.connect_timeout)
+            .pool_max_idle_per_host(client.pool_max_idle_per_host)
+            .pool_idle_timeout(client.pool_idle_timeout)
+            .tcp_keepalive(client.tcp_keepalive)
+            .redirect(forward::redirect_policy(forward, homeserver.clone()));
+
+        if client.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+
+        let client = builder
+            .build()
+            .with_context(|| format!("Could not create the HTTP client for {base_url}"))?;
+
+        Ok(Self { homeserver, client })
+    }
+}
+
+/// Get a homeserver, discovering it if a server name is given instead of a base URL.
+async fn homeserver(
+    base_url: &Url,
+    server_name: Option<&str>,
+    client: &reqwest::Client,
+    config: &DiscoveryConfig,
+) -> Result<Arc<Homeserver>> {
+    match server_name {
+        Some(server_name) => discovery::discover(server_name, client.clone(), config).await,
+        None => Ok(Homeserver::fixed(base_url.clone())),
+    }
+}
+
+#[derive(Debug)]
+struct VirtualHost {
+    host: Option<String>,
+    listen: Option<SocketAddr>,
+    upstream: Upstream,
+}
+
+/// The homeservers requests can be routed to.
+#[derive(Debug)]
+pub struct VirtualHosts {
+    virtual_hosts: Vec<VirtualHost>,
+    default: Upstream,
+    unknown_host: UnknownHost,
+}
+
+impl VirtualHosts {
+    /// Create the HTTP clients for the configured homeserver and every virtual host, and
+    /// discover the homeservers that are configured by their server name.
+    pub async fn new(config: &Config) -> Result<Self> {
+        let defaults = config.client();
+
+        let discovery_client = reqwest::Client::builder()
+            .timeout(defaults.request_timeout)
+            .connect_timeout(defaults.connect_timeout)
+            .build()
+            .context("Could not create the HTTP client for discovering homeservers")?;
+
+        let mut virtual_hosts = Vec::new();
+
+        for virtual_host in &config.virtual_hosts {
+            let homeserver = homeserver(
+                virtual_host
+                    .homeserver
+                    .as_ref()
+                    .unwrap_or(&config.homeserver),
+                virtual_host.server_name.as_deref(),
+                &discovery_client,
+                &config.discovery,
+            )
+            .await?;
+
+            virtual_hosts.push(VirtualHost {
+                host: virtual_host.host.clone(),
+                listen: virtual_host.listen,
+                upstream: Upstream::new(
+                    homeserver,
+                    &virtual_host.client(&defaults),
+                    &config.forward,
+                )?,
+            });
+        }
+
+        let default_homeserver = homeserver(
+            &config.homeserver,
+            config.server_name.as_deref(),
+            &discovery_client,
+            &config.discovery,
+        )
+        .await?;
+
+        Ok(Self {
+            virtual_hosts,
+            default: Upstream::new(default_homeserver, &defaults, &config.forward)?,
+            unknown_host: config.unknown_host,
+        })
+    }
+
+    /// The configured homeserver.
+    pub fn default_upstream(&self) -> &Upstream {
+        &self.default
+    }
+
+    /// Find the homeserver for a request carrying the Uri-Host option, which arrived on the
+    /// listener.
+    ///
+    /// Returns the response code for the client if no virtual host matches and such requests
+    /// are rejected.
+    pub fn route(
+        &self,
+        host: Option<&str>,
+        listener: Option<SocketAddr>,
+    ) -> Result<&Upstream, ResponseType> {
+        let host = host.map(str::to_ascii_lowercase);
+
+        let matching = self.virtual_hosts.iter().find(|virtual_host| {
+            virtual_host
+                .host
+                .as_ref()
+                .is_none_or(|expected| host.as_ref() == Some(expected))
+                && virtual_host
+                    .listen
+                    .is_none_or(|expected| listener == Some(expected))
+        });
+
+        match (matching, self.unknown_host) {
+            (Some(virtual_host), _) => Ok(&virtual_host.upstream),
+            (None, UnknownHost::Default) => Ok(&self.default),
+            (None, UnknownHost::NotFound) => Err(ResponseType::NotFound),
+            (None, UnknownHost::BadGateway) => Err(ResponseType::BadGateway),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        homeserver = "https://default.example/"
+
+        [[virtual_hosts]]
+        host = "alpha.example"
+        listen = "127.0.0.1:5683"
+        homeserver = "https://alpha-local.example/"
+
+        [[virtual_hosts]]
+        host = "alpha.example"
+        homeserver = "https://alpha.example/"
+
+        [[virtual_hosts]]
+        listen = "127.0.0.1:5684"
+        homeserver = "https://beta.example/"
+    "#;
+
+    async fn virtual_hosts(unknown_host: &str) -> VirtualHosts {
+        let config: Config =
+            toml::from_str(&format!("unknown_host = \"{unknown_host}\"\n{CONFIG}")).unwrap();
+
+        VirtualHosts::new(&config).await.unwrap()
+    }
+
+    fn routed(
+        virtual_hosts: &VirtualHosts,
+        host: Option<&str>,
+        listener: Option<&str>,
+    ) -> Result<String, ResponseType> {
+        let listener = listener.map(|listener| listener.parse().unwrap());
+
+        virtual_hosts
+            .route(host, listener)
+            .map(|upstream| upstream.homeserver.base_url().to_string())
+    }
+
+    #[tokio::test]
+    async fn routes_by_uri_host_and_listener() {
+        let virtual_hosts = virtual_hosts("default").await;
+        let route = |host, listener| routed(&virtual_hosts, host, listener);
+
+        // The first matching virtual host wins, hosts are case-insensitive.
+        assert_eq!(
+            route(Some("alpha.example"), Some("127.0.0.1:5683")).unwrap(),
+            "https://alpha-local.example/"
+        );
+        assert_eq!(
+            route(Some("ALPHA.example"), Some("127.0.0.1:5685")).unwrap(),
+            "https://alpha.example/"
+        );
+        assert_eq!(
+            route(Some("alpha.example"), None).unwrap(),
+            "https://alpha.example/"
+        );
+
+        // Virtual hosts without a host match any Uri-Host on their listener.
+        assert_eq!(
+            route(None, Some("127.0.0.1:5684")).unwrap(),
+            "https://beta.example/"
+        );
+        assert_eq!(
+            route(Some("gamma.example"), Some("127.0.0.1:5684")).unwrap(),
+            "https://beta.example/"
+        );
+    }
+
+    #[tokio::test]
+    async fn handles_unknown_hosts_as_configured() {
+        let default = virtual_hosts("default").await;
+        assert_eq!(
+            routed(&default, Some("gamma.example"), Some("127.0.0.1:5685")).unwrap(),
+            "https://default.example/"
+        );
+        assert_eq!(
+            routed(&default, None, None).unwrap(),
+            "https://default.example/"
+        );
+
+        let not_found = virtual_hosts("not-found").await;
+        assert_eq!(
+            routed(&not_found, Some("gamma.example"), None),
+            Err(ResponseType::NotFound)
+        );
+
+        let bad_gateway = virtual_hosts("bad-gateway").await;
+        assert_eq!(
+            routed(&bad_gateway, None, Some("127.0.0.1:5685")),
+            Err(ResponseType::BadGateway)
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! The CoAP over WebSockets transport of the CoAP server (RFC 8323).
+//!
+//! Clients open a WebSocket on `/.well-known/coap` using the `coap` subprotocol. Every binary
+//! WebSocket message carries a single CoAP message in the framing of the TCP transport, without
+//! the length, so everything past the handshake is shared with the TCP transport.
+
+use std::{io, net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use coap::server::{Listener, TransportRequestSender};
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::Semaphore,
+    task::JoinHandle,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request, Response},
+    http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue, StatusCode},
+    protocol::WebSocketConfig,
+    Message as WebSocketMessage,
+};
+use tracing::debug;
+
+use crate::{
+    tcp::{self, Connection, Message},
+    transport::ListenerPeers,
+};
+
+/// The path CoAP is served on.
+const PATH: &str = "/.well-known/coap";
+
+/// The subprotocol clients need to request.
+const SUBPROTOCOL: &str = "coap";
+
+/// A listener for CoAP over WebSockets, or over secure WebSockets if it's given an acceptor,
+/// which registers every peer that connects.
+pub struct WebSocketListener {
+    listener: tokio::net::TcpListener,
+    tls: Option<TlsAcceptor>,
+    peers: ListenerPeers,
+    connections: Arc<Semaphore>,
+}
+
+impl WebSocketListener {
+    /// Bind a TCP socket to the address.
+    ///
+    /// The connections of all listeners sharing the semaphore count towards the same limit.
+    pub async fn new(
+        address: SocketAddr,
+        tls: Option<TlsAcceptor>,
+        peers: ListenerPeers,
+        connections: Arc<Semaphore>,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            listener: tokio::net::TcpListener::bind(address).await?,
+            tls,
+            peers,
+            connections,
+        })
+    }
+
+    async fn accept_loop(self, sender: TransportRequestSender) -> io::Result<()> {
+        loop {
+            let (stream, address, permit) = tcp::accept(&self.listener, &self.connections).await;
+            let served = serve(
+                stream,
+                address,
+                self.tls.clone(),
+                self.peers.clone(),
+                sender.clone(),
+            );
+
+            tokio::spawn(async move {
+                served.await;
+                drop(permit);
+            });
+        }
+    }
+}
+
+#[async_trait]
+impl Listener for WebSocketListener {
+    async fn listen(
+        self: Box<Self>,
+        sender: TransportRequestSender,
+    ) -> io::Result<JoinHandle<io::Result<()>>> {
+        Ok(tokio::spawn(self.accept_loop(sender)))
+    }
+}
+
+/// Check that the client opens the WebSocket on the right path using the right subprotocol.
+// The signature is the one tungstenite expects from a handshake callback.
+#[allow(clippy::result_large_err)]
+fn check_request(request: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+    if request.uri().path() != PATH {
+        return Err(error_response(
+            StatusCode::NOT_FOUND,
+            "CoAP is only served on /.well-known/coap",
+        ));
+    }
+
+    let requests_subprotocol = request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|subprotocol| subprotocol.trim() == SUBPROTOCOL);
+
+    if !requests_subprotocol {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            "The coap subprotocol needs to be requested",
+        ));
+    }
+
+    response.headers_mut().insert(
+        SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_static(SUBPROTOCOL),
+    );
+
+    Ok(response)
+}
+
+fn error_response(status: StatusCode, message: &str) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(message.to_owned()));
+    *response.status_mut() = status;
+
+    response
+}
+
+/// Complete the TLS handshake, if needed, and the WebSocket handshake and pass the messages the
+/// client sends on to the server.
+async fn serve(
+    stream: TcpStream,
+    address: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    peers: ListenerPeers,
+    sender: TransportRequestSender,
+) {
+    let Some(acceptor) = tls else {
+        serve_stream(stream, address, peers, sender).await;
+        return;
+    };
+
+    match tokio::time::timeout(tcp::HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => serve_stream(stream, address, peers, sender).await,
+        Ok(Err(e)) => debug!("The TLS handshake with {address} failed: {e}"),
+        Err(_) => debug!("The TLS handshake with {address} timed out"),
+    }
+}
+
+async fn serve_stream<S>(
+    stream: S,
+    address: SocketAddr,
+    peers: ListenerPeers,
+    sender: TransportRequestSender,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let config = WebSocketConfig {
+        max_message_size: Some(tcp::MAX_MESSAGE_SIZE),
+        max_frame_size: Some(tcp::MAX_MESSAGE_SIZE),
+        ..Default::default()
+    };
+    let handshake =
+        tokio_tungstenite::accept_hdr_async_with_config(stream, check_request, Some(config));
+
+    let websocket = match tokio::time::timeout(tcp::HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok(websocket)) => websocket,
+        Ok(Err(e)) => {
+            debug!("The WebSocket handshake with {address} failed: {e}");
+            return;
+        }
+        Err(_) => {
+            debug!("The WebSocket handshake with {address} timed out");
+            return;
+        }
+    };
+
+    let (mut sink, mut stream) = websocket.split();
+    let (mut connection, mut outgoing) = Connection::open(address, true, peers, sender);
+
+    let write_task = tokio::spawn(async move {
+        while let Some(bytes) = outgoing.recv().await {
+            if let Err(e) = sink.send(WebSocketMessage::Binary(bytes)).await {
+                debug!("Could not send a message to {address}: {e}");
+                break;
+            }
+        }
+    });
+
+    loop {
+        match tokio::time::timeout(tcp::IDLE_TIMEOUT, stream.next()).await {
+            Ok(Some(Ok(WebSocketMessage::Binary(bytes)))) => {
+                let Some(message) = Message::from_websocket(&bytes) else {
+                    debug!("Received an invalid message from {address}");
+                    break;
+                };
+
+                if !connection.receive(message) {
+                    break;
+                }
+            }
+            // Pings are answered by the WebSocket implementation.
+            Ok(Some(Ok(WebSocketMessage::Ping(_) | WebSocketMessage::Pong(_)))) => {}
+            Ok(Some(Ok(_))) | Ok(None) | Err(_) => break,
+            Ok(Some(Err(e))) => {
+                debug!("Could not read a message from {address}: {e}");
+                break;
+            }
+        }
+    }
+
+    drop(connection);
+    write_task.abort();
+}
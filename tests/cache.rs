@@ -0,0 +1,155 @@
+//! Revalidation of stale cached responses while clients validate their own representations.
+
+mod common;
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use coap_lite::{CoapOption, MessageClass, Packet, ResponseType};
+use common::{exchange, free_udp_port, versions_request, Proxy};
+use hyper::{
+    header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use tokio::net::UdpSocket;
+
+const FIRST: (&str, &str) = (r#""v1""#, r#"{"versions":["v1.1"]}"#);
+const SECOND: (&str, &str) = (r#""v2""#, r#"{"versions":["v1.2"]}"#);
+
+#[derive(Default)]
+struct State {
+    /// The entity tag and body of the current representation.
+    current: (&'static str, &'static str),
+    /// The `If-None-Match` headers of the requests, in the order they arrived.
+    if_none_match: Vec<Vec<String>>,
+}
+
+/// A homeserver with a single representation that stays fresh for a second, confirming it
+/// without an `ETag` header.
+struct Homeserver {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl Homeserver {
+    fn start(current: (&'static str, &'static str)) -> Self {
+        let state = Arc::new(Mutex::new(State {
+            current,
+            ..Default::default()
+        }));
+        let shared = state.clone();
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service_fn(
+            move |_| {
+                let state = shared.clone();
+
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        let response = respond(&mut state.lock().unwrap(), &request);
+                        async move { Ok::<_, Infallible>(response) }
+                    }))
+                }
+            },
+        ));
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        Self { address, state }
+    }
+
+    fn change(&self, current: (&'static str, &'static str)) {
+        self.state.lock().unwrap().current = current;
+    }
+
+    fn last_if_none_match(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.if_none_match.last().cloned().unwrap_or_default()
+    }
+}
+
+fn respond(state: &mut State, request: &Request<Body>) -> Response<Body> {
+    let if_none_match: Vec<String> = request
+        .headers()
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap().split(','))
+        .map(|entity_tag| entity_tag.trim().to_owned())
+        .collect();
+
+    let (entity_tag, body) = state.current;
+    let not_modified = if_none_match.iter().any(|tag| tag == entity_tag);
+    state.if_none_match.push(if_none_match);
+
+    let response = Response::builder().header(CACHE_CONTROL, "max-age=1");
+
+    if not_modified {
+        return response
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    response
+        .header(ETAG, entity_tag)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn etag(message: &Packet) -> Vec<u8> {
+    message
+        .get_option(CoapOption::ETag)
+        .and_then(|values| values.front().cloned())
+        .expect("The response should carry an ETag")
+}
+
+#[tokio::test]
+async fn revalidates_stale_responses_only_with_their_own_entity_tag() {
+    let homeserver = Homeserver::start(FIRST);
+    let address = SocketAddr::from(([127, 0, 0, 1], free_udp_port()));
+    let _proxy = Proxy::start(&format!(
+        r#"
+        listen = ["{address}"]
+        homeserver = "http://{}/"
+        "#,
+        homeserver.address
+    ));
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    // The first representation ends up in the cache.
+    let response = exchange(&client, address, &versions_request(1)).await;
+    assert_eq!(response.payload, FIRST.1.as_bytes());
+
+    // A conditional request bypasses the cache and hands out the ETag of the second one.
+    homeserver.change(SECOND);
+    let mut request = versions_request(2);
+    request.add_option(CoapOption::IfMatch, Vec::new());
+    let response = exchange(&client, address, &request).await;
+    assert_eq!(response.payload, SECOND.1.as_bytes());
+    let second = etag(&response);
+
+    tokio::time::sleep(Duration::from_millis(1200)).await;
+
+    // The cached response is stale now, but the client only asks about its own representation.
+    let mut request = versions_request(3);
+    request.add_option(CoapOption::ETag, second.clone());
+    let response = exchange(&client, address, &request).await;
+    assert_eq!(homeserver.last_if_none_match(), [SECOND.0]);
+    assert_eq!(
+        response.header.code,
+        MessageClass::Response(ResponseType::Valid)
+    );
+    assert_eq!(etag(&response), second);
+    assert!(response.payload.is_empty());
+
+    // The confirmation of the client's representation didn't refresh the cached one.
+    let response = exchange(&client, address, &versions_request(4)).await;
+    assert_eq!(homeserver.last_if_none_match(), [FIRST.0]);
+    assert_eq!(response.payload, SECOND.1.as_bytes());
+    assert_eq!(etag(&response), second);
+}
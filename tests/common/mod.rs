@@ -0,0 +1,149 @@
+//! Helpers to run the proxy binary against a local homeserver.
+
+#![allow(dead_code)]
+
+use std::{
+    convert::Infallible,
+    io::Write,
+    net::{SocketAddr, UdpSocket},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use coap_lite::{CoapOption, MessageClass, MessageType, Packet, RequestType};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Response, Server,
+};
+use tempfile::NamedTempFile;
+
+pub const VERSIONS: &str = r#"{"versions":["v1.1"]}"#;
+
+/// A homeserver answering every request with the supported versions, counting the requests.
+pub struct Homeserver {
+    pub address: SocketAddr,
+    hits: Arc<AtomicUsize>,
+}
+
+impl Homeserver {
+    pub async fn start() -> Self {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service_fn(
+            move |_| {
+                let counter = counter.clone();
+
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |_| {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        async { Ok::<_, Infallible>(Response::new(Body::from(VERSIONS))) }
+                    }))
+                }
+            },
+        ));
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        Self { address, hits }
+    }
+
+    /// The number of requests the homeserver received.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+}
+
+/// Find a port that is free for UDP right now.
+pub fn free_udp_port() -> u16 {
+    UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// The proxy binary running with a configuration, killed once it's dropped.
+pub struct Proxy {
+    child: Child,
+    _config: NamedTempFile,
+}
+
+impl Proxy {
+    pub fn start(config: &str) -> Self {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(config.as_bytes()).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_coap-proxy"))
+            .arg("--config")
+            .arg(file.path())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        Self {
+            child,
+            _config: file,
+        }
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A confirmable GET request for the supported versions.
+pub fn versions_request(message_id: u16) -> Packet {
+    let mut message = Packet::new();
+    message.header.set_type(MessageType::Confirmable);
+    message.header.code = MessageClass::Request(RequestType::Get);
+    message.header.message_id = message_id;
+    message.set_token(message_id.to_be_bytes().to_vec());
+
+    for segment in ["_matrix", "client", "versions"] {
+        message.add_option(CoapOption::UriPath, segment.as_bytes().to_vec());
+    }
+
+    message
+}
+
+/// Send the request over UDP until its response arrives, giving the proxy time to start.
+pub async fn exchange(
+    socket: &tokio::net::UdpSocket,
+    proxy: SocketAddr,
+    request: &Packet,
+) -> Packet {
+    let datagram = request.to_bytes().unwrap();
+    let mut buffer = [0; 2048];
+
+    for _ in 0..50 {
+        socket.send_to(&datagram, proxy).await.unwrap();
+
+        // Responses to earlier retransmissions can still arrive, skip them.
+        let response = tokio::time::timeout(Duration::from_millis(200), async {
+            loop {
+                let len = socket.recv(&mut buffer).await.unwrap();
+                let response = Packet::from_bytes(&buffer[..len]).unwrap();
+
+                if response.get_token() == request.get_token() {
+                    return response;
+                }
+            }
+        })
+        .await;
+
+        if let Ok(response) = response {
+            return response;
+        }
+    }
+
+    panic!("The proxy never answered");
+}
@@ -0,0 +1,200 @@
+//! Handshakes and requests over the DTLS listener, with pre-shared keys and with pinned client
+//! keys.
+
+mod common;
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use coap_lite::{MessageClass, Packet, ResponseType};
+use common::{exchange, free_udp_port, versions_request, Homeserver, Proxy, VERSIONS};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use tokio::net::UdpSocket;
+use webrtc_dtls::{
+    cipher_suite::CipherSuiteId,
+    config::{Config, ExtendedMasterSecretType},
+    conn::DTLSConn,
+    crypto::Certificate,
+};
+use webrtc_util::Conn;
+
+const IDENTITY: &str = "client1";
+const KEY: &[u8] = b"secret123";
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The SHA-256 hash of the public key of a certificate, as the proxy expects it in its
+/// configuration.
+fn key_hash(certificate: &Certificate) -> String {
+    let (_, certificate) = x509_parser::parse_x509_certificate(&certificate.certificate[0].0)
+        .expect("The certificate should be valid");
+
+    hex(&Sha256::digest(certificate.public_key().raw))
+}
+
+/// Start the proxy with a DTLS listener configured by the section, waiting until it answers
+/// on its plain UDP listener.
+async fn start(dtls: &str) -> (Homeserver, Proxy, SocketAddr) {
+    let homeserver = Homeserver::start().await;
+    let address = SocketAddr::from(([127, 0, 0, 1], free_udp_port()));
+    let dtls_address = SocketAddr::from(([127, 0, 0, 1], free_udp_port()));
+
+    let proxy = Proxy::start(&format!(
+        r#"
+        listen = ["{address}"]
+        homeserver = "http://{}/"
+
+        [dtls]
+        listen = ["{dtls_address}"]
+        {dtls}
+        "#,
+        homeserver.address
+    ));
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    exchange(&client, address, &versions_request(1)).await;
+
+    (homeserver, proxy, dtls_address)
+}
+
+fn psk_config(key: &'static [u8]) -> Config {
+    Config {
+        psk: Some(Arc::new(move |_| Ok(key.to_vec()))),
+        psk_identity_hint: Some(IDENTITY.as_bytes().to_vec()),
+        cipher_suites: vec![CipherSuiteId::Tls_Psk_With_Aes_128_Ccm_8],
+        extended_master_secret: ExtendedMasterSecretType::Require,
+        ..Default::default()
+    }
+}
+
+fn certificate_config(certificate: Certificate) -> Config {
+    Config {
+        certificates: vec![certificate],
+        // The proxy uses a self-signed certificate as well.
+        insecure_skip_verify: true,
+        extended_master_secret: ExtendedMasterSecretType::Require,
+        ..Default::default()
+    }
+}
+
+async fn connect(proxy: SocketAddr, config: Config) -> Option<DTLSConn> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(proxy).await.unwrap();
+
+    let handshake = DTLSConn::new(Arc::new(socket), config, true, None);
+
+    match tokio::time::timeout(Duration::from_secs(10), handshake).await {
+        Ok(Ok(conn)) => Some(conn),
+        _ => None,
+    }
+}
+
+async fn request_versions(conn: &DTLSConn) {
+    let request = versions_request(2);
+    conn.send(&request.to_bytes().unwrap()).await.unwrap();
+
+    let mut buffer = vec![0; 2048];
+    let len = tokio::time::timeout(Duration::from_secs(10), conn.recv(&mut buffer))
+        .await
+        .expect("The proxy should answer")
+        .unwrap();
+
+    let response = Packet::from_bytes(&buffer[..len]).unwrap();
+    assert_eq!(response.get_token(), request.get_token());
+    assert_eq!(
+        response.header.code,
+        MessageClass::Response(ResponseType::Content)
+    );
+    assert_eq!(response.payload, VERSIONS.as_bytes());
+}
+
+fn psk_section() -> String {
+    format!(
+        r#"
+        [dtls.psk]
+        {IDENTITY} = "{}"
+        "#,
+        hex(KEY)
+    )
+}
+
+/// A certificate section with a new self-signed certificate of the proxy, pinning the key.
+fn pinned_section(client_key: &str) -> (String, NamedTempFile, NamedTempFile) {
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+
+    let certificate_file = NamedTempFile::new().unwrap();
+    std::fs::write(&certificate_file, certificate.serialize_pem().unwrap()).unwrap();
+    let key_file = NamedTempFile::new().unwrap();
+    std::fs::write(&key_file, certificate.serialize_private_key_pem()).unwrap();
+
+    let section = format!(
+        r#"
+        certificate = "{}"
+        private_key = "{}"
+        client_auth = "pinned"
+        client_keys = ["{client_key}"]
+        "#,
+        certificate_file.path().display(),
+        key_file.path().display()
+    );
+
+    (section, certificate_file, key_file)
+}
+
+#[tokio::test]
+async fn answers_clients_with_a_pre_shared_key() {
+    let (_homeserver, _proxy, address) = start(&psk_section()).await;
+
+    let conn = connect(address, psk_config(KEY))
+        .await
+        .expect("The handshake should succeed");
+    request_versions(&conn).await;
+}
+
+#[tokio::test]
+async fn rejects_clients_with_another_pre_shared_key() {
+    let (homeserver, _proxy, address) = start(&psk_section()).await;
+    let hits = homeserver.hits();
+
+    assert!(connect(address, psk_config(b"another key")).await.is_none());
+    assert_eq!(homeserver.hits(), hits);
+}
+
+#[tokio::test]
+async fn answers_clients_with_a_pinned_key() {
+    let certificate = Certificate::generate_self_signed(vec!["client".to_owned()]).unwrap();
+    let (section, _certificate_file, _key_file) = pinned_section(&key_hash(&certificate));
+    let (_homeserver, _proxy, address) = start(&section).await;
+
+    let conn = connect(address, certificate_config(certificate))
+        .await
+        .expect("The handshake should succeed");
+    request_versions(&conn).await;
+}
+
+#[tokio::test]
+async fn rejects_clients_with_another_key() {
+    let pinned = Certificate::generate_self_signed(vec!["client".to_owned()]).unwrap();
+    let (section, _certificate_file, _key_file) = pinned_section(&key_hash(&pinned));
+    let (homeserver, _proxy, address) = start(&section).await;
+    let hits = homeserver.hits();
+
+    let other = Certificate::generate_self_signed(vec!["client".to_owned()]).unwrap();
+    assert!(connect(address, certificate_config(other)).await.is_none());
+    assert_eq!(homeserver.hits(), hits);
+}
+
+#[tokio::test]
+async fn turns_away_clients_beyond_the_connection_limit() {
+    let (_homeserver, _proxy, address) =
+        start(&format!("max_connections = 1\n{}", psk_section())).await;
+
+    let conn = connect(address, psk_config(KEY))
+        .await
+        .expect("The handshake should succeed");
+    assert!(connect(address, psk_config(KEY)).await.is_none());
+
+    request_versions(&conn).await;
+}
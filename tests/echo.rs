@@ -0,0 +1,106 @@
+//! Requests from spoofed source addresses never reach the homeserver when the Echo option is
+//! required.
+
+mod common;
+
+use std::net::SocketAddr;
+
+use coap_lite::{CoapOption, MessageClass, Packet, ResponseType};
+use common::{exchange, free_udp_port, versions_request, Homeserver, Proxy};
+use tokio::net::UdpSocket;
+
+const ECHO: CoapOption = CoapOption::Unknown(252);
+
+fn code(message: &Packet) -> MessageClass {
+    message.header.code
+}
+
+fn echo(message: &Packet) -> Vec<u8> {
+    message
+        .get_option(ECHO)
+        .and_then(|values| values.front().cloned())
+        .expect("The challenge should carry an Echo option")
+}
+
+async fn start() -> (Homeserver, Proxy, SocketAddr) {
+    let homeserver = Homeserver::start().await;
+    let address = SocketAddr::from(([127, 0, 0, 1], free_udp_port()));
+
+    let proxy = Proxy::start(&format!(
+        r#"
+        listen = ["{address}"]
+        homeserver = "http://{}/"
+
+        [echo]
+        enabled = true
+        "#,
+        homeserver.address
+    ));
+
+    (homeserver, proxy, address)
+}
+
+#[tokio::test]
+async fn challenges_requests_without_echo() {
+    let (homeserver, _proxy, address) = start().await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let mut request = versions_request(1);
+    request.payload = b"not reflected".to_vec();
+
+    let response = exchange(&client, address, &request).await;
+    assert_eq!(
+        code(&response),
+        MessageClass::Response(ResponseType::Unauthorized)
+    );
+    assert!(!echo(&response).is_empty());
+    assert!(response.payload.is_empty());
+    assert_eq!(homeserver.hits(), 0);
+
+    let mut request = versions_request(2);
+    request.add_option(ECHO, echo(&response));
+
+    let response = exchange(&client, address, &request).await;
+    assert_eq!(
+        code(&response),
+        MessageClass::Response(ResponseType::Content)
+    );
+    assert_eq!(homeserver.hits(), 1);
+}
+
+#[tokio::test]
+async fn rejects_echo_values_of_other_addresses() {
+    let (homeserver, _proxy, address) = start().await;
+    let victim = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let attacker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let challenge = exchange(&victim, address, &versions_request(1)).await;
+
+    // The Echo value was issued to the victim, another address can't use it.
+    let mut request = versions_request(2);
+    request.add_option(ECHO, echo(&challenge));
+
+    let response = exchange(&attacker, address, &request).await;
+    assert_eq!(
+        code(&response),
+        MessageClass::Response(ResponseType::Unauthorized)
+    );
+    assert_ne!(echo(&response), echo(&challenge));
+    assert_eq!(homeserver.hits(), 0);
+
+    // Neither can a request without the Echo value once the victim proved its address.
+    let mut request = versions_request(3);
+    request.add_option(ECHO, echo(&challenge));
+    let response = exchange(&victim, address, &request).await;
+    assert_eq!(
+        code(&response),
+        MessageClass::Response(ResponseType::Content)
+    );
+
+    let response = exchange(&attacker, address, &versions_request(4)).await;
+    assert_eq!(
+        code(&response),
+        MessageClass::Response(ResponseType::Unauthorized)
+    );
+    assert_eq!(homeserver.hits(), 1);
+}
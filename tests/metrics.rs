@@ -0,0 +1,61 @@
+//! The metrics count the responses clients actually received.
+
+mod common;
+
+use std::net::SocketAddr;
+
+use coap_lite::{MessageClass, ResponseType};
+use common::{exchange, free_udp_port, versions_request, Homeserver, Proxy};
+use tokio::net::UdpSocket;
+
+fn free_tcp_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+#[tokio::test]
+async fn counts_responses_replaced_by_the_amplification_guard() {
+    let homeserver = Homeserver::start().await;
+    let address = SocketAddr::from(([127, 0, 0, 1], free_udp_port()));
+    let metrics = SocketAddr::from(([127, 0, 0, 1], free_tcp_port()));
+
+    // Any response with a payload is larger than the request.
+    let _proxy = Proxy::start(&format!(
+        r#"
+        listen = ["{address}"]
+        homeserver = "http://{}/"
+
+        [metrics]
+        listen = "{metrics}"
+
+        [amplification]
+        enabled = true
+        factor = 1
+        "#,
+        homeserver.address
+    ));
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let response = exchange(&client, address, &versions_request(1)).await;
+    assert_eq!(
+        response.header.code,
+        MessageClass::Response(ResponseType::ServiceUnavailable)
+    );
+
+    let metrics = reqwest::get(format!("http://{metrics}/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    assert!(
+        metrics.contains(r#"coap_proxy_requests_total{code="5.03",method="GET"} 1"#),
+        "{metrics}"
+    );
+    assert!(!metrics.contains(r#"code="2.05""#), "{metrics}");
+    assert!(metrics.contains(r#"coap_proxy_errors_total{class="amplification"} 1"#));
+}
@@ -0,0 +1,137 @@
+//! CoAP over TCP, with the limit of open connections.
+
+mod common;
+
+use std::{net::SocketAddr, time::Duration};
+
+use common::{free_udp_port, Homeserver, Proxy, VERSIONS};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Codes of the signaling messages.
+const CODE_CSM: u8 = 0xE1;
+const CODE_PING: u8 = 0xE2;
+const CODE_PONG: u8 = 0xE3;
+
+/// 2.05 Content.
+const CODE_CONTENT: u8 = 0x45;
+
+/// Find a port that is free for TCP right now.
+fn free_tcp_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+async fn connect(address: SocketAddr) -> TcpStream {
+    for _ in 0..50 {
+        if let Ok(stream) = TcpStream::connect(address).await {
+            return stream;
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    panic!("The proxy never accepted the connection");
+}
+
+/// Read a message, returning its code, token and the options and payload, `None` if the
+/// connection was closed.
+async fn read(stream: &mut TcpStream) -> Option<(u8, Vec<u8>, Vec<u8>)> {
+    tokio::time::timeout(Duration::from_secs(5), read_message(stream))
+        .await
+        .expect("The proxy should answer in time")
+}
+
+async fn read_message(stream: &mut TcpStream) -> Option<(u8, Vec<u8>, Vec<u8>)> {
+    let first = stream.read_u8().await.ok()?;
+    let length = match first >> 4 {
+        13 => usize::from(stream.read_u8().await.ok()?) + 13,
+        14 => usize::from(stream.read_u16().await.ok()?) + 269,
+        length => usize::from(length),
+    };
+
+    let code = stream.read_u8().await.ok()?;
+    let mut token = vec![0; usize::from(first & 0x0F)];
+    stream.read_exact(&mut token).await.ok()?;
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await.ok()?;
+
+    Some((code, token, body))
+}
+
+async fn start(max_connections: usize) -> (Homeserver, Proxy, SocketAddr) {
+    let homeserver = Homeserver::start().await;
+    let address = SocketAddr::from(([127, 0, 0, 1], free_tcp_port()));
+
+    let proxy = Proxy::start(&format!(
+        r#"
+        listen = ["127.0.0.1:{}"]
+        homeserver = "http://{}/"
+
+        [tcp]
+        listen = ["{address}"]
+        max_connections = {max_connections}
+        "#,
+        free_udp_port(),
+        homeserver.address
+    ));
+
+    (homeserver, proxy, address)
+}
+
+#[tokio::test]
+async fn answers_requests_and_pings() {
+    let (homeserver, _proxy, address) = start(4).await;
+    let mut stream = connect(address).await;
+
+    let (code, _, _) = read(&mut stream).await.unwrap();
+    assert_eq!(code, CODE_CSM);
+
+    // A ping with the token 7.
+    stream.write_all(&[0x01, CODE_PING, 7]).await.unwrap();
+    assert_eq!(
+        read(&mut stream).await.unwrap(),
+        (CODE_PONG, vec![7], Vec::new())
+    );
+
+    // GET /_matrix/client/versions with the token 1, the options are 24 bytes long.
+    let mut message = vec![13 << 4 | 1, 24 - 13, 0x01, 1];
+    message.push(0xB7);
+    message.extend(b"_matrix");
+    message.push(0x06);
+    message.extend(b"client");
+    message.push(0x08);
+    message.extend(b"versions");
+    stream.write_all(&message).await.unwrap();
+
+    let (code, token, body) = read(&mut stream).await.unwrap();
+    assert_eq!(code, CODE_CONTENT);
+    assert_eq!(token, [1]);
+    assert!(body.ends_with(VERSIONS.as_bytes()));
+    assert_eq!(homeserver.hits(), 1);
+}
+
+#[tokio::test]
+async fn closes_connections_beyond_the_limit() {
+    let (_homeserver, _proxy, address) = start(2).await;
+
+    let mut first = connect(address).await;
+    let mut second = connect(address).await;
+    assert_eq!(read(&mut first).await.unwrap().0, CODE_CSM);
+    assert_eq!(read(&mut second).await.unwrap().0, CODE_CSM);
+
+    let mut third = connect(address).await;
+    assert_eq!(read(&mut third).await, None);
+
+    // Closing a connection makes room for another one.
+    drop(first);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut fourth = connect(address).await;
+    assert_eq!(read(&mut fourth).await.unwrap().0, CODE_CSM);
+}